@@ -0,0 +1,30 @@
+//! Measures building a command with many arguments, like a linker invocation
+//! with thousands of object files.
+//!
+//! ```text
+//! cargo run --release --example args_bench
+//! ```
+use std::{hint::black_box, path::PathBuf, time::Instant};
+
+use xshell::Cmd;
+
+const N_ARGS: usize = 50_000;
+const N_ITERS: usize = 20;
+
+fn main() {
+    let objects =
+        || (0..N_ARGS).map(|i| PathBuf::from(format!("target/obj/{}.o", i))).collect::<Vec<_>>();
+
+    // Creating and dropping the arguments themselves, the same in each case.
+    bench("Vec<PathBuf>", || drop(black_box(objects())));
+    bench("args(Vec<PathBuf>)", || drop(black_box(Cmd::new("ld").args(objects()))));
+    bench("args_owned(Vec<PathBuf>)", || drop(black_box(Cmd::new("ld").args_owned(objects()))));
+}
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..N_ITERS {
+        f();
+    }
+    println!("{:<28} {:>8.2?}", name, start.elapsed() / N_ITERS as u32);
+}
@@ -21,6 +21,7 @@ fn try_main() -> Result<()> {
     {
         let _s = Section::new("TEST");
         cmd!("cargo test --workspace").run()?;
+        cmd!("cargo test --workspace --all-features").run()?;
     }
 
     {
@@ -33,7 +34,7 @@ fn try_main() -> Result<()> {
                 match words.as_slice() {
                     [n, "=", v, ..] if n.trim() == "version" => {
                         assert!(v.starts_with('"') && v.ends_with('"'));
-                        return Some(&v[1..v.len() - 1]);
+                        Some(&v[1..v.len() - 1])
                     }
                     _ => None,
                 }
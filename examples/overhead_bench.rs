@@ -0,0 +1,68 @@
+//! Measures what xshell adds on top of `std::process::Command` when running
+//! many small commands, like `git cat-file` in a loop.
+//!
+//! ```text
+//! cargo run --release --example overhead_bench 2>/dev/null
+//! ```
+//!
+//! Keep stderr redirected, as that's when the stderr of commands is retained
+//! for error messages. Command timings are off, as by default, so nothing is
+//! recorded for them.
+use std::{
+    hint::black_box,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use xshell::{cmd, Cmd};
+
+const N_BUILD: u32 = 200_000;
+const N_SPAWN: usize = 3_000;
+
+fn main() {
+    let object = "4b825dc642cb6eb9a060e54bf8d69288fbc4904d";
+    bench("cmd! construction", || {
+        drop(black_box(cmd!("git cat-file -p {object}")));
+    });
+    let quoted = "it's";
+    let cmd = cmd!("git cat-file -p {object} --textconv --filters 'a b' {quoted}").quiet();
+    bench("Display", || drop(black_box(cmd.to_string())));
+
+    // The process itself is most of the cost of a spawn, and it varies a lot,
+    // so compare the medians of interleaved runs.
+    let mut std_runs = Vec::with_capacity(N_SPAWN);
+    let mut xshell_runs = Vec::with_capacity(N_SPAWN);
+    for _ in 0..N_SPAWN {
+        std_runs.push(time(|| assert!(Command::new("true").status().unwrap().success())));
+        xshell_runs.push(time(|| Cmd::new("true").quiet().run().unwrap()));
+    }
+    let std_run = median(std_runs);
+    let xshell_run = median(xshell_runs);
+    report("std::process::Command", std_run);
+    report("Cmd::run (quiet)", xshell_run);
+    report("overhead per spawn", xshell_run.saturating_sub(std_run));
+}
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    f();
+    let start = Instant::now();
+    for _ in 0..N_BUILD {
+        f();
+    }
+    report(name, start.elapsed() / N_BUILD);
+}
+
+fn time(f: impl FnOnce()) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn median(mut durations: Vec<Duration>) -> Duration {
+    durations.sort();
+    durations[durations.len() / 2]
+}
+
+fn report(name: &str, duration: Duration) {
+    println!("{:<24} {:>10.2?}", name, duration);
+}
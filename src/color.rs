@@ -0,0 +1,54 @@
+use std::{
+    io::{self, IsTerminal},
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Whether command echoes and error messages are colored, see
+/// [`set_echo_color`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color the output if it goes to a terminal, following the `NO_COLOR`
+    /// and `CLICOLOR_FORCE` conventions.
+    Auto,
+    Always,
+    Never,
+}
+
+static COLOR: AtomicU8 = AtomicU8::new(0);
+
+/// Sets whether `$ cmd` echoes and the `Debug` output of errors, which is
+/// what `fn main() -> xshell::Result<()>` prints, are colored. The default is
+/// [`ColorChoice::Auto`].
+pub fn set_echo_color(choice: ColorChoice) {
+    let value = match choice {
+        ColorChoice::Auto => 0,
+        ColorChoice::Always => 1,
+        ColorChoice::Never => 2,
+    };
+    COLOR.store(value, Ordering::Relaxed);
+}
+
+pub(crate) enum Stream {
+    Stdout,
+    Stderr,
+}
+
+pub(crate) fn enabled(stream: Stream) -> bool {
+    match COLOR.load(Ordering::Relaxed) {
+        1 => return true,
+        2 => return false,
+        _ => (),
+    }
+    // See https://no-color.org and https://bixense.com/clicolors.
+    let var = |key: &str| std::env::var_os(key).filter(|it| !it.is_empty());
+    if var("NO_COLOR").is_some() {
+        return false;
+    }
+    if var("CLICOLOR_FORCE").is_some_and(|it| it != "0") {
+        return true;
+    }
+    match stream {
+        Stream::Stdout => io::stdout().is_terminal(),
+        Stream::Stderr => io::stderr().is_terminal(),
+    }
+}
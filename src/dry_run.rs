@@ -0,0 +1,48 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::echo::echo;
+
+const UNSET: u8 = 0;
+const OFF: u8 = 1;
+const ON: u8 = 2;
+
+static DRY_RUN: AtomicU8 = AtomicU8::new(UNSET);
+
+/// Turns dry-run mode on or off for the whole process.
+///
+/// In dry-run mode, commands are echoed but not executed: [`Cmd::run`]
+/// succeeds and [`Cmd::read`] returns an empty string. File system helpers
+/// which modify something, like [`rm_rf`] or [`write_file`], print what they
+/// would do instead of doing it. Commands marked with [`Cmd::force_run`]
+/// still run.
+///
+/// Unless set explicitly, dry-run mode is on if the `XSHELL_DRY_RUN`
+/// environment variable is set to a non-empty value.
+///
+/// [`Cmd::run`]: crate::Cmd::run
+/// [`Cmd::read`]: crate::Cmd::read
+/// [`Cmd::force_run`]: crate::Cmd::force_run
+/// [`rm_rf`]: crate::rm_rf
+/// [`write_file`]: crate::write_file
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(if dry_run { ON } else { OFF }, Ordering::Relaxed)
+}
+
+pub fn is_dry_run() -> bool {
+    match DRY_RUN.load(Ordering::Relaxed) {
+        UNSET => std::env::var_os("XSHELL_DRY_RUN").is_some_and(|it| !it.is_empty()),
+        state => state == ON,
+    }
+}
+
+/// Returns `true` and echoes `action` if it should be skipped.
+pub(crate) fn skip(action: fmt::Arguments<'_>) -> bool {
+    if !is_dry_run() {
+        return false;
+    }
+    echo(&action);
+    true
+}
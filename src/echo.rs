@@ -0,0 +1,107 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use crate::{
+    color::{self, Stream},
+    Cmd, DisplayCmds,
+};
+
+type EchoHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+static ECHO_HOOK: RwLock<Option<EchoHook>> = RwLock::new(None);
+
+/// Routes command echoes through `hook` instead of printing them to stdout.
+///
+/// The hook gets the rendered command line (without the `$ ` prompt) before
+/// the command is spawned. It is global for the whole process and replaces
+/// the previously installed one.
+pub fn set_echo_hook(hook: impl Fn(&str) + Send + Sync + 'static) {
+    *ECHO_HOOK.write().unwrap() = Some(Arc::new(hook));
+}
+
+/// Restores the default behavior of printing command echoes to stdout.
+pub fn remove_echo_hook() {
+    *ECHO_HOOK.write().unwrap() = None;
+}
+
+/// How much xshell prints about the commands it runs, see [`set_verbosity`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// No command echoes at all.
+    Quiet,
+    /// `$ cmd` before each command, the default.
+    Normal,
+    /// Like `Normal`, but also prints the working directory and the
+    /// environment overrides of each command, and how long it took.
+    Verbose,
+}
+
+const UNSET: u8 = 0;
+static VERBOSITY: AtomicU8 = AtomicU8::new(UNSET);
+
+/// Sets the verbosity for the whole process.
+///
+/// Unless set explicitly, the verbosity is `Quiet` if the `XSHELL_QUIET`
+/// environment variable is set to a non-empty value, `Verbose` if
+/// `XSHELL_VERBOSE` is, and `Normal` otherwise. The variables are read
+/// every time, so they can be changed while the process runs.
+///
+/// A command marked with [`Cmd::quiet`] is never echoed, and with `Quiet`
+/// no command is, regardless of [`Cmd::echo_cmd`].
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8 + 1, Ordering::Relaxed)
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        UNSET => {
+            let var = |key: &str| std::env::var_os(key).is_some_and(|it| !it.is_empty());
+            if var("XSHELL_QUIET") {
+                Verbosity::Quiet
+            } else if var("XSHELL_VERBOSE") {
+                Verbosity::Verbose
+            } else {
+                Verbosity::Normal
+            }
+        }
+        1 => Verbosity::Quiet,
+        2 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+/// Echoes `cmds` as a pipeline, with their details in verbose mode.
+pub(crate) fn echo_cmds(cmds: &[Cmd]) {
+    let verbosity = verbosity();
+    if verbosity == Verbosity::Quiet {
+        return;
+    }
+    echo(&DisplayCmds(cmds));
+    // A hook only gets the command line.
+    if verbosity == Verbosity::Verbose && ECHO_HOOK.read().unwrap().is_none() {
+        for cmd in cmds {
+            print!("{}", cmd.details());
+        }
+    }
+}
+
+pub(crate) fn echo(cmd: &dyn fmt::Display) {
+    if verbosity() == Verbosity::Quiet {
+        return;
+    }
+    // Don't hold the lock while calling the hook, it might want to echo
+    // something itself.
+    let hook = ECHO_HOOK.read().unwrap().clone();
+    match hook {
+        Some(hook) => hook(&cmd.to_string()),
+        None if color::enabled(Stream::Stdout) => {
+            println!("\x1b[1;36m$\x1b[0m \x1b[1m{}\x1b[0m", cmd)
+        }
+        None => println!("$ {}", cmd),
+    }
+}
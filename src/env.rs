@@ -2,13 +2,20 @@ use std::{
     cell::Cell,
     ffi::OsStr,
     ffi::OsString,
-    mem::MaybeUninit,
+    fmt::Write,
+    io, iter,
     path::{Path, PathBuf},
-    ptr,
-    sync::{Mutex, MutexGuard, Once},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
 };
 
-use crate::{cwd, error::fs_err, Result};
+use crate::{
+    cwd,
+    error::{env_err, fs_err},
+    Result,
+};
 
 pub fn pushd(dir: impl AsRef<Path>) -> Result<Pushd> {
     Pushd::new(dir.as_ref())
@@ -17,46 +24,150 @@ pub fn pushd(dir: impl AsRef<Path>) -> Result<Pushd> {
 #[must_use]
 pub struct Pushd {
     _guard: GlobalShellLock,
+    _scope: Scope,
     prev_dir: PathBuf,
     dir: PathBuf,
+    popped: bool,
+}
+
+/// Runs `f` with `dir` as the current directory.
+///
+/// Unlike [`pushd`], the previous directory is restored even if `f` panics,
+/// and failing to restore it is reported as an error rather than a panic.
+pub fn with_dir<T>(dir: impl AsRef<Path>, f: impl FnOnce() -> T) -> Result<T> {
+    struct Restore<'a> {
+        prev_dir: &'a Path,
+        armed: bool,
+    }
+    impl Drop for Restore<'_> {
+        fn drop(&mut self) {
+            if self.armed {
+                let _ = std::env::set_current_dir(self.prev_dir);
+            }
+        }
+    }
+
+    let _guard = GlobalShellLock::lock();
+    let prev_dir = cwd()?;
+    set_current_dir(dir.as_ref())?;
+    let _scope = Scope::dir(&prev_dir, &cwd()?);
+    let mut restore = Restore { prev_dir: &prev_dir, armed: true };
+    let res = f();
+    restore.armed = false;
+    set_current_dir(&prev_dir)?;
+    Ok(res)
 }
 
 pub fn pushenv(k: impl AsRef<OsStr>, v: impl AsRef<OsStr>) -> Pushenv {
-    Pushenv::new(k.as_ref(), v.as_ref())
+    Pushenv::new(k.as_ref(), Some(v.as_ref()))
+}
+
+/// Unsets the environment variable `k` until the returned guard is dropped,
+/// like `RUSTC_WRAPPER` for a nested cargo. Setting it to an empty value
+/// with [`pushenv`] is not the same for most programs.
+pub fn pushenv_remove(k: impl AsRef<OsStr>) -> Pushenv {
+    Pushenv::new(k.as_ref(), None)
+}
+
+/// Prepends `dir` to `PATH` until the returned guard is dropped.
+pub fn pushenv_path(dir: impl AsRef<Path>) -> Result<Pushenv> {
+    _pushenv_path(dir.as_ref())
+}
+fn _pushenv_path(dir: &Path) -> Result<Pushenv> {
+    let guard = GlobalShellLock::lock();
+    let path = std::env::var_os("PATH");
+    let dirs = iter::once(dir.to_path_buf())
+        .chain(path.iter().filter(|it| !it.is_empty()).flat_map(std::env::split_paths))
+        .collect::<Vec<_>>();
+    let value = std::env::join_paths(dirs)
+        .map_err(|err| fs_err(dir.to_path_buf(), io::Error::other(err)))?;
+    Ok(Pushenv::with_guard(guard, OsStr::new("PATH"), Some(&value)))
 }
 
 #[must_use]
 pub struct Pushenv {
     _guard: GlobalShellLock,
+    _scope: Scope,
     key: OsString,
     prev_value: Option<OsString>,
-    value: OsString,
+    value: Option<OsString>,
+    popped: bool,
 }
 
 impl Pushd {
     fn new(dir: &Path) -> Result<Pushd> {
         let guard = GlobalShellLock::lock();
         let prev_dir = cwd()?;
-        set_current_dir(&dir)?;
+        set_current_dir(dir)?;
         let dir = cwd()?;
-        Ok(Pushd { _guard: guard, prev_dir, dir })
+        let scope = Scope::dir(&prev_dir, &dir);
+        Ok(Pushd { _guard: guard, _scope: scope, prev_dir, dir, popped: false })
+    }
+
+    /// Restores the previous directory, returning an error instead of
+    /// panicking if something went wrong.
+    ///
+    /// The previous directory is restored even if the current directory was
+    /// changed concurrently, but that is reported as an error.
+    pub fn pop(mut self) -> Result<()> {
+        self.popped = true;
+        let dir = cwd();
+        set_current_dir(&self.prev_dir)?;
+        match dir {
+            Ok(dir) if dir != self.dir => {
+                let msg =
+                    format!("current directory was changed concurrently to `{}`", dir.display());
+                Err(fs_err(self.dir.clone(), std::io::Error::other(msg)))
+            }
+            _ => Ok(()),
+        }
     }
 }
 
 impl Drop for Pushd {
     fn drop(&mut self) {
-        let dir = cwd().unwrap();
-        assert_eq!(
-            dir,
-            self.dir,
-            "current directory was changed concurrently.
+        if self.popped {
+            return;
+        }
+        // The directory itself might have been removed, like a `TempDir`
+        // dropped before the `Pushd` into it. Just go back then.
+        if let Ok(dir) = cwd() {
+            if dir != self.dir {
+                let msg = format!(
+                    "current directory was changed concurrently.
 expected {}
 got      {}",
-            self.dir.display(),
-            dir.display()
-        );
-        set_current_dir(&self.prev_dir).unwrap()
+                    self.dir.display(),
+                    dir.display()
+                );
+                // Panicking again would abort, hiding the original panic.
+                if std::thread::panicking() {
+                    eprintln!("warning: {}", msg);
+                } else {
+                    restore_dir(&self.prev_dir);
+                    panic!("{}", msg);
+                }
+            }
+        }
+        restore_dir(&self.prev_dir)
+    }
+}
+
+fn restore_dir(prev_dir: &Path) {
+    // If the previous directory is gone, get as close to it as possible.
+    for dir in prev_dir.ancestors() {
+        if std::env::set_current_dir(dir).is_ok() {
+            if dir != prev_dir {
+                eprintln!(
+                    "warning: `{}` no longer exists, changed to `{}` instead",
+                    prev_dir.display(),
+                    dir.display()
+                );
+            }
+            return;
+        }
     }
+    let _ = std::env::set_current_dir(std::env::temp_dir());
 }
 
 fn set_current_dir(path: &Path) -> Result<()> {
@@ -64,43 +175,222 @@ fn set_current_dir(path: &Path) -> Result<()> {
 }
 
 impl Pushenv {
-    fn new(key: &OsStr, value: &OsStr) -> Pushenv {
-        let guard = GlobalShellLock::lock();
+    fn new(key: &OsStr, value: Option<&OsStr>) -> Pushenv {
+        Pushenv::with_guard(GlobalShellLock::lock(), key, value)
+    }
+    fn with_guard(guard: GlobalShellLock, key: &OsStr, value: Option<&OsStr>) -> Pushenv {
         let prev_value = std::env::var_os(key);
-        std::env::set_var(key, value);
-        Pushenv { _guard: guard, key: key.to_os_string(), prev_value, value: value.to_os_string() }
+        set_var(key, value);
+        Pushenv {
+            _guard: guard,
+            _scope: Scope::var(key, prev_value.as_deref(), value),
+            key: key.to_os_string(),
+            prev_value,
+            value: value.map(OsStr::to_os_string),
+            popped: false,
+        }
+    }
+
+    /// Restores the previous value, returning an error instead of panicking
+    /// if the variable was changed concurrently.
+    pub fn pop(mut self) -> Result<()> {
+        self.popped = true;
+        let value = std::env::var_os(&self.key);
+        set_var(&self.key, self.prev_value.as_deref());
+        if value != self.value {
+            return Err(env_err(self.key.clone(), self.value.clone(), value));
+        }
+        Ok(())
     }
 }
 
 impl Drop for Pushenv {
     fn drop(&mut self) {
-        let value = std::env::var_os(&self.key);
-        assert_eq!(
-            value.as_ref(),
-            Some(&self.value),
+        if self.popped {
+            return;
+        }
+        restore_var(&self.key, self.value.as_deref(), self.prev_value.as_deref())
+    }
+}
+
+/// Sets several environment variables at once, see [`Pushenvs`].
+pub fn pushenvs<I, K, V>(vars: I) -> Pushenvs
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    let guard = GlobalShellLock::lock();
+    let vars = vars
+        .into_iter()
+        .map(|(key, value)| {
+            let (key, value) = (key.as_ref(), value.as_ref());
+            let prev_value = std::env::var_os(key);
+            std::env::set_var(key, value);
+            let scope = Scope::var(key, prev_value.as_deref(), Some(value));
+            (key.to_os_string(), prev_value, value.to_os_string(), scope)
+        })
+        .collect();
+    Pushenvs { _guard: guard, vars }
+}
+
+/// Like [`Pushenv`], but for several variables, which are restored in
+/// reverse order.
+#[must_use]
+pub struct Pushenvs {
+    _guard: GlobalShellLock,
+    vars: Vec<(OsString, Option<OsString>, OsString, Scope)>,
+}
+
+impl Drop for Pushenvs {
+    fn drop(&mut self) {
+        for (key, prev_value, value, _scope) in self.vars.iter().rev() {
+            restore_var(key, Some(value), prev_value.as_deref())
+        }
+    }
+}
+
+fn restore_var(key: &OsStr, expected: Option<&OsStr>, prev_value: Option<&OsStr>) {
+    let value = std::env::var_os(key);
+    if value.as_deref() != expected {
+        let msg = format!(
             "environmental variable was changed concurrently.
 var      {:?}
 expected {:?}
 got      {:?}",
-            self.key,
-            self.value,
-            value
+            key, expected, value
         );
-        match &self.prev_value {
-            Some(it) => std::env::set_var(&self.key, &it),
-            None => std::env::remove_var(&self.key),
+        // Panicking again would abort, hiding the original panic.
+        if !std::thread::panicking() {
+            panic!("{}", msg);
         }
+        eprintln!("warning: {}", msg);
+    }
+    set_var(key, prev_value)
+}
+
+fn set_var(key: &OsStr, value: Option<&OsStr>) {
+    match value {
+        Some(it) => std::env::set_var(key, it),
+        None => std::env::remove_var(key),
+    }
+}
+
+/// Describes the state xshell changed for the whole process: the current
+/// directory and, from the innermost, the active [`pushd`], [`with_dir`] and
+/// [`pushenv`] scopes, for printing when something goes wrong:
+///
+/// ```text
+/// cwd: /home/me/project/frontend
+/// pushd /home/me/project -> /home/me/project/frontend (thread `main`)
+/// pushenv NODE_ENV: unset -> "test" (thread `main`)
+/// ```
+///
+/// Values of variables with names like `*TOKEN*`, `*SECRET*`, `*PASSWORD*` or
+/// `*KEY*` are masked. As the scopes hold the [`lock`], they all belong to the
+/// same thread. In verbose mode, see [`set_verbosity`](crate::set_verbosity),
+/// this is included in command errors too.
+pub fn debug_state() -> String {
+    let mut res = match std::env::current_dir() {
+        Ok(dir) => format!("cwd: {}\n", dir.display()),
+        Err(err) => format!("cwd: {}\n", err),
+    };
+    let scopes = SCOPES.lock().unwrap_or_else(|err| err.into_inner());
+    for (_id, thread, kind) in scopes.iter().rev() {
+        match kind {
+            ScopeKind::Dir { prev_dir, dir } => {
+                write!(res, "pushd {} -> {}", prev_dir.display(), dir.display()).unwrap()
+            }
+            ScopeKind::Var { key, prev_value, value } => {
+                let fmt_value = |value: &Option<OsString>| match value {
+                    Some(_) if is_secret(key) => "***".to_string(),
+                    Some(value) => format!("{:?}", value),
+                    None => "unset".to_string(),
+                };
+                let (prev_value, value) = (fmt_value(prev_value), fmt_value(value));
+                write!(res, "pushenv {}: {} -> {}", key.to_string_lossy(), prev_value, value)
+                    .unwrap()
+            }
+        }
+        writeln!(res, " (thread `{}`)", thread).unwrap();
+    }
+    res
+}
+
+fn is_secret(key: &OsStr) -> bool {
+    let key = key.to_string_lossy().to_ascii_uppercase();
+    ["TOKEN", "SECRET", "PASSWORD", "KEY"].iter().any(|it| key.contains(it))
+}
+
+enum ScopeKind {
+    Dir { prev_dir: PathBuf, dir: PathBuf },
+    Var { key: OsString, prev_value: Option<OsString>, value: Option<OsString> },
+}
+
+static SCOPES: Mutex<Vec<(u64, String, ScopeKind)>> = Mutex::new(Vec::new());
+static NEXT_SCOPE: AtomicU64 = AtomicU64::new(0);
+
+/// Registers a scope for [`debug_state`] until dropped.
+struct Scope {
+    id: u64,
+}
+
+impl Scope {
+    fn dir(prev_dir: &Path, dir: &Path) -> Scope {
+        Scope::new(ScopeKind::Dir { prev_dir: prev_dir.to_path_buf(), dir: dir.to_path_buf() })
+    }
+    fn var(key: &OsStr, prev_value: Option<&OsStr>, value: Option<&OsStr>) -> Scope {
+        Scope::new(ScopeKind::Var {
+            key: key.to_os_string(),
+            prev_value: prev_value.map(OsStr::to_os_string),
+            value: value.map(OsStr::to_os_string),
+        })
+    }
+    fn new(kind: ScopeKind) -> Scope {
+        let id = NEXT_SCOPE.fetch_add(1, Ordering::Relaxed);
+        let thread = std::thread::current();
+        let thread = match thread.name() {
+            Some(name) => name.to_string(),
+            None => format!("{:?}", thread.id()),
+        };
+        SCOPES.lock().unwrap_or_else(|err| err.into_inner()).push((id, thread, kind));
+        Scope { id }
     }
 }
 
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let mut scopes = SCOPES.lock().unwrap_or_else(|err| err.into_inner());
+        scopes.retain(|(id, ..)| *id != self.id);
+    }
+}
+
+/// Takes the lock which xshell holds while it changes the current directory
+/// or the environment of the process, like in [`pushd`] and [`pushenv`].
+///
+/// Hold it around code which does the same behind xshell's back, like
+/// calling `std::env::set_current_dir`, so that it doesn't race with xshell.
+///
+/// The lock is reentrant: while a thread holds it, `pushd` and friends on
+/// the same thread don't block. Guards should be dropped in the reverse
+/// order of their creation, the lock is released with the outermost one.
+pub fn lock() -> ShellLockGuard {
+    ShellLockGuard { _guard: GlobalShellLock::lock() }
+}
+
+/// The guard returned by [`lock`]. It can't be sent to another thread.
+#[must_use]
+pub struct ShellLockGuard {
+    _guard: GlobalShellLock,
+}
+
 struct GlobalShellLock {
     guard: Option<MutexGuard<'static, ()>>,
 }
 
-static mut MUTEX: MaybeUninit<Mutex<()>> = MaybeUninit::uninit();
-static MUTEX_INIT: Once = Once::new();
+static MUTEX: Mutex<()> = Mutex::new(());
 thread_local! {
-    pub static LOCKED: Cell<bool> = Cell::new(false);
+    pub static LOCKED: Cell<bool> = const { Cell::new(false) };
 }
 
 impl GlobalShellLock {
@@ -109,10 +399,9 @@ impl GlobalShellLock {
             return GlobalShellLock { guard: None };
         }
 
-        let guard = unsafe {
-            MUTEX_INIT.call_once(|| ptr::write(MUTEX.as_mut_ptr(), Mutex::new(())));
-            (*MUTEX.as_ptr()).lock().unwrap()
-        };
+        // A panic while holding the lock, like in a `with_dir` closure,
+        // doesn't leave anything in an inconsistent state.
+        let guard = MUTEX.lock().unwrap_or_else(|err| err.into_inner());
         LOCKED.with(|it| it.set(true));
         GlobalShellLock { guard: Some(guard) }
     }
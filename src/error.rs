@@ -1,6 +1,19 @@
-use std::{fmt, io, path::PathBuf, process::ExitStatus, string::FromUtf8Error};
+use std::{
+    ffi::OsString,
+    fmt, io,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    string::FromUtf8Error,
+    time::Duration,
+};
 
-use crate::Cmd;
+use crate::{
+    color::{self, Stream},
+    debug_state,
+    echo::{verbosity, Verbosity},
+    timing::FmtDuration,
+    which, Cmd,
+};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -8,63 +21,440 @@ pub struct Error {
     repr: Box<Repr>,
 }
 
+// Always behind a `Box`, so the size of the variants doesn't matter.
+#[allow(clippy::large_enum_variant)]
 enum Repr {
     CmdError(CmdError),
     FsError(FsError),
+    Which(WhichError),
+    Env(EnvError),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fn errstr(err: &io::Error) -> String {
-            let mut res = err.to_string();
-            if res.is_char_boundary(1) {
-                res[..1].make_ascii_lowercase();
-            }
-            res
-        }
         match &*self.repr {
-            Repr::CmdError(err) => match &err.kind {
-                CmdErrorKind::NonZeroStatus(status) => {
-                    write!(f, "command `{}` failed, {}", err.cmd, status)
+            Repr::CmdError(err) => {
+                fmt_cmd_error(f, err)?;
+                match &err.state {
+                    Some(state) => write!(f, "\n{}", state.trim_end()),
+                    None => Ok(()),
                 }
-                CmdErrorKind::Io(io_err) => {
-                    if io_err.kind() == io::ErrorKind::NotFound {
-                        write!(f, "command not found: `{}`", err.cmd.args[0].to_string_lossy())
-                    } else {
-                        write!(f, "command `{}` failed, {}", err.cmd, errstr(io_err))
+            }
+            Repr::FsError(err) => write!(f, "`{}`: {}", err.path.display(), errstr(&err.io_err)),
+            Repr::Which(err) => {
+                match &err.path {
+                    Some(path) => write!(
+                        f,
+                        "program `{}` not found in PATH `{}`",
+                        err.program.display(),
+                        path.to_string_lossy()
+                    )?,
+                    None => {
+                        write!(f, "program `{}` not found, PATH is not set", err.program.display())?
                     }
                 }
-                CmdErrorKind::NonUtf8Stdout(utf8_err) => {
-                    write!(f, "command `{}` produced invalid utf8, {}", err.cmd, utf8_err)
+                write_not_executable(f, &err.not_executable)
+            }
+            Repr::Env(err) => {
+                write!(f, "environment variable {:?} was changed concurrently, ", err.key)?;
+                match &err.expected {
+                    Some(expected) => write!(f, "expected {:?}, ", expected)?,
+                    None => write!(f, "expected it to be unset, ")?,
                 }
-            },
-            Repr::FsError(err) => write!(f, "`{}`: {}", err.path.display(), errstr(&err.io_err)),
+                match &err.got {
+                    Some(got) => write!(f, "got {:?}", got),
+                    None => write!(f, "but it was removed"),
+                }
+            }
+        }
+    }
+}
+
+fn errstr(err: &io::Error) -> String {
+    let mut res = err.to_string();
+    // Keep acronyms, like the `TOML` of `TOML parse error`.
+    let acronym = res.as_bytes().get(1).is_some_and(|it| it.is_ascii_uppercase());
+    if res.is_char_boundary(1) && !acronym {
+        res[..1].make_ascii_lowercase();
+    }
+    res
+}
+
+fn fmt_cmd_error(f: &mut fmt::Formatter<'_>, err: &CmdError) -> fmt::Result {
+    match &err.kind {
+        CmdErrorKind::NonZeroStatus { status, stderr } => {
+            write!(f, "command `{}` failed", err.cmd_line)?;
+            if err.cmd.attempts > 1 {
+                write!(f, " after {} attempts", err.cmd.attempts)?;
+            }
+            write_status(f, *status)?;
+            write_excerpt(f, "stderr", stderr)
+        }
+        CmdErrorKind::Io(io_err) => {
+            write!(f, "command `{}` failed, ", err.cmd_line)?;
+            match io_err.kind() {
+                io::ErrorKind::PermissionDenied => write!(
+                    f,
+                    "can't execute `{}`, permission denied",
+                    err.cmd.args[0].to_string_lossy()
+                ),
+                _ => write!(f, "{}", errstr(io_err)),
+            }
+        }
+        CmdErrorKind::ProgramMissing { path, io_err, not_executable } => {
+            write!(f, "command `{}` failed, ", err.cmd_line)?;
+            let program = Path::new(&err.cmd.args[0]);
+            if io_err.kind() == io::ErrorKind::PermissionDenied {
+                write!(f, "can't execute `{}`, permission denied", program.display())?;
+                return write_not_executable(f, not_executable);
+            }
+            write!(f, "`{}` not found", program.display())?;
+            // Paths like `./x.sh` aren't looked up in PATH.
+            if program.components().count() > 1 {
+                return Ok(());
+            }
+            write!(f, ", is it installed and on PATH?")?;
+            match path {
+                Some(path) => write!(f, "\nPATH: {}", path.to_string_lossy())?,
+                None => write!(f, "\nPATH is not set")?,
+            }
+            write_not_executable(f, not_executable)
+        }
+        CmdErrorKind::Dir { dir: path, io_err } | CmdErrorKind::File { path, io_err } => {
+            let (path, io_err) = (path.display(), errstr(io_err));
+            write!(f, "command `{}` failed, `{}`: {}", err.cmd_line, path, io_err)
+        }
+        CmdErrorKind::StdoutRedirected => {
+            let to = if err.cmd.null_stdout { "discarded" } else { "redirected to a file" };
+            write!(f, "can't read the stdout of `{}`, it is {}", err.cmd_line, to)
+        }
+        CmdErrorKind::NonUtf8Stdout(utf8_err) => {
+            write!(f, "command `{}` produced invalid utf8, {}", err.cmd_line, utf8_err)
+        }
+        CmdErrorKind::Parse(msg) => {
+            write!(f, "can't parse the output of `{}`: {}", err.cmd_line, msg)
+        }
+        CmdErrorKind::OutputLimit { limit, .. } => write!(
+            f,
+            "command `{}` was killed, its output exceeded the limit of {} bytes",
+            err.cmd_line, limit
+        ),
+        CmdErrorKind::Timeout { timeout, stdout, stderr } => {
+            write!(f, "command `{}` timed out after {}", err.cmd_line, FmtDuration(*timeout))?;
+            write_excerpt(f, "stdout", stdout)?;
+            write_excerpt(f, "stderr", stderr)
+        }
+    }
+}
+
+fn write_status(f: &mut fmt::Formatter<'_>, status: ExitStatus) -> fmt::Result {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            write!(f, ", terminated by signal {}", signal)?;
+            if let Some(name) = signal_name(signal) {
+                write!(f, " ({})", name)?;
+            }
+            if status.core_dumped() {
+                write!(f, " (core dumped)")?;
+            }
+            return Ok(());
+        }
+    }
+    match status.code() {
+        // NTSTATUS codes, like 0xC0000005 for an access violation, are only
+        // recognizable in hex.
+        #[cfg(windows)]
+        Some(code) if code as u32 >= 0xC000_0000 => write!(f, ", exit code: {:#X}", code as u32),
+        Some(code) => write!(f, ", exit code: {}", code),
+        None => write!(f, ", {}", status),
+    }
+}
+
+/// Names of the signals which have the same number everywhere.
+#[cfg(unix)]
+fn signal_name(signal: i32) -> Option<&'static str> {
+    let name = match signal {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        5 => "SIGTRAP",
+        6 => "SIGABRT",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Writes the tail of the output of the command, as that's where the reason
+/// for the failure usually is.
+fn write_excerpt(f: &mut fmt::Formatter<'_>, name: &str, output: &[u8]) -> fmt::Result {
+    const MAX_EXCERPT: usize = 4 * 1024;
+    if output.is_empty() {
+        return Ok(());
+    }
+    let mut start = output.len().saturating_sub(MAX_EXCERPT);
+    // Don't start in the middle of a multibyte UTF-8 sequence.
+    while start < output.len() && output[start] & 0b1100_0000 == 0b1000_0000 {
+        start += 1;
+    }
+    write!(f, "\n{}:\n", name)?;
+    if start > 0 {
+        writeln!(f, "[... {} bytes truncated ...]", start)?;
+    }
+    let excerpt = String::from_utf8_lossy(&output[start..]);
+    write!(f, "{}", excerpt.trim_end_matches('\n'))
+}
+
+/// Describes a failure to parse `text`: the parser's message and the part
+/// of `text` it points at, if the message has a `line N column M` in it, like
+/// the ones of `serde_json`. Otherwise, the start of `text`.
+pub(crate) fn parse_error(text: &str, msg: &str) -> String {
+    const MAX_LINE: usize = 120;
+    // Multiline messages, like the ones of `toml`, show the input themselves.
+    if msg.contains('\n') {
+        return msg.to_string();
+    }
+    let position = msg.rsplit_once("line ").and_then(|(_, rest)| {
+        let (line, rest) = rest.split_once(' ')?;
+        let column = rest.trim_start_matches(|c: char| !c.is_ascii_digit());
+        let column = &column[..column.find(|c: char| !c.is_ascii_digit()).unwrap_or(column.len())];
+        Some((line.trim_end_matches(',').parse::<usize>().ok()?, column.parse::<usize>().ok()?))
+    });
+    let excerpt = |line: &str, column: Option<usize>| {
+        let start = column.map_or(0, |it| it.saturating_sub(MAX_LINE / 2));
+        let line = line.chars().skip(start).take(MAX_LINE).collect::<String>();
+        match column {
+            Some(column) => format!("\n{}\n{}^", line, " ".repeat(column - start - 1)),
+            None => format!("\n{}", line),
+        }
+    };
+    match position {
+        Some((line, column)) if line > 0 && column > 0 => match text.lines().nth(line - 1) {
+            Some(it) => format!("{}{}", msg, excerpt(it, Some(column))),
+            None => msg.to_string(),
+        },
+        _ if text.trim().is_empty() => format!("{}, the input is empty", msg),
+        _ => format!("{}{}", msg, excerpt(text.lines().next().unwrap_or_default(), None)),
+    }
+}
+
+impl Error {
+    /// The failed command, as shown in the message: with
+    /// [secrets](crate::Secret) masked and, if there are many arguments, only
+    /// the first and the last few of them.
+    pub fn cmd(&self) -> Option<&str> {
+        match &*self.repr {
+            Repr::CmdError(err) => Some(&err.cmd_line),
+            _ => None,
+        }
+    }
+
+    /// The exit status of the failed command, if it ran to completion.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        match &*self.repr {
+            Repr::CmdError(CmdError {
+                kind: CmdErrorKind::NonZeroStatus { status, .. }, ..
+            }) => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// The signal which terminated the failed command. Always `None` on
+    /// platforms other than Unix.
+    pub fn signal(&self) -> Option<i32> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            self.exit_status().and_then(|it| it.signal())
+        }
+        #[cfg(not(unix))]
+        {
+            None
+        }
+    }
+
+    /// Whether something wasn't found: a program which isn't installed, or a
+    /// missing file or directory.
+    pub fn is_not_found(&self) -> bool {
+        match &*self.repr {
+            Repr::Which(_) => true,
+            _ => self.io().is_some_and(|it| it.kind() == io::ErrorKind::NotFound),
+        }
+    }
+
+    /// Whether the program of a command (or looked up with
+    /// [`which`](crate::which)) isn't installed, as opposed to a missing
+    /// file or directory.
+    pub fn is_program_missing(&self) -> bool {
+        match &*self.repr {
+            Repr::Which(_) => true,
+            Repr::CmdError(CmdError {
+                kind: CmdErrorKind::ProgramMissing { io_err, .. }, ..
+            }) => io_err.kind() == io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+
+    /// The path of the file or directory a failed operation was working with.
+    pub fn path(&self) -> Option<&Path> {
+        match &*self.repr {
+            Repr::FsError(FsError { path, .. })
+            | Repr::CmdError(CmdError { kind: CmdErrorKind::Dir { dir: path, .. }, .. })
+            | Repr::CmdError(CmdError { kind: CmdErrorKind::File { path, .. }, .. }) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The underlying IO error, if there is one.
+    pub fn io(&self) -> Option<&io::Error> {
+        match &*self.repr {
+            Repr::FsError(FsError { io_err, .. })
+            | Repr::CmdError(CmdError { kind: CmdErrorKind::Io(io_err), .. })
+            | Repr::CmdError(CmdError {
+                kind: CmdErrorKind::ProgramMissing { io_err, .. }, ..
+            })
+            | Repr::CmdError(CmdError { kind: CmdErrorKind::Dir { io_err, .. }, .. })
+            | Repr::CmdError(CmdError { kind: CmdErrorKind::File { io_err, .. }, .. }) => {
+                Some(io_err)
+            }
+            _ => None,
+        }
+    }
+
+    /// The stderr of the failed command, if it was captured.
+    pub fn stderr(&self) -> Option<&[u8]> {
+        match &*self.repr {
+            Repr::CmdError(CmdError {
+                kind: CmdErrorKind::NonZeroStatus { stderr, .. }, ..
+            })
+            | Repr::CmdError(CmdError { kind: CmdErrorKind::Timeout { stderr, .. }, .. })
+            | Repr::CmdError(CmdError { kind: CmdErrorKind::OutputLimit { stderr, .. }, .. }) => {
+                Some(stderr)
+            }
+            _ => None,
+        }
+    }
+
+    /// The stdout which a command produced before it was killed, because it
+    /// [timed out](Cmd::timeout) or exceeded the [output limit](Cmd::output_limit).
+    /// Only captured output is kept, as with [`Cmd::read`] or [`Cmd::tee`].
+    /// [`Cmd::read_lines`] yields the lines read before the timeout instead.
+    pub fn partial_stdout(&self) -> Option<&[u8]> {
+        match &*self.repr {
+            Repr::CmdError(CmdError { kind: CmdErrorKind::Timeout { stdout, .. }, .. })
+            | Repr::CmdError(CmdError { kind: CmdErrorKind::OutputLimit { stdout, .. }, .. }) => {
+                Some(stdout)
+            }
+            _ => None,
         }
     }
 }
 
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(self, f)
+        if !color::enabled(Stream::Stderr) {
+            return fmt::Display::fmt(self, f);
+        }
+        // Only the summary is red, not the stderr excerpt.
+        let msg = self.to_string();
+        let (summary, rest) = msg.split_once('\n').map_or((&*msg, None), |(a, b)| (a, Some(b)));
+        write!(f, "\x1b[31m{}\x1b[0m", summary)?;
+        if let Some(rest) = rest {
+            write!(f, "\n{}", rest)?;
+        }
+        Ok(())
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &*self.repr {
+            Repr::CmdError(CmdError { kind: CmdErrorKind::NonUtf8Stdout(utf8_err), .. }) => {
+                Some(utf8_err)
+            }
+            _ => self.io().map(|it| it as &(dyn std::error::Error + 'static)),
+        }
+    }
+}
 
 pub(crate) struct CmdError {
     cmd: Cmd,
+    /// The masked `cmd`, with long argument lists elided.
+    cmd_line: String,
+    /// The [`debug_state`](crate::debug_state) in verbose mode.
+    state: Option<String>,
     kind: CmdErrorKind,
 }
 
 pub(crate) enum CmdErrorKind {
-    NonZeroStatus(ExitStatus),
+    NonZeroStatus {
+        status: ExitStatus,
+        stderr: Vec<u8>,
+    },
     Io(io::Error),
+    /// Spawning failed with `NotFound`, and it's not the working directory
+    /// which is missing. Or with `PermissionDenied`, because the program
+    /// found in `PATH` is not executable.
+    ProgramMissing {
+        /// The `PATH` the program was looked up in.
+        path: Option<OsString>,
+        io_err: io::Error,
+        /// Files named like the program in `PATH`, which are not executable.
+        not_executable: Vec<PathBuf>,
+    },
+    Dir {
+        dir: PathBuf,
+        io_err: io::Error,
+    },
+    /// Failed to open a file the command was redirected to or from.
+    File {
+        path: PathBuf,
+        io_err: io::Error,
+    },
+    /// Tried to read the stdout of a command, which goes to a file or is
+    /// discarded.
+    StdoutRedirected,
     NonUtf8Stdout(FromUtf8Error),
+    /// The parser of [`Cmd::read_with`] failed, with its message and an
+    /// excerpt of the output.
+    Parse(String),
+    /// The captured output was more than [`Cmd::output_limit`] bytes. The
+    /// output read before the command was killed is kept, up to the limit.
+    OutputLimit {
+        limit: usize,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    },
+    /// The output read before the command was killed is kept.
+    Timeout {
+        timeout: Duration,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    },
 }
 
 impl CmdErrorKind {
     pub(crate) fn err(self, cmd: Cmd) -> Error {
-        Error { repr: Box::new(Repr::CmdError(CmdError { cmd, kind: self })) }
+        let cmd_line = CmdLine(&cmd).to_string();
+        let state = (verbosity() == Verbosity::Verbose).then(debug_state);
+        Error { repr: Box::new(Repr::CmdError(CmdError { cmd, cmd_line, state, kind: self })) }
+    }
+}
+
+struct CmdLine<'a>(&'a Cmd);
+
+impl fmt::Display for CmdLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MAX_ARGS: usize = 16;
+        self.0.fmt_elided(f, MAX_ARGS)
     }
 }
 
@@ -76,3 +466,33 @@ pub(crate) struct FsError {
 pub(crate) fn fs_err(path: PathBuf, io_err: io::Error) -> Error {
     Error { repr: Box::new(Repr::FsError(FsError { path, io_err })) }
 }
+
+pub(crate) struct WhichError {
+    program: PathBuf,
+    path: Option<OsString>,
+    not_executable: Vec<PathBuf>,
+}
+
+pub(crate) fn which_err(program: &Path, path: Option<OsString>) -> Error {
+    let not_executable = which::not_executable(program, path.as_deref());
+    let err = WhichError { program: program.to_path_buf(), path, not_executable };
+    Error { repr: Box::new(Repr::Which(err)) }
+}
+
+/// Lists the files which would have been found if they were executable.
+fn write_not_executable(f: &mut fmt::Formatter<'_>, not_executable: &[PathBuf]) -> fmt::Result {
+    for path in not_executable {
+        write!(f, "\nnot executable: {}", path.display())?;
+    }
+    Ok(())
+}
+
+pub(crate) struct EnvError {
+    key: OsString,
+    expected: Option<OsString>,
+    got: Option<OsString>,
+}
+
+pub(crate) fn env_err(key: OsString, expected: Option<OsString>, got: Option<OsString>) -> Error {
+    Error { repr: Box::new(Repr::Env(EnvError { key, expected, got })) }
+}
@@ -0,0 +1,1306 @@
+use std::{
+    ffi::OsStr,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    process::{self, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    dry_run::is_dry_run, echo::echo_cmds, error::CmdErrorKind, timing::FmtDuration, which, Cmd,
+    Redirect, Result as XResult,
+};
+
+#[cfg(feature = "async")]
+mod nonblocking;
+
+#[cfg(feature = "async")]
+pub(crate) use self::nonblocking::exec as exec_async;
+#[cfg(feature = "async")]
+pub use self::nonblocking::CmdFuture;
+
+/// Spawns all the `cmds` connected with pipes and waits for them.
+///
+/// On success, returns exit statuses of all commands and the output of the
+/// last one. On failure, returns the index of the command which caused it.
+pub(crate) fn exec_pipeline(
+    cmds: &[Cmd],
+    capture: bool,
+) -> Result<(Vec<ExitStatus>, Output), (usize, CmdErrorKind)> {
+    if let Some(output) = dry_run(cmds, capture) {
+        return Ok((vec![ExitStatus::default(); cmds.len()], output));
+    }
+
+    let stderr_retention = if capture { None } else { cmds.last().unwrap().stderr_retention() };
+    let mut children = Vec::with_capacity(cmds.len());
+    let mut groups = Vec::new();
+    let mut prev_stdout = None;
+    let mut last_stdout = None;
+    for (idx, cmd) in cmds.iter().enumerate() {
+        let is_last = idx + 1 == cmds.len();
+        let stdin = match prev_stdout.take() {
+            Some(stdout) => stdout,
+            None => match stdin_of(cmd, if capture { Stdio::null } else { Stdio::inherit }) {
+                Ok(it) => it,
+                Err(kind) => {
+                    kill_spawned(children);
+                    return Err((idx, kind));
+                }
+            },
+        };
+        let (stdout, stderr) = match (is_last, capture) {
+            (true, true) => (Stdio::piped(), Stdio::piped()),
+            (true, false) if stderr_retention.is_some() => (Stdio::inherit(), Stdio::piped()),
+            (true, false) => (Stdio::inherit(), Stdio::inherit()),
+            // Like in the shell, so that the user sees why a stage failed.
+            (false, _) => (Stdio::piped(), Stdio::inherit()),
+        };
+        let stdout_piped = capture || !is_last;
+        let stdio = if cmd.stdout_redirected() && stdout_piped {
+            Err(CmdErrorKind::StdoutRedirected)
+        } else {
+            redirect(cmd, stdout, stderr, stdout_piped)
+        };
+        let (stdout, stderr, merged) = match stdio {
+            Ok(it) => it,
+            Err(kind) => {
+                kill_spawned(children);
+                return Err((idx, kind));
+            }
+        };
+        let mut command = cmd.command();
+        command.stdin(stdin).stdout(stdout).stderr(stderr);
+        let our_stdin = idx == 0 && !capture && inherits_stdin(cmd);
+        let own_group = cmd.timeout.is_some()
+            && cmd.process_group
+            && sys::new_process_group(&mut command, our_stdin);
+        match command.spawn() {
+            Ok(mut child) => {
+                if own_group {
+                    groups.push(sys::forward_signals(child.id()));
+                }
+                if is_last {
+                    last_stdout = match merged {
+                        Some(it) => Some(Box::new(it) as Pipe),
+                        None => child.stdout.take().map(|it| Box::new(it) as Pipe),
+                    };
+                } else {
+                    prev_stdout = match merged {
+                        Some(it) => Some(Stdio::from(it)),
+                        None => child.stdout.take().map(Stdio::from),
+                    };
+                }
+                children.push(child);
+            }
+            Err(io_err) => {
+                kill_spawned(children);
+                return Err((idx, spawn_err(cmd, io_err)));
+            }
+        }
+    }
+
+    // Only now, as ignored signals are inherited by the children.
+    let _ignore_interrupts = cmds.iter().any(|it| it.interactive).then(sys::ignore_interrupts);
+    let stdin = children[0].stdin.take();
+    thread::scope(|scope| {
+        // Feed stdin from a separate thread, so that a child which fills up
+        // its stdout before reading all of its input doesn't deadlock us.
+        let writer = match (stdin, &cmds[0].stdin_contents) {
+            (Some(mut stdin), Some(stdin_contents)) => Some(scope.spawn(move || {
+                stdin.write_all(stdin_contents)?;
+                stdin.flush()
+            })),
+            _ => None,
+        };
+
+        let last = children.last_mut().unwrap();
+        let last_cmd = cmds.last().unwrap();
+        let has_timeout = cmds.iter().any(|it| it.timeout.is_some());
+        let (stdout_tee, stderr_tee) = match (capture, last_cmd) {
+            (true, Cmd { tee: Some(keep), .. }) => (
+                last_stdout.take().map(|it| Capture::tee(it, io::stdout(), *keep)),
+                last.stderr.take().map(|it| Capture::tee(it, io::stderr(), *keep)),
+            ),
+            (false, _) => match stderr_retention {
+                Some(keep) => (
+                    None,
+                    last.stderr.take().map(|it| Capture::tee_until_exit(it, io::stderr(), keep)),
+                ),
+                None => (None, None),
+            },
+            (true, _) => (None, None),
+        };
+
+        let heartbeat = cmds.iter().find(|it| it.heartbeat.is_some()).map(|cmd| {
+            let (done_tx, done) = mpsc::channel::<()>();
+            let thread = scope.spawn(move || heartbeat(cmd, done));
+            (done_tx, thread)
+        });
+
+        // Tee keeps a bounded amount of output anyway.
+        let limit = if capture && stdout_tee.is_none() { last_cmd.output_limit } else { None };
+        let mut res = if has_timeout {
+            wait_with_deadlines(cmds, children, last_stdout, limit)
+        } else {
+            wait(children, last_stdout, limit)
+        };
+
+        if let Some((done_tx, thread)) = heartbeat {
+            drop(done_tx);
+            thread.join().unwrap();
+        }
+
+        for (tee, is_stdout) in [(stdout_tee, true), (stderr_tee, false)] {
+            let tee = match tee {
+                Some(it) => it,
+                None => continue,
+            };
+            match &mut res {
+                // Only retained stderr is read when not capturing, which
+                // mustn't keep us waiting once the command is done.
+                Ok((_statuses, output)) if !capture => output.stderr = tee.finish_exited(),
+                Ok((_statuses, output)) => {
+                    let buf = if is_stdout { &mut output.stdout } else { &mut output.stderr };
+                    *buf = tee.finish(None)
+                }
+                Err((_idx, CmdErrorKind::Timeout { stdout, stderr, .. })) => {
+                    let buf = if is_stdout { stdout } else { stderr };
+                    *buf = tee.finish_killed()
+                }
+                Err(_) => (),
+            }
+        }
+
+        if let Some(writer) = writer {
+            match writer.join().unwrap() {
+                // The child is free to exit without reading all of stdin,
+                // its exit status is what matters.
+                Err(io_err) if res.is_ok() && io_err.kind() != io::ErrorKind::BrokenPipe => {
+                    return Err((0, CmdErrorKind::Io(io_err)))
+                }
+                _ => (),
+            }
+        }
+        res
+    })
+}
+
+/// In dry-run mode, echoes `cmds` instead of running them, unless all of
+/// them are forced to run.
+fn dry_run(cmds: &[Cmd], capture: bool) -> Option<Output> {
+    if !is_dry_run() || cmds.iter().all(|it| it.force_run) {
+        return None;
+    }
+    // `run` has already echoed the commands, unless they are quiet.
+    if capture || cmds.iter().all(|it| !it.echo_cmd) {
+        echo_cmds(cmds);
+    }
+    Some(Output { status: ExitStatus::default(), stdout: Vec::new(), stderr: Vec::new() })
+}
+
+/// Calls the heartbeat callback of `cmd` every interval, until `done` is
+/// disconnected.
+fn heartbeat(cmd: &Cmd, done: mpsc::Receiver<()>) {
+    let heartbeat = cmd.heartbeat.as_ref().unwrap();
+    let start = Instant::now();
+    let mut next = start;
+    loop {
+        next += heartbeat.interval;
+        let timeout = next.saturating_duration_since(Instant::now());
+        match done.recv_timeout(timeout) {
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            _ => return,
+        }
+        beat(cmd, start.elapsed());
+    }
+}
+
+fn beat(cmd: &Cmd, elapsed: Duration) {
+    match &cmd.heartbeat.as_ref().unwrap().callback {
+        Some(callback) => callback(elapsed),
+        None => eprintln!("still running after {}: {}", FmtDuration(elapsed), cmd),
+    }
+}
+
+fn kill_spawned(children: Vec<process::Child>) {
+    for mut child in children {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// The stdin for `cmd`, unless it is connected to a pipe from another command.
+fn stdin_of(cmd: &Cmd, default: fn() -> Stdio) -> Result<Stdio, CmdErrorKind> {
+    if let Some(path) = &cmd.stdin_file {
+        let path = cmd.redirect_path(path);
+        return match File::open(&path) {
+            Ok(file) => Ok(file.into()),
+            Err(io_err) => Err(CmdErrorKind::File { path, io_err }),
+        };
+    }
+    Ok(if cmd.stdin_contents.is_some() { Stdio::piped() } else { default() })
+}
+
+/// Whether `cmd` gets our stdin, if that's the default.
+fn inherits_stdin(cmd: &Cmd) -> bool {
+    cmd.stdin_file.is_none() && cmd.stdin_contents.is_none()
+}
+
+/// Replaces `stdout` and `stderr` with files or the null device, if `cmd` is
+/// redirected.
+///
+/// With [`Cmd::merge_stderr`], stderr goes wherever stdout goes. If stdout is
+/// to be piped, both are connected to a new pipe, which is returned, so that
+/// the order of the output is preserved.
+fn redirect(
+    cmd: &Cmd,
+    stdout: Stdio,
+    stderr: Stdio,
+    stdout_piped: bool,
+) -> Result<(Stdio, Stdio, Option<io::PipeReader>), CmdErrorKind> {
+    let open = |redirect: &Redirect| -> Result<File, CmdErrorKind> {
+        let path = cmd.redirect_path(&redirect.path);
+        let mut options = OpenOptions::new();
+        options.create(true);
+        if redirect.append {
+            options.append(true);
+        } else {
+            options.write(true).truncate(true);
+        }
+        options.open(&path).map_err(|io_err| CmdErrorKind::File { path, io_err })
+    };
+    let stdout_file = cmd.stdout_file.as_ref().map(open).transpose()?;
+
+    if cmd.merge_stderr {
+        let io = |io_err| CmdErrorKind::Io(io_err);
+        return Ok(match stdout_file {
+            Some(file) => (file.try_clone().map_err(io)?.into(), file.into(), None),
+            None if cmd.null_stdout => (Stdio::null(), Stdio::null(), None),
+            None if stdout_piped => {
+                let (reader, writer) = io::pipe().map_err(io)?;
+                (writer.try_clone().map_err(io)?.into(), writer.into(), Some(reader))
+            }
+            None => (stdout, io::stdout().into(), None),
+        });
+    }
+
+    let stdout = match stdout_file {
+        Some(file) => file.into(),
+        None if cmd.null_stdout => Stdio::null(),
+        None => stdout,
+    };
+    let stderr = match &cmd.stderr_file {
+        Some(redirect) => open(redirect)?.into(),
+        None if cmd.null_stderr => Stdio::null(),
+        None => stderr,
+    };
+    Ok((stdout, stderr, None))
+}
+
+/// A missing working directory and a missing program are both reported as
+/// `NotFound` by the OS, tell them apart.
+pub(crate) fn spawn_err(cmd: &Cmd, io_err: io::Error) -> CmdErrorKind {
+    let path = cmd.env_var(OsStr::new("PATH"));
+    let program = Path::new(&cmd.args[0]);
+    let not_executable = || which::not_executable(program, path.as_deref());
+    match io_err.kind() {
+        io::ErrorKind::NotFound => match cmd.working_dir() {
+            Some(dir) if !dir.is_dir() => CmdErrorKind::Dir { dir, io_err },
+            _ => {
+                let not_executable = not_executable();
+                CmdErrorKind::ProgramMissing { path, io_err, not_executable }
+            }
+        },
+        io::ErrorKind::PermissionDenied => match not_executable() {
+            not_executable if not_executable.is_empty() => CmdErrorKind::Io(io_err),
+            not_executable => CmdErrorKind::ProgramMissing { path, io_err, not_executable },
+        },
+        _ => CmdErrorKind::Io(io_err),
+    }
+}
+
+/// Exits the process like the command with `status` did.
+pub(crate) fn exit_like(status: ExitStatus) -> ! {
+    if let Some(code) = status.code() {
+        process::exit(code)
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            sys::reraise(signal);
+            process::exit(128 + signal)
+        }
+    }
+    process::exit(1)
+}
+
+fn output_limit(limit: usize, stdout: Option<Capture>, stderr: Option<Capture>) -> CmdErrorKind {
+    let finish = |capture: Option<Capture>| {
+        let mut res = capture.map(Capture::finish_killed).unwrap_or_default();
+        res.truncate(limit);
+        res
+    };
+    CmdErrorKind::OutputLimit { limit, stdout: finish(stdout), stderr: finish(stderr) }
+}
+
+/// The stdout of the last command, which is a separate pipe if it is merged
+/// with stderr.
+type Pipe = Box<dyn Read + Send>;
+
+type WaitResult = Result<(Vec<ExitStatus>, Output), (usize, CmdErrorKind)>;
+
+/// Waits for all `children`, capturing the output of the last one, unless it
+/// is more than `limit` bytes.
+fn wait(
+    mut children: Vec<process::Child>,
+    stdout: Option<Pipe>,
+    limit: Option<usize>,
+) -> WaitResult {
+    let last_idx = children.len() - 1;
+    let (overflow_tx, overflow) = mpsc::channel();
+    let last = &mut children[last_idx];
+    let stdout = stdout.map(|it| Capture::capped(it, limit, overflow_tx.clone()));
+    let stderr = last.stderr.take().map(|it| Capture::capped(it, limit, overflow_tx.clone()));
+    drop(overflow_tx);
+
+    // Blocks until both pipes are closed, or one of them overflows.
+    if overflow.recv().is_ok() {
+        let statuses = vec![None; children.len()];
+        kill_all(&mut children, &statuses);
+        return Err((last_idx, output_limit(limit.unwrap(), stdout, stderr)));
+    }
+
+    let mut statuses = Vec::with_capacity(children.len());
+    for (idx, child) in children.iter_mut().enumerate() {
+        statuses.push(child.wait().map_err(|io_err| (idx, CmdErrorKind::Io(io_err)))?);
+    }
+    let output = Output {
+        status: statuses[last_idx],
+        stdout: stdout.map(|it| it.finish(None)).unwrap_or_default(),
+        stderr: stderr.map(|it| it.finish(None)).unwrap_or_default(),
+    };
+    Ok((statuses, output))
+}
+
+/// Like `wait`, but kills the commands which run for longer than their
+/// timeout.
+///
+/// As `std` doesn't provide a way to wait for a child with a timeout, we poll
+/// with `try_wait`. Polling and killing happen on the same thread, so we never
+/// kill a process which was already reaped.
+fn wait_with_deadlines(
+    cmds: &[Cmd],
+    mut children: Vec<process::Child>,
+    stdout: Option<Pipe>,
+    limit: Option<usize>,
+) -> WaitResult {
+    let start = Instant::now();
+    let (overflow_tx, overflow) = mpsc::channel();
+    let last = children.last_mut().unwrap();
+    let stdout = stdout.map(|it| Capture::capped(it, limit, overflow_tx.clone()));
+    let stderr = last.stderr.take().map(|it| Capture::capped(it, limit, overflow_tx.clone()));
+    drop(overflow_tx);
+
+    let mut statuses = vec![None; children.len()];
+    let mut timed_out = None;
+    let mut delay = Duration::from_millis(1);
+    loop {
+        for (idx, child) in children.iter_mut().enumerate() {
+            if statuses[idx].is_some() {
+                continue;
+            }
+            match child.try_wait() {
+                Ok(Some(status)) => statuses[idx] = Some(status),
+                Ok(None) => {
+                    if cmds[idx].timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                        timed_out = Some(idx);
+                        break;
+                    }
+                }
+                Err(io_err) => {
+                    kill_all(&mut children, &statuses);
+                    return Err((idx, CmdErrorKind::Io(io_err)));
+                }
+            }
+        }
+        if timed_out.is_some() || statuses.iter().all(|it| it.is_some()) {
+            break;
+        }
+        if overflow.try_recv().is_ok() {
+            kill_all(&mut children, &statuses);
+            let idx = children.len() - 1;
+            return Err((idx, output_limit(limit.unwrap(), stdout, stderr)));
+        }
+        thread::sleep(delay);
+        delay = (delay * 2).min(Duration::from_millis(50));
+    }
+
+    if let Some(idx) = timed_out {
+        kill_all(&mut children, &statuses);
+        return Err((
+            idx,
+            CmdErrorKind::Timeout {
+                timeout: cmds[idx].timeout.unwrap(),
+                stdout: stdout.map(Capture::finish_killed).unwrap_or_default(),
+                stderr: stderr.map(Capture::finish_killed).unwrap_or_default(),
+            },
+        ));
+    }
+
+    let statuses = statuses.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+    let output = Output {
+        status: *statuses.last().unwrap(),
+        stdout: stdout.map(|it| it.finish(None)).unwrap_or_default(),
+        stderr: stderr.map(|it| it.finish(None)).unwrap_or_default(),
+    };
+    Ok((statuses, output))
+}
+
+fn kill_all(children: &mut [process::Child], statuses: &[Option<ExitStatus>]) {
+    for (child, status) in children.iter_mut().zip(statuses) {
+        if status.is_none() {
+            let _ = sys::kill_process_group(child);
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Reads a pipe to the end on a background thread.
+#[derive(Debug)]
+struct Capture {
+    buf: Arc<Mutex<Vec<u8>>>,
+    done: mpsc::Receiver<()>,
+    keep: usize,
+    /// Set by `finish_exited` for pipes read with `UntilExit`.
+    exited: Option<Arc<AtomicBool>>,
+}
+
+impl Capture {
+    /// Stops reading and notifies `overflow` once more than `limit` bytes
+    /// have been read.
+    fn capped(
+        pipe: impl Read + Send + 'static,
+        limit: Option<usize>,
+        overflow: mpsc::Sender<()>,
+    ) -> Capture {
+        Capture::spawn(pipe, None, usize::MAX, limit.map(|it| (it, overflow)))
+    }
+
+    /// Forwards everything to `sink` as it arrives, keeping only the last
+    /// `keep` bytes.
+    fn tee(
+        pipe: impl Read + Send + 'static,
+        sink: impl Write + Send + 'static,
+        keep: usize,
+    ) -> Capture {
+        Capture::spawn(pipe, Some(Box::new(sink)), keep, None)
+    }
+
+    /// Like `tee`, but stops reading at the end of what is in the pipe once
+    /// `finish_exited` is called, even if a process the command left running
+    /// in the background still holds it.
+    fn tee_until_exit(
+        pipe: process::ChildStderr,
+        sink: impl Write + Send + 'static,
+        keep: usize,
+    ) -> Capture {
+        if !cfg!(unix) {
+            return Capture::tee(pipe, sink, keep);
+        }
+        let exited = Arc::new(AtomicBool::new(false));
+        let pipe = UntilExit { pipe, exited: Arc::clone(&exited) };
+        let mut res = Capture::tee(pipe, sink, keep);
+        res.exited = Some(exited);
+        res
+    }
+
+    fn spawn(
+        mut pipe: impl Read + Send + 'static,
+        mut sink: Option<Box<dyn Write + Send>>,
+        keep: usize,
+        limit: Option<(usize, mpsc::Sender<()>)>,
+    ) -> Capture {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let (tx, done) = mpsc::channel();
+        on_pump_thread(Box::new({
+            let buf = Arc::clone(&buf);
+            move || {
+                let sink = sink.as_mut().map(|it| it as &mut dyn Write);
+                pump(&mut pipe, sink, &buf, keep, limit.as_ref());
+                let _ = tx.send(());
+            }
+        }));
+        Capture { buf, done, keep, exited: None }
+    }
+
+    /// Finishes reading after the command was killed. Someone outside of its
+    /// process group might still hold the pipe, so this doesn't wait for EOF
+    /// indefinitely.
+    fn finish_killed(self) -> Vec<u8> {
+        self.finish(Some(Duration::from_millis(100)))
+    }
+
+    /// Finishes reading after the command exited. Like with `finish_killed`,
+    /// a process it left running in the background might still hold the
+    /// pipe, so without `tee_until_exit` this doesn't wait for EOF
+    /// indefinitely.
+    fn finish_exited(self) -> Vec<u8> {
+        match &self.exited {
+            Some(exited) => {
+                exited.store(true, Ordering::SeqCst);
+                self.finish(None)
+            }
+            None => self.finish_killed(),
+        }
+    }
+
+    fn finish(self, timeout: Option<Duration>) -> Vec<u8> {
+        let _ = match timeout {
+            Some(timeout) => self.done.recv_timeout(timeout).ok(),
+            None => self.done.recv().ok(),
+        };
+        keep_last(std::mem::take(&mut *self.buf.lock().unwrap()), self.keep)
+    }
+}
+
+/// Reads a pipe like usual, until `exited` is set. From then on, reports EOF
+/// as soon as there's nothing left to read.
+struct UntilExit {
+    pipe: process::ChildStderr,
+    exited: Arc<AtomicBool>,
+}
+
+impl Read for UntilExit {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // Check before polling, so that everything written before the
+            // command exited is read.
+            let exited = self.exited.load(Ordering::SeqCst);
+            let timeout = if exited { Duration::ZERO } else { Duration::from_millis(50) };
+            if sys::readable(&self.pipe, timeout) {
+                return self.pipe.read(buf);
+            }
+            if exited {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+type PumpJob = Box<dyn FnOnce() + Send>;
+
+/// Threads which are done with their pipe and wait for the next one, so that
+/// running many short commands doesn't spawn a thread for each.
+static IDLE_PUMPS: Mutex<Vec<(usize, mpsc::Sender<PumpJob>)>> = Mutex::new(Vec::new());
+const MAX_IDLE_PUMPS: usize = 4;
+const PUMP_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn on_pump_thread(job: PumpJob) {
+    let idle = IDLE_PUMPS.lock().unwrap().pop();
+    let job = match idle {
+        Some((_id, pump)) => match pump.send(job) {
+            Ok(()) => return,
+            Err(mpsc::SendError(job)) => job,
+        },
+        None => job,
+    };
+    thread::spawn(move || pump_thread(job));
+}
+
+fn pump_thread(mut job: PumpJob) {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, jobs) = mpsc::channel();
+    loop {
+        job();
+        {
+            let mut idle = IDLE_PUMPS.lock().unwrap();
+            if idle.len() >= MAX_IDLE_PUMPS {
+                return;
+            }
+            idle.push((id, tx.clone()));
+        }
+        job = match jobs.recv_timeout(PUMP_IDLE_TIMEOUT) {
+            Ok(it) => it,
+            Err(_) => {
+                let mut idle = IDLE_PUMPS.lock().unwrap();
+                match idle.iter().position(|(it, _)| *it == id) {
+                    Some(idx) => {
+                        idle.swap_remove(idx);
+                        return;
+                    }
+                    // Someone took us off the list just now, and is about to
+                    // send a job.
+                    None => {
+                        drop(idle);
+                        match jobs.recv() {
+                            Ok(it) => it,
+                            Err(_) => return,
+                        }
+                    }
+                }
+            }
+        };
+    }
+}
+
+/// Reads `pipe` into `buf` until EOF, forwarding it to `sink`, for
+/// `Capture`.
+fn pump(
+    pipe: &mut dyn Read,
+    mut sink: Option<&mut dyn Write>,
+    buf: &Mutex<Vec<u8>>,
+    keep: usize,
+    limit: Option<&(usize, mpsc::Sender<()>)>,
+) {
+    let mut chunk = [0u8; 8 * 1024];
+    while let Ok(n @ 1..) = pipe.read(&mut chunk) {
+        // One write per chunk, so that lines from stdout and stderr mostly
+        // don't get mixed.
+        if let Some(sink) = &mut sink {
+            let _ = sink.write_all(&chunk[..n]);
+            let _ = sink.flush();
+        }
+        let mut buf = buf.lock().unwrap();
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some((limit, overflow)) = limit {
+            if buf.len() > *limit {
+                let _ = overflow.send(());
+                break;
+            }
+        }
+        // Trim in batches, to avoid shifting the buffer on every read.
+        if buf.len() > keep.saturating_mul(2) {
+            let excess = buf.len() - keep;
+            buf.drain(..excess);
+        }
+    }
+}
+
+fn keep_last(mut buf: Vec<u8>, keep: usize) -> Vec<u8> {
+    if buf.len() > keep {
+        buf.drain(..buf.len() - keep);
+    }
+    buf
+}
+
+/// An iterator over the lines of the stdout of a running command, created by
+/// [`Cmd::read_lines`].
+///
+/// Once the output is exhausted, the exit status of the command is checked,
+/// and a failure is reported as the last item. Dropping the iterator early
+/// kills the command.
+#[derive(Debug)]
+pub struct ReadLines {
+    cmd: Option<Cmd>,
+    child: Option<process::Child>,
+    lines: mpsc::Receiver<io::Result<Vec<u8>>>,
+    stderr: Option<Capture>,
+    /// Notified once the stderr exceeds the output limit.
+    stderr_overflow: mpsc::Receiver<()>,
+    deadline: Option<Instant>,
+    /// The stderr of a command which timed out, to report once the lines
+    /// read before are yielded.
+    timed_out: Option<Vec<u8>>,
+    _group: Option<sys::ForwardSignals>,
+}
+
+impl ReadLines {
+    pub(crate) fn spawn(cmd: Cmd) -> XResult<ReadLines> {
+        if is_dry_run() && !cmd.force_run {
+            echo_cmds(std::slice::from_ref(&cmd));
+            let (_tx, lines) = mpsc::channel();
+            let (_tx, stderr_overflow) = mpsc::channel();
+            return Ok(ReadLines {
+                cmd: None,
+                child: None,
+                lines,
+                stderr: None,
+                stderr_overflow,
+                deadline: None,
+                timed_out: None,
+                _group: None,
+            });
+        }
+        if cmd.stdout_redirected() {
+            return Err(CmdErrorKind::StdoutRedirected.err(cmd));
+        }
+        let stdio = stdin_of(&cmd, Stdio::null)
+            .and_then(|stdin| Ok((stdin, redirect(&cmd, Stdio::piped(), Stdio::piped(), true)?)));
+        let (stdin, (stdout, stderr, merged)) = match stdio {
+            Ok(it) => it,
+            Err(kind) => return Err(kind.err(cmd)),
+        };
+        let mut command = cmd.command();
+        command.stdin(stdin).stdout(stdout).stderr(stderr);
+        // Dropping the iterator early kills the command.
+        let own_group = cmd.process_group && sys::new_process_group(&mut command, false);
+        let spawned = command.spawn();
+        // The command holds the write end of a merged pipe.
+        drop(command);
+        let mut child = match spawned {
+            Ok(it) => it,
+            Err(io_err) => return Err(spawn_err(&cmd, io_err).err(cmd)),
+        };
+        let group = own_group.then(|| sys::forward_signals(child.id()));
+        let deadline = cmd.timeout.map(|it| Instant::now() + it);
+
+        if let (Some(mut stdin), Some(stdin_contents)) =
+            (child.stdin.take(), cmd.stdin_contents.clone())
+        {
+            thread::spawn(move || {
+                let _ = stdin.write_all(&stdin_contents);
+            });
+        }
+        let (overflow_tx, stderr_overflow) = mpsc::channel();
+        let stderr =
+            child.stderr.take().map(|it| Capture::capped(it, cmd.output_limit, overflow_tx));
+        let stdout: Pipe = match merged {
+            Some(it) => Box::new(it),
+            None => Box::new(child.stdout.take().unwrap()),
+        };
+        let stdout = BufReader::new(stdout);
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in stdout.split(b'\n') {
+                let line = line.map(|mut line| {
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+                    line
+                });
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(ReadLines {
+            cmd: Some(cmd),
+            child: Some(child),
+            lines,
+            stderr,
+            stderr_overflow,
+            deadline,
+            timed_out: None,
+            _group: group,
+        })
+    }
+
+    fn kill(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = sys::kill_process_group(child);
+            let _ = child.wait();
+        }
+        self._group = None;
+    }
+
+    fn fail(&mut self, kind: CmdErrorKind) -> Option<XResult<String>> {
+        self.kill();
+        Some(Err(kind.err(self.cmd.take()?)))
+    }
+
+    fn finish(&mut self) -> Option<XResult<String>> {
+        let child = self.child.as_mut()?;
+        let cmd = self.cmd.take()?;
+        let status = child.wait();
+        self._group = None;
+        let status = match status {
+            Ok(it) => it,
+            Err(io_err) => return Some(Err(CmdErrorKind::Io(io_err).err(cmd))),
+        };
+        let mut stderr = self.stderr.take().map(|it| it.finish(None)).unwrap_or_default();
+        if let Some(limit) = cmd.output_limit.filter(|it| stderr.len() > *it) {
+            stderr.truncate(limit);
+            let kind = CmdErrorKind::OutputLimit { limit, stdout: Vec::new(), stderr };
+            return Some(Err(kind.err(cmd)));
+        }
+        if status.success() || cmd.ignore_status {
+            return None;
+        }
+        Some(Err(CmdErrorKind::NonZeroStatus { status, stderr }.err(cmd)))
+    }
+}
+
+impl Iterator for ReadLines {
+    type Item = XResult<String>;
+
+    fn next(&mut self) -> Option<XResult<String>> {
+        self.cmd.as_ref()?;
+        let line = match self.deadline {
+            Some(deadline) => {
+                match self.lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(line) => Some(line),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => None,
+                    // Gave up on reading the rest after killing the command.
+                    Err(mpsc::RecvTimeoutError::Timeout) if self.timed_out.is_some() => None,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if let Some(child) = &mut self.child {
+                            let _ = sys::kill_process_group(child);
+                        }
+                        let stderr =
+                            self.stderr.take().map(Capture::finish_killed).unwrap_or_default();
+                        // Yield what is left in the pipe first, like an
+                        // incomplete last line.
+                        self.timed_out = Some(stderr);
+                        self.deadline = Some(Instant::now() + Duration::from_millis(100));
+                        return self.next();
+                    }
+                }
+            }
+            None => self.lines.recv().ok(),
+        };
+        if self.stderr_overflow.try_recv().is_ok() {
+            let limit = self.cmd.as_ref()?.output_limit.unwrap();
+            let kind = output_limit(limit, None, self.stderr.take());
+            return self.fail(kind);
+        }
+        match line {
+            Some(Ok(line)) => match String::from_utf8(line) {
+                Ok(line) => Some(Ok(line)),
+                Err(utf8_err) => self.fail(CmdErrorKind::NonUtf8Stdout(utf8_err)),
+            },
+            Some(Err(io_err)) => self.fail(CmdErrorKind::Io(io_err)),
+            None => match self.timed_out.take() {
+                Some(stderr) => {
+                    let timeout = self.cmd.as_ref()?.timeout.unwrap();
+                    self.fail(CmdErrorKind::Timeout { timeout, stdout: Vec::new(), stderr })
+                }
+                None => self.finish(),
+            },
+        }
+    }
+}
+
+impl Drop for ReadLines {
+    fn drop(&mut self) {
+        if self.cmd.is_some() {
+            self.kill()
+        }
+    }
+}
+
+/// A handle to a command running in the background, created by
+/// [`Cmd::spawn`].
+///
+/// Dropping the handle kills the command together with the processes it
+/// started (on Unix, the command runs in its own
+/// [process group](crate::Cmd::process_group), on Windows only the command
+/// itself is killed), unless the command was [detached](Child::detach).
+#[derive(Debug)]
+pub struct Child {
+    cmd: Cmd,
+    /// `None` in dry-run mode.
+    child: Option<process::Child>,
+    detached: bool,
+    _group: Option<sys::ForwardSignals>,
+}
+
+impl Child {
+    pub(crate) fn spawn(cmd: Cmd) -> XResult<Child> {
+        if is_dry_run() && !cmd.force_run {
+            return Ok(Child { cmd, child: None, detached: false, _group: None });
+        }
+        let stdio = stdin_of(&cmd, Stdio::inherit).and_then(|stdin| {
+            Ok((stdin, redirect(&cmd, Stdio::inherit(), Stdio::inherit(), false)?))
+        });
+        let (stdin, (stdout, stderr, _)) = match stdio {
+            Ok(it) => it,
+            Err(kind) => return Err(kind.err(cmd)),
+        };
+        let mut command = cmd.command();
+        command.stdin(stdin).stdout(stdout).stderr(stderr);
+        let own_group =
+            cmd.process_group && sys::new_process_group(&mut command, inherits_stdin(&cmd));
+        let mut child = match command.spawn() {
+            Ok(it) => it,
+            Err(io_err) => return Err(spawn_err(&cmd, io_err).err(cmd)),
+        };
+        let group = own_group.then(|| sys::forward_signals(child.id()));
+        if let (Some(mut stdin), Some(stdin_contents)) =
+            (child.stdin.take(), cmd.stdin_contents.clone())
+        {
+            thread::spawn(move || {
+                let _ = stdin.write_all(&stdin_contents);
+            });
+        }
+        Ok(Child { cmd, child: Some(child), detached: false, _group: group })
+    }
+
+    /// The OS process id, or zero in [dry-run mode](crate::set_dry_run).
+    pub fn id(&self) -> u32 {
+        self.child.as_ref().map_or(0, process::Child::id)
+    }
+
+    /// Waits for the command to finish. Like with [`Cmd::run`], a non-zero
+    /// exit status is an error, unless [`Cmd::ignore_status`] is set.
+    pub fn wait(mut self) -> XResult<ExitStatus> {
+        let status = match &mut self.child {
+            Some(child) => child.wait(),
+            None => Ok(ExitStatus::default()),
+        };
+        self.check(status)
+    }
+
+    /// Like [`Child::wait`], but doesn't block the current thread. Dropping
+    /// the future kills the command, like dropping the handle does.
+    #[cfg(feature = "async")]
+    pub fn wait_async(mut self) -> CmdFuture<XResult<ExitStatus>> {
+        CmdFuture::new(async move {
+            // `std` can't tell us when the child exits, so we poll, like
+            // `wait_with_deadlines` does.
+            let mut delay = Duration::from_millis(1);
+            loop {
+                if let Some(status) = self.try_wait()? {
+                    return Ok(status);
+                }
+                async_io::Timer::after(delay).await;
+                delay = (delay * 2).min(Duration::from_millis(50));
+            }
+        })
+    }
+
+    /// Returns the exit status if the command has finished, checking it like
+    /// [`Child::wait`].
+    pub fn try_wait(&mut self) -> XResult<Option<ExitStatus>> {
+        let status = match &mut self.child {
+            Some(child) => child.try_wait(),
+            None => Ok(Some(ExitStatus::default())),
+        };
+        match status {
+            Ok(Some(status)) => self.check(Ok(status)).map(Some),
+            Ok(None) => Ok(None),
+            Err(io_err) => Err(CmdErrorKind::Io(io_err).err(self.cmd.clone())),
+        }
+    }
+
+    pub fn kill(&mut self) -> XResult<()> {
+        match &mut self.child {
+            Some(child) => sys::kill_process_group(child)
+                .map_err(|io_err| CmdErrorKind::Io(io_err).err(self.cmd.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Lets the command outlive the handle.
+    pub fn detach(mut self) {
+        self.detached = true;
+        // Nor do our signals reach it anymore.
+        self._group = None;
+    }
+
+    fn check(&self, status: io::Result<ExitStatus>) -> XResult<ExitStatus> {
+        match status {
+            Ok(status) if status.success() || self.cmd.ignore_status => Ok(status),
+            Ok(status) => {
+                Err(CmdErrorKind::NonZeroStatus { status, stderr: Vec::new() }
+                    .err(self.cmd.clone()))
+            }
+            Err(io_err) => Err(CmdErrorKind::Io(io_err).err(self.cmd.clone())),
+        }
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        let child = match &mut self.child {
+            Some(it) if !self.detached => it,
+            _ => return,
+        };
+        if let Ok(Some(_status)) = child.try_wait() {
+            return;
+        }
+        let _ = sys::kill_process_group(child);
+        let _ = child.wait();
+    }
+}
+
+pub(crate) use self::sys::shell_command;
+
+#[cfg(unix)]
+mod sys {
+    use std::{
+        ffi::OsString,
+        io,
+        os::unix::{io::AsRawFd, process::CommandExt},
+        process::{self, Command},
+        sync::{
+            atomic::{AtomicI32, AtomicUsize, Ordering},
+            Once,
+        },
+        time::Duration,
+    };
+
+    extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+        fn signal(sig: i32, handler: usize) -> usize;
+    }
+    const SIGINT: i32 = 2;
+    const SIGQUIT: i32 = 3;
+    const SIGKILL: i32 = 9;
+    const SIG_IGN: usize = 1;
+    const SIG_ERR: usize = usize::MAX;
+
+    pub(super) struct IgnoreInterrupts {
+        prev: [(i32, usize); 2],
+    }
+
+    pub(super) fn ignore_interrupts() -> IgnoreInterrupts {
+        let prev = [SIGINT, SIGQUIT].map(|sig| (sig, unsafe { signal(sig, SIG_IGN) }));
+        IgnoreInterrupts { prev }
+    }
+
+    impl Drop for IgnoreInterrupts {
+        fn drop(&mut self) {
+            for (sig, handler) in self.prev {
+                if handler != SIG_ERR {
+                    unsafe { signal(sig, handler) };
+                }
+            }
+        }
+    }
+
+    extern "C" {
+        fn getpid() -> i32;
+    }
+    const SIG_DFL: usize = 0;
+
+    /// Kills us with `sig`, unless it is caught or ignored.
+    pub(super) fn reraise(sig: i32) {
+        unsafe {
+            signal(sig, SIG_DFL);
+            kill(getpid(), sig);
+        }
+    }
+
+    /// `"$@"` runs the program and the arguments as they are, with builtins
+    /// found before executables on `PATH`.
+    pub(crate) fn shell_command(args: &[OsString]) -> Command {
+        let mut res = Command::new("sh");
+        res.arg("-c").arg("\"$@\"").arg("sh").args(args);
+        res
+    }
+
+    extern "C" {
+        fn getpgrp() -> i32;
+        fn tcgetpgrp(fd: i32) -> i32;
+    }
+    const SIGTERM: i32 = 15;
+
+    /// Puts the command into its own process group, unless it inherits our
+    /// stdin while we are in the foreground of a terminal. Reading from the
+    /// terminal would stop it outside of the foreground. Returns whether it
+    /// gets its own group.
+    pub(super) fn new_process_group(command: &mut Command, inherits_stdin: bool) -> bool {
+        if inherits_stdin && unsafe { tcgetpgrp(0) == getpgrp() } {
+            return false;
+        }
+        command.process_group(0);
+        true
+    }
+
+    /// The process groups of the running commands, zero for a free slot.
+    static GROUPS: [AtomicI32; 64] = [const { AtomicI32::new(0) }; 64];
+    /// The handlers of SIGINT and SIGTERM from before `forward`.
+    static PREV_HANDLERS: [AtomicUsize; 2] = [const { AtomicUsize::new(SIG_DFL) }; 2];
+    static INSTALL: Once = Once::new();
+
+    /// Keeps forwarding the SIGINT and SIGTERM we get to the process group of
+    /// a command until dropped, as they would reach it without its own group.
+    #[derive(Debug)]
+    pub(super) struct ForwardSignals {
+        slot: Option<usize>,
+    }
+
+    pub(super) fn forward_signals(pid: u32) -> ForwardSignals {
+        INSTALL.call_once(|| {
+            for (&sig, prev) in [SIGINT, SIGTERM].iter().zip(&PREV_HANDLERS) {
+                let handler = unsafe { signal(sig, forward as extern "C" fn(i32) as usize) };
+                match handler {
+                    // Someone doesn't want to be interrupted, respect that.
+                    SIG_IGN | SIG_ERR => unsafe {
+                        signal(sig, handler);
+                    },
+                    _ => prev.store(handler, Ordering::SeqCst),
+                }
+            }
+        });
+        let pgid = pid as i32;
+        let slot = GROUPS.iter().position(|it| {
+            it.compare_exchange(0, pgid, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        });
+        ForwardSignals { slot }
+    }
+
+    impl Drop for ForwardSignals {
+        fn drop(&mut self) {
+            if let Some(slot) = self.slot {
+                GROUPS[slot].store(0, Ordering::SeqCst);
+            }
+        }
+    }
+
+    extern "C" fn forward(sig: i32) {
+        for group in &GROUPS {
+            let pgid = group.load(Ordering::SeqCst);
+            if pgid != 0 {
+                unsafe { kill(-pgid, sig) };
+            }
+        }
+        let prev = &PREV_HANDLERS[if sig == SIGINT { 0 } else { 1 }];
+        match prev.load(Ordering::SeqCst) {
+            SIG_DFL => reraise(sig),
+            handler => {
+                let handler: extern "C" fn(i32) = unsafe { std::mem::transmute(handler) };
+                handler(sig)
+            }
+        }
+    }
+
+    #[repr(C)]
+    struct PollFd {
+        fd: i32,
+        events: i16,
+        revents: i16,
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    type NFds = std::os::raw::c_ulong;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    type NFds = std::os::raw::c_uint;
+    extern "C" {
+        fn poll(fds: *mut PollFd, nfds: NFds, timeout: i32) -> i32;
+    }
+    const POLLIN: i16 = 1;
+
+    /// Waits up to `timeout` for `pipe` to have something to read, or to be
+    /// closed. Errors count as readable, so that the read reports them.
+    pub(super) fn readable(pipe: &impl AsRawFd, timeout: Duration) -> bool {
+        let mut fd = PollFd { fd: pipe.as_raw_fd(), events: POLLIN, revents: 0 };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        loop {
+            match unsafe { poll(&mut fd, 1, timeout) } {
+                0 => return false,
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => (),
+                _ => return true,
+            }
+        }
+    }
+
+    pub(super) fn kill_process_group(child: &mut process::Child) -> io::Result<()> {
+        // Without a group of its own, we kill just the child.
+        if kill_group(child.id()) {
+            return Ok(());
+        }
+        child.kill()
+    }
+
+    /// Kills the process group led by `pid`, if there is one. The group id
+    /// of a leader is its pid.
+    pub(super) fn kill_group(pid: u32) -> bool {
+        unsafe { kill(-(pid as i32), SIGKILL) == 0 }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::{
+        ffi::OsString,
+        io, iter,
+        process::{self, Command},
+        time::Duration,
+    };
+
+    /// Builds a `cmd /D /C` line which passes every argument as is.
+    ///
+    /// cmd.exe has no quoting of its own: it interprets special characters
+    /// outside of double quotes, and expands `%VAR%` everywhere. So each
+    /// argument is first quoted the way programs split their command line,
+    /// and then every special character, quotes included, is escaped with `^`,
+    /// leaving nothing for cmd.exe to interpret. Unpaired surrogates are
+    /// replaced with U+FFFD.
+    pub(crate) fn shell_command(args: &[OsString]) -> Command {
+        let mut line = String::new();
+        for arg in args {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            for c in quote_arg(&arg.to_string_lossy()).chars() {
+                if "()%!^\"<>&|".contains(c) {
+                    line.push('^');
+                }
+                line.push(c);
+            }
+        }
+        let mut res = Command::new("cmd");
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            res.raw_arg("/D /C").raw_arg(line);
+        }
+        #[cfg(not(windows))]
+        res.arg("/D").arg("/C").arg(line);
+        res
+    }
+
+    /// Quotes `arg` like `CommandLineToArgvW` expects it.
+    fn quote_arg(arg: &str) -> String {
+        if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+            return arg.to_string();
+        }
+        let mut res = String::from('"');
+        let mut backslashes = 0;
+        for c in arg.chars() {
+            match c {
+                '\\' => backslashes += 1,
+                '"' => {
+                    res.extend(iter::repeat_n('\\', backslashes + 1));
+                    backslashes = 0;
+                }
+                _ => backslashes = 0,
+            }
+            res.push(c);
+        }
+        res.extend(iter::repeat_n('\\', backslashes));
+        res.push('"');
+        res
+    }
+
+    // See `Cmd::process_group`, only the command itself is killed.
+    pub(super) fn new_process_group(_command: &mut Command, _inherits_stdin: bool) -> bool {
+        false
+    }
+
+    #[derive(Debug)]
+    pub(super) struct ForwardSignals;
+
+    pub(super) fn forward_signals(_pid: u32) -> ForwardSignals {
+        ForwardSignals
+    }
+
+    // Unused, `Capture::tee_until_exit` needs `poll`.
+    pub(super) fn readable<T>(_pipe: &T, _timeout: Duration) -> bool {
+        true
+    }
+
+    pub(super) struct IgnoreInterrupts;
+
+    pub(super) fn ignore_interrupts() -> IgnoreInterrupts {
+        IgnoreInterrupts
+    }
+
+    pub(super) fn kill_process_group(child: &mut process::Child) -> io::Result<()> {
+        child.kill()
+    }
+
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    pub(super) fn kill_group(_pid: u32) -> bool {
+        false
+    }
+}
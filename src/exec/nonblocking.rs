@@ -0,0 +1,354 @@
+//! Running commands without blocking an async runtime.
+//!
+//! The processes are managed by `async-process`, and the timers are the ones
+//! of `async-io`, which work with any runtime.
+
+use std::{
+    fmt,
+    future::Future,
+    io::{self, Write},
+    pin::Pin,
+    process::{ExitStatus, Output, Stdio},
+    slice,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use futures_lite::{future, AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use super::{beat, dry_run, inherits_stdin, keep_last, redirect, spawn_err, stdin_of, sys};
+use crate::{error::CmdErrorKind, Cmd};
+
+/// The result of a command run without blocking, created by
+/// [`Cmd::run_async`](crate::Cmd::run_async) and friends.
+///
+/// The command starts when the future is first polled, and dropping the
+/// future kills it, together with its [process group](Cmd::process_group),
+/// if it has one.
+#[must_use = "futures do nothing unless polled"]
+pub struct CmdFuture<T> {
+    inner: Pin<Box<dyn Future<Output = T> + Send>>,
+}
+
+impl<T> CmdFuture<T> {
+    pub(crate) fn new(fut: impl Future<Output = T> + Send + 'static) -> CmdFuture<T> {
+        CmdFuture { inner: Box::pin(fut) }
+    }
+}
+
+impl<T> fmt::Debug for CmdFuture<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CmdFuture").finish_non_exhaustive()
+    }
+}
+
+impl<T> Future for CmdFuture<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+type Pipe = Box<dyn AsyncRead + Send + Unpin>;
+
+enum Waited {
+    Exited(io::Result<ExitStatus>),
+    TimedOut,
+    Overflow,
+}
+
+/// Like [`exec_pipeline`](super::exec_pipeline) for a single command.
+pub(crate) async fn exec(cmd: &Cmd, capture: bool) -> Result<Output, CmdErrorKind> {
+    if let Some(output) = dry_run(slice::from_ref(cmd), capture) {
+        return Ok(output);
+    }
+
+    let stderr_retention = if capture { None } else { cmd.stderr_retention() };
+    let stdin = stdin_of(cmd, if capture { Stdio::null } else { Stdio::inherit })?;
+    let (stdout, stderr) = match capture {
+        true => (Stdio::piped(), Stdio::piped()),
+        false if stderr_retention.is_some() => (Stdio::inherit(), Stdio::piped()),
+        false => (Stdio::inherit(), Stdio::inherit()),
+    };
+    if cmd.stdout_redirected() && capture {
+        return Err(CmdErrorKind::StdoutRedirected);
+    }
+    let (stdout, stderr, merged) = redirect(cmd, stdout, stderr, capture)?;
+    let mut command = cmd.command();
+    let our_stdin = !capture && inherits_stdin(cmd);
+    let own_group = cmd.timeout.is_some()
+        && cmd.process_group
+        && sys::new_process_group(&mut command, our_stdin);
+    let child = {
+        // The stdio has to be set on the async command, or it is reset.
+        let mut command = async_process::Command::from(command);
+        command.stdin(stdin).stdout(stdout).stderr(stderr).kill_on_drop(true);
+        command.spawn().map_err(|io_err| spawn_err(cmd, io_err))?
+    };
+    let mut child =
+        Running { _signals: own_group.then(|| sys::forward_signals(child.id())), own_group, child };
+
+    let mut stdout_pipe = match merged {
+        Some(it) => Some(async_pipe(it).map_err(CmdErrorKind::Io)?),
+        None => child.child.stdout.take().map(|it| Box::new(it) as Pipe),
+    };
+    let mut stderr_pipe = child.child.stderr.take().map(|it| Box::new(it) as Pipe);
+    let write_stdin = {
+        let stdin = child.child.stdin.take();
+        async move {
+            match (stdin, &cmd.stdin_contents) {
+                (Some(mut stdin), Some(stdin_contents)) => {
+                    stdin.write_all(stdin_contents).await?;
+                    stdin.close().await
+                }
+                _ => Ok(()),
+            }
+        }
+    };
+
+    let (mut out_sink, mut err_sink) = (io::stdout(), io::stderr());
+    let tee = if capture { cmd.tee } else { stderr_retention };
+    let (out_tee, err_tee) = sinks(tee, capture, &mut out_sink, &mut err_sink);
+    // Tee keeps a bounded amount of output anyway.
+    let limit = if capture && tee.is_none() { cmd.output_limit } else { None };
+    let keep = tee.unwrap_or(usize::MAX);
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let mut stdin_res = Ok(());
+
+    let waited = {
+        let status = child.child.status();
+        // Stops at the first pipe which overflows.
+        let read = future::try_zip(
+            pump(stdout_pipe.as_mut(), out_tee, &mut stdout, keep, limit),
+            pump(stderr_pipe.as_mut(), err_tee, &mut stderr, keep, limit),
+        );
+        let wait = async {
+            if capture {
+                if read.await.is_err() {
+                    return Waited::Overflow;
+                }
+                Waited::Exited(status.await)
+            } else {
+                // Only retained stderr is read when not capturing, which
+                // mustn't keep us waiting once the command is done.
+                let read = async {
+                    let _ = read.await;
+                    future::pending().await
+                };
+                Waited::Exited(future::or(status, read).await)
+            }
+        };
+        let deadline = async {
+            match cmd.timeout {
+                Some(timeout) => Timer::after(timeout).await,
+                None => future::pending().await,
+            };
+            Waited::TimedOut
+        };
+        // Feed stdin alongside, so that a child which fills up its stdout
+        // before reading all of its input doesn't deadlock us.
+        let write_stdin = async {
+            stdin_res = write_stdin.await;
+            future::pending().await
+        };
+        future::or(future::or(wait, deadline), future::or(heartbeat(cmd), write_stdin)).await
+    };
+
+    match waited {
+        Waited::Exited(Ok(status)) => {
+            if !capture {
+                // What was written before the command exited is in the pipe.
+                let (_, err_tee) = sinks(tee, capture, &mut out_sink, &mut err_sink);
+                pump_ready(stderr_pipe.as_mut(), err_tee, &mut stderr, keep).await;
+            }
+            match stdin_res {
+                // The child is free to exit without reading all of stdin,
+                // its exit status is what matters.
+                Err(io_err) if io_err.kind() != io::ErrorKind::BrokenPipe => {
+                    Err(CmdErrorKind::Io(io_err))
+                }
+                _ => Ok(Output {
+                    status,
+                    stdout: keep_last(stdout, keep),
+                    stderr: keep_last(stderr, keep),
+                }),
+            }
+        }
+        Waited::Exited(Err(io_err)) => Err(CmdErrorKind::Io(io_err)),
+        Waited::TimedOut => {
+            child.kill().await;
+            let (out_tee, err_tee) = sinks(tee, capture, &mut out_sink, &mut err_sink);
+            finish_killed(future::zip(
+                pump(stdout_pipe.as_mut(), out_tee, &mut stdout, keep, None),
+                pump(stderr_pipe.as_mut(), err_tee, &mut stderr, keep, None),
+            ))
+            .await;
+            Err(CmdErrorKind::Timeout {
+                timeout: cmd.timeout.unwrap(),
+                stdout: keep_last(stdout, keep),
+                stderr: keep_last(stderr, keep),
+            })
+        }
+        Waited::Overflow => {
+            child.kill().await;
+            let limit = limit.unwrap();
+            finish_killed(future::zip(
+                pump(stdout_pipe.as_mut(), None, &mut stdout, keep, None),
+                pump(stderr_pipe.as_mut(), None, &mut stderr, keep, None),
+            ))
+            .await;
+            stdout.truncate(limit);
+            stderr.truncate(limit);
+            Err(CmdErrorKind::OutputLimit { limit, stdout, stderr })
+        }
+    }
+}
+
+type Sink<'a> = &'a mut (dyn Write + Send);
+
+/// Where the output is forwarded with `tee`: both stdout and stderr when
+/// capturing, otherwise just the retained stderr.
+fn sinks<'a>(
+    tee: Option<usize>,
+    capture: bool,
+    out_sink: &'a mut io::Stdout,
+    err_sink: &'a mut io::Stderr,
+) -> (Option<Sink<'a>>, Option<Sink<'a>>) {
+    match tee {
+        Some(_) if capture => (Some(out_sink), Some(err_sink)),
+        Some(_) => (None, Some(err_sink)),
+        None => (None, None),
+    }
+}
+
+/// The spawned command, killed when dropped before it exits.
+struct Running {
+    child: async_process::Child,
+    own_group: bool,
+    _signals: Option<sys::ForwardSignals>,
+}
+
+impl Running {
+    async fn kill(&mut self) {
+        if !(self.own_group && sys::kill_group(self.child.id())) {
+            let _ = self.child.kill();
+        }
+        let _ = self.child.status().await;
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        // `async-process` kills just the command itself.
+        if self.own_group && matches!(self.child.try_status(), Ok(None)) {
+            sys::kill_group(self.child.id());
+        }
+    }
+}
+
+#[cfg(unix)]
+fn async_pipe(pipe: io::PipeReader) -> io::Result<Pipe> {
+    Ok(Box::new(async_io::Async::new(pipe)?))
+}
+#[cfg(not(unix))]
+fn async_pipe(pipe: io::PipeReader) -> io::Result<Pipe> {
+    Ok(Box::new(blocking::Unblock::new(pipe)))
+}
+
+/// More than the limit was read from a pipe.
+struct Overflow;
+
+/// Like [`super::pump`]: reads `pipe` into `buf` until EOF, forwarding it to
+/// `sink`.
+async fn pump(
+    pipe: Option<&mut Pipe>,
+    sink: Option<Sink<'_>>,
+    buf: &mut Vec<u8>,
+    keep: usize,
+    limit: Option<usize>,
+) -> Result<(), Overflow> {
+    match pipe {
+        Some(pipe) => pump_chunks(pipe, sink, buf, keep, limit, false).await,
+        None => Ok(()),
+    }
+}
+
+/// Like `pump`, but stops as soon as there's nothing left to read, for a
+/// command which exited, but might have left a process holding the pipe.
+async fn pump_ready(
+    pipe: Option<&mut Pipe>,
+    sink: Option<Sink<'_>>,
+    buf: &mut Vec<u8>,
+    keep: usize,
+) {
+    if let Some(pipe) = pipe {
+        let _ = pump_chunks(pipe, sink, buf, keep, None, true).await;
+    }
+}
+
+async fn pump_chunks(
+    pipe: &mut Pipe,
+    mut sink: Option<Sink<'_>>,
+    buf: &mut Vec<u8>,
+    keep: usize,
+    limit: Option<usize>,
+    ready_only: bool,
+) -> Result<(), Overflow> {
+    let mut chunk = [0u8; 8 * 1024];
+    loop {
+        let read = pipe.read(&mut chunk);
+        let read = if ready_only { future::poll_once(read).await } else { Some(read.await) };
+        let n = match read {
+            Some(Ok(n @ 1..)) => n,
+            _ => return Ok(()),
+        };
+        // One write per chunk, so that lines from stdout and stderr mostly
+        // don't get mixed.
+        if let Some(sink) = &mut sink {
+            let _ = sink.write_all(&chunk[..n]);
+            let _ = sink.flush();
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if limit.is_some_and(|limit| buf.len() > limit) {
+            return Err(Overflow);
+        }
+        // Trim in batches, to avoid shifting the buffer on every read.
+        if buf.len() > keep.saturating_mul(2) {
+            let excess = buf.len() - keep;
+            buf.drain(..excess);
+        }
+    }
+}
+
+/// Finishes `read` after the command was killed. Like with
+/// `Capture::finish_killed`, someone outside of its process group might
+/// still hold the pipes, so this doesn't wait for EOF indefinitely.
+async fn finish_killed(read: impl Future) {
+    let grace = async {
+        Timer::after(Duration::from_millis(100)).await;
+    };
+    future::or(
+        async {
+            read.await;
+        },
+        grace,
+    )
+    .await
+}
+
+/// Calls the heartbeat callback of `cmd` every interval. Never finishes.
+async fn heartbeat<T>(cmd: &Cmd) -> T {
+    let interval = match &cmd.heartbeat {
+        Some(it) => it.interval,
+        None => return future::pending().await,
+    };
+    let start = Instant::now();
+    let mut next = start;
+    loop {
+        next += interval;
+        Timer::at(next).await;
+        beat(cmd, start.elapsed());
+    }
+}
@@ -1,82 +1,1019 @@
 use std::path::{Path, PathBuf};
 
-use crate::{error::fs_err, Result};
+use crate::{dry_run, error::fs_err, Quoted, Result};
 
-pub fn rm_rf(path: impl AsRef<Path>) -> Result<()> {
+/// Removes `path`, a file or a whole directory tree, and returns what was
+/// removed. A missing `path` is not an error.
+pub fn rm_rf(path: impl AsRef<Path>) -> Result<RemoveStats> {
     _rm_rf(path.as_ref())
 }
-fn _rm_rf(path: &Path) -> Result<()> {
-    if !path.exists() {
-        return Ok(());
+fn _rm_rf(path: &Path) -> Result<RemoveStats> {
+    if dry_run::skip(format_args!("rm -rf {}", Quoted(path))) {
+        return Ok(RemoveStats::default());
     }
-    with_path(path, if path.is_file() { std::fs::remove_file(path) } else { remove_dir_all(path) })
+    rm_rf_aux(path)
+}
+fn rm_rf_aux(path: &Path) -> Result<RemoveStats> {
+    // Not `exists`, which follows symlinks, so that dangling ones are removed.
+    let meta = match path.symlink_metadata() {
+        Ok(it) => it,
+        Err(io_err) if io_err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(RemoveStats::default())
+        }
+        Err(io_err) => return Err(fs_err(path.to_path_buf(), io_err)),
+    };
+    if !meta.is_dir() {
+        with_path(path, remove_file(path))?;
+        let bytes = if meta.is_file() { meta.len() } else { 0 };
+        return Ok(RemoveStats { files: 1, dirs: 0, bytes });
+    }
+    let mut stats = RemoveStats::default();
+    // Explicit work list rather than recursion, so deep trees are fine. A
+    // directory is removed once its entries are.
+    let mut todo = vec![(path.to_path_buf(), false)];
+    while let Some((dir, emptied)) = todo.pop() {
+        if emptied {
+            with_path(&dir, remove_dir(&dir))?;
+            stats.dirs += 1;
+            continue;
+        }
+        let entries = with_path(&dir, std::fs::read_dir(&dir))?;
+        todo.push((dir.clone(), true));
+        for entry in entries {
+            let entry = with_path(&dir, entry)?;
+            let path = entry.path();
+            let file_type = with_path(&path, entry.file_type())?;
+            if file_type.is_dir() {
+                todo.push((path, false));
+                continue;
+            }
+            // The size is only for the stats, not being able to get it
+            // shouldn't stop the removal.
+            let bytes =
+                if file_type.is_file() { entry.metadata().map_or(0, |it| it.len()) } else { 0 };
+            with_path(&path, remove_file(&path))?;
+            stats.files += 1;
+            stats.bytes += bytes;
+        }
+    }
+    Ok(stats)
+}
+
+/// What [`rm_rf`] removed. Symlinks count as files of zero bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RemoveStats {
+    pub files: usize,
+    pub dirs: usize,
+    pub bytes: u64,
 }
 
+/// Reads the file at `path` as UTF-8, stripping a leading byte order mark.
+///
+/// The error for a file which is not UTF-8 tells the offset of the first
+/// invalid byte. See [`read_file_lossy`] and [`read_file_bytes`] for
+/// files which might not be valid UTF-8.
 pub fn read_file(path: impl AsRef<Path>) -> Result<String> {
     _read_file(path.as_ref())
 }
 fn _read_file(path: &Path) -> Result<String> {
-    with_path(path, std::fs::read_to_string(path))
+    let bytes = _read_file_bytes(path)?;
+    let text = strip_bom(&bytes);
+    match std::str::from_utf8(text) {
+        Ok(it) => Ok(it.to_string()),
+        Err(err) => {
+            let offset = bytes.len() - text.len() + err.valid_up_to();
+            let msg = format!("invalid UTF-8 at byte offset {}", offset);
+            with_path(path, Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg)))
+        }
+    }
+}
+
+/// Like [`read_file`], but replaces invalid UTF-8 with `U+FFFD`.
+pub fn read_file_lossy(path: impl AsRef<Path>) -> Result<String> {
+    _read_file_lossy(path.as_ref())
+}
+fn _read_file_lossy(path: &Path) -> Result<String> {
+    let bytes = _read_file_bytes(path)?;
+    Ok(String::from_utf8_lossy(strip_bom(&bytes)).into_owned())
+}
+
+/// Reads the file at `path` and parses it with `parse`, like
+/// `toml::from_str`. If parsing fails, the error has the path, the message of
+/// the parser, and the line of the file it points at, if any.
+pub fn read_file_with<T, E: std::fmt::Display>(
+    path: impl AsRef<Path>,
+    parse: impl FnOnce(&str) -> std::result::Result<T, E>,
+) -> Result<T> {
+    let path = path.as_ref();
+    let text = read_file(path)?;
+    parse(&text).map_err(|err| {
+        let msg = crate::error::parse_error(&text, &err.to_string());
+        fs_err(path.to_path_buf(), std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+    })
 }
 
+/// Reads the file at `path` and deserializes it as JSON, with errors like
+/// the ones of [`read_file_with`].
+#[cfg(feature = "serde")]
+pub fn read_json_file<T: serde::de::DeserializeOwned>(path: impl AsRef<Path>) -> Result<T> {
+    read_file_with(path, |text| serde_json::from_str(text))
+}
+
+/// Reads the file at `path` and deserializes it as TOML, with errors like
+/// the ones of [`read_file_with`].
+#[cfg(feature = "serde")]
+pub fn read_toml_file<T: serde::de::DeserializeOwned>(path: impl AsRef<Path>) -> Result<T> {
+    read_file_with(path, |text| toml::from_str(text))
+}
+
+/// Reads the file at `path` as is.
+pub fn read_file_bytes(path: impl AsRef<Path>) -> Result<Vec<u8>> {
+    _read_file_bytes(path.as_ref())
+}
+fn _read_file_bytes(path: &Path) -> Result<Vec<u8>> {
+    with_path(path, std::fs::read(path))
+}
+
+fn strip_bom(bytes: &[u8]) -> &[u8] {
+    bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes)
+}
+
+/// Writes `contents` to the file at `path`, creating the missing parent
+/// directories.
 pub fn write_file(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
     _write_file(path.as_ref(), contents.as_ref())
 }
 fn _write_file(path: &Path, contents: &[u8]) -> Result<()> {
-    with_path(path, std::fs::write(path, contents))
+    if dry_run::skip(format_args!("write {}", Quoted(path))) {
+        return Ok(());
+    }
+    with_parents(path, || std::fs::write(path, contents))
+}
+
+/// Runs `f`, which creates `path`, and if it fails because the parent
+/// directories are missing, creates them and runs `f` again.
+fn with_parents<T>(path: &Path, mut f: impl FnMut() -> std::io::Result<T>) -> Result<T> {
+    // Only look at the parents if `f` fails, which is rare.
+    match f() {
+        Err(err)
+            if matches!(
+                err.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory
+            ) =>
+        {
+            create_parent(path)?;
+            with_path(path, f())
+        }
+        res => with_path(path, res),
+    }
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(it) if !it.as_os_str().is_empty() => it,
+        _ => return Ok(()),
+    };
+    // `create_dir_all` would just say that the file exists, without telling
+    // which one.
+    if let Some(file) = parent.ancestors().find(|it| it.is_file()) {
+        let io_err = std::io::Error::new(std::io::ErrorKind::NotADirectory, "not a directory");
+        return Err(fs_err(file.to_path_buf(), io_err));
+    }
+    with_path(parent, std::fs::create_dir_all(parent))
+}
+
+/// Appends `contents` to the file at `path`, creating it and the missing
+/// parent directories if needed.
+///
+/// The contents are written with a single `write` call where possible, so,
+/// with `O_APPEND`, concurrent appends don't interleave.
+pub fn append_file(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+    _append_file(path.as_ref(), contents.as_ref())
+}
+fn _append_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if dry_run::skip(format_args!("append {}", Quoted(path))) {
+        return Ok(());
+    }
+    use std::io::Write;
+
+    let mut file =
+        with_parents(path, || std::fs::OpenOptions::new().append(true).create(true).open(path))?;
+    with_path(path, file.write_all(contents))
+}
+
+/// Like [`write_file`], but readers only ever see the old or the new
+/// contents, even if the process is killed mid-write.
+///
+/// The contents are written to a temporary file next to `path`, which is
+/// then renamed over it. The permissions of an existing file are kept. Like
+/// with `write_file`, the missing parent directories are created.
+pub fn write_file_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+    _write_file_atomic(path.as_ref(), contents.as_ref())
 }
+fn _write_file_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    if dry_run::skip(format_args!("write {}", Quoted(path))) {
+        return Ok(());
+    }
+    let tmp = tmp_sibling(path);
+    with_parents(path, || {
+        let res = write_file_atomic_aux(path, &tmp, contents);
+        if res.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        res
+    })
+}
+fn write_file_atomic_aux(path: &Path, tmp: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut file = std::fs::File::create(tmp)?;
+    file.write_all(contents)?;
+    if let Ok(meta) = std::fs::metadata(path) {
+        file.set_permissions(meta.permissions())?;
+    }
+    file.sync_all()?;
+    drop(file);
+    rename_over(tmp, path)
+}
+
+fn tmp_sibling(path: &Path) -> PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub fn mkdir_p(path: impl AsRef<Path>) -> Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.{}.tmp", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+    path.with_file_name(name)
+}
+
+#[cfg(not(windows))]
+fn rename_over(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::rename(from, to)
+}
+
+#[cfg(windows)]
+fn rename_over(from: &Path, to: &Path) -> std::io::Result<()> {
+    // `rename` replaces existing files, but fails while someone else (an
+    // antivirus, an indexer) has the destination open.
+    for _ in 0..99 {
+        if std::fs::rename(from, to).is_ok() {
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10))
+    }
+    std::fs::rename(from, to)
+}
+
+/// Creates the directory `path` and its missing parents. Returns whether
+/// anything was created, an existing directory is not an error.
+pub fn mkdir_p(path: impl AsRef<Path>) -> Result<bool> {
     _mkdir_p(path.as_ref())
 }
-fn _mkdir_p(path: &Path) -> Result<()> {
-    with_path(path, std::fs::create_dir_all(path))
+fn _mkdir_p(path: &Path) -> Result<bool> {
+    let existed = path.is_dir();
+    if dry_run::skip(format_args!("mkdir -p {}", Quoted(path))) {
+        return Ok(!existed);
+    }
+    with_path(path, std::fs::create_dir_all(path))?;
+    Ok(!existed)
 }
 
+/// Copies the file `src` to `dst`, keeping its permissions.
+///
+/// Like `cp`, if `dst` is an existing directory, `src` is copied into it.
 pub fn cp(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
-    _cp(src.as_ref(), dst.as_ref())
+    _cp(src.as_ref(), dst.as_ref(), CopyOptions::default()).map(|_stats| ())
+}
+/// Like [`cp`], but `opts` controls what happens when the destination file
+/// already exists.
+pub fn cp_opts(
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    opts: CopyOptions,
+) -> Result<CopyStats> {
+    _cp(src.as_ref(), dst.as_ref(), opts)
+}
+fn _cp(src: &Path, dst: &Path, opts: CopyOptions) -> Result<CopyStats> {
+    let mut stats = CopyStats::default();
+    if dry_run::skip(format_args!("cp {} {}", Quoted(src), Quoted(dst))) {
+        return Ok(stats);
+    }
+    let mut dst = dst.to_path_buf();
+    if dst.is_dir() {
+        if let Some(name) = src.file_name() {
+            dst.push(name);
+        }
+    }
+    if is_same_file(src, &dst) {
+        let err = std::io::Error::other(format!("`{}` is the same file", dst.display()));
+        return with_path(src, Err(err));
+    }
+    copy_file(src, &dst, opts, &mut stats, |src, dst| std::fs::copy(src, dst).map(|_size| ()))?;
+    Ok(stats)
+}
+
+/// What [`cp_opts`] and [`copy_dir_opts`] do with a destination file that
+/// already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overwrite {
+    /// Replace it, like plain `cp`.
+    #[default]
+    Always,
+    /// Fail with an error naming both paths.
+    Error,
+    /// Replace it only if the source was modified later, like `cp -u`.
+    IfNewer,
+}
+
+/// Options for [`cp_opts`] and [`copy_dir_opts`], the default behaves like
+/// [`cp`] and [`copy_dir`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CopyOptions {
+    pub overwrite: Overwrite,
+}
+
+/// The number of files copied and skipped by [`cp_opts`] or
+/// [`copy_dir_opts`]. Symlinks count as files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CopyStats {
+    pub copied: usize,
+    pub skipped: usize,
+}
+
+// FAT stores modification times with a two second resolution, so a fresh
+// copy can look older than its source there.
+const MTIME_TOLERANCE: std::time::Duration =
+    std::time::Duration::from_secs(if cfg!(windows) { 2 } else { 0 });
+
+fn copy_file(
+    src: &Path,
+    dst: &Path,
+    opts: CopyOptions,
+    stats: &mut CopyStats,
+    copy: impl FnOnce(&Path, &Path) -> std::io::Result<()>,
+) -> Result<()> {
+    if opts.overwrite != Overwrite::Always {
+        if let Ok(dst_meta) = dst.symlink_metadata() {
+            if opts.overwrite == Overwrite::Error {
+                let err = std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("refusing to overwrite `{}`", dst.display()),
+                );
+                return with_path(src, Err(err));
+            }
+            let src_mtime = with_path(src, src.symlink_metadata().and_then(|it| it.modified()))?;
+            let dst_mtime = with_path(dst, dst_meta.modified())?;
+            if src_mtime <= dst_mtime + MTIME_TOLERANCE {
+                stats.skipped += 1;
+                return Ok(());
+            }
+        }
+    }
+    with_path(src, copy(src, dst))?;
+    stats.copied += 1;
+    Ok(())
+}
+
+/// Moves `src` to `dst`, overwriting `dst` if it is a file.
+///
+/// Like `mv`, if `dst` is an existing directory, `src` is moved into it. When
+/// `src` and `dst` are on different file systems, `src` is copied (keeping
+/// permissions) and then removed.
+pub fn mv(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    _mv(src.as_ref(), dst.as_ref())
+}
+fn _mv(src: &Path, dst: &Path) -> Result<()> {
+    if dry_run::skip(format_args!("mv {} {}", Quoted(src), Quoted(dst))) {
+        return Ok(());
+    }
+    let mut dst = dst.to_path_buf();
+    if dst.is_dir() {
+        if let Some(name) = src.file_name() {
+            dst.push(name);
+        }
+    }
+    match std::fs::rename(src, &dst) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => (),
+        Err(err) => return with_path(src, Err(phase_err("rename to", &dst, err))),
+    }
+    if src.is_dir() {
+        _copy_dir(src, &dst, CopyOptions::default())?;
+        with_path(
+            src,
+            remove_dir_all(src).map_err(|err| phase_err("remove after copying to", &dst, err)),
+        )
+    } else {
+        with_path(src, std::fs::copy(src, &dst).map_err(|err| phase_err("copy to", &dst, err)))?;
+        with_path(
+            src,
+            remove_file(src).map_err(|err| phase_err("remove after copying to", &dst, err)),
+        )
+    }
+}
+
+fn phase_err(phase: &str, dst: &Path, err: std::io::Error) -> std::io::Error {
+    std::io::Error::new(err.kind(), format!("failed to {} `{}`: {}", phase, dst.display(), err))
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Like `canonicalize`, but for a path whose last components might not exist
+/// yet. `None` if that can't be done.
+fn canonicalize_prefix(path: &Path) -> Option<PathBuf> {
+    let mut missing = Vec::new();
+    let mut prefix = path;
+    loop {
+        let existing = if prefix.as_os_str().is_empty() { Path::new(".") } else { prefix };
+        if let Ok(mut res) = existing.canonicalize() {
+            res.extend(missing.iter().rev());
+            return Some(res);
+        }
+        missing.push(prefix.file_name()?);
+        prefix = prefix.parent()?;
+    }
+}
+
+/// Recursively copies the directory `src` to `dst`, creating `dst` if needed.
+///
+/// If `dst` already exists, the trees are merged: files from `src` overwrite
+/// files with the same relative path in `dst`, other files in `dst` are left
+/// alone. On Unix, symlinks are copied as links rather than followed.
+/// Copying a directory into itself, like `a` to `a/b`, is an error.
+pub fn copy_dir(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    _copy_dir(src.as_ref(), dst.as_ref(), CopyOptions::default()).map(|_stats| ())
+}
+/// Like [`copy_dir`], but `opts` controls what happens to files that already
+/// exist in `dst`.
+pub fn copy_dir_opts(
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    opts: CopyOptions,
+) -> Result<CopyStats> {
+    _copy_dir(src.as_ref(), dst.as_ref(), opts)
+}
+fn _copy_dir(src: &Path, dst: &Path, opts: CopyOptions) -> Result<CopyStats> {
+    let mut stats = CopyStats::default();
+    if dry_run::skip(format_args!("cp -r {} {}", Quoted(src), Quoted(dst))) {
+        return Ok(stats);
+    }
+    if let (Ok(src_abs), Some(dst_abs)) = (src.canonicalize(), canonicalize_prefix(dst)) {
+        if dst_abs.starts_with(&src_abs) {
+            let msg = format!("can't copy a directory into itself, `{}`", dst.display());
+            return with_path(src, Err(std::io::Error::other(msg)));
+        }
+    }
+    // Explicit work list rather than recursion, so deep trees are fine.
+    let mut todo = vec![(src.to_path_buf(), dst.to_path_buf())];
+    while let Some((src, dst)) = todo.pop() {
+        with_path(&dst, std::fs::create_dir_all(&dst))?;
+        for entry in with_path(&src, std::fs::read_dir(&src))? {
+            let entry = with_path(&src, entry)?;
+            let src = entry.path();
+            let dst = dst.join(entry.file_name());
+            let mut file_type = with_path(&src, entry.file_type())?;
+            if file_type.is_symlink() && cfg!(not(unix)) {
+                // Creating symlinks needs extra privileges on Windows, so
+                // follow them instead.
+                file_type = with_path(&src, std::fs::metadata(&src))?.file_type();
+            }
+            if file_type.is_dir() {
+                todo.push((src, dst));
+            } else if file_type.is_symlink() {
+                copy_file(&src, &dst, opts, &mut stats, copy_symlink)?;
+            } else {
+                copy_file(&src, &dst, opts, &mut stats, |src, dst| {
+                    std::fs::copy(src, dst).map(|_size| ())
+                })?;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let target = std::fs::read_link(src)?;
+    if dst.symlink_metadata().is_ok() {
+        std::fs::remove_file(dst)?;
+    }
+    std::os::unix::fs::symlink(target, dst)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::fs::copy(src, dst).map(|_size| ())
+}
+
+/// Creates a symbolic link at `link` pointing to `original`.
+///
+/// On Windows, a file or a directory link is created depending on what
+/// `original` is.
+pub fn symlink(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<()> {
+    _symlink(original.as_ref(), link.as_ref())
+}
+fn _symlink(original: &Path, link: &Path) -> Result<()> {
+    if dry_run::skip(format_args!("ln -s {} {}", Quoted(original), Quoted(link))) {
+        return Ok(());
+    }
+    with_path(link, symlink_aux(original, link))
+}
+
+/// Like [`symlink`], but atomically replaces `link` if it already exists.
+pub fn symlink_force(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<()> {
+    _symlink_force(original.as_ref(), link.as_ref())
+}
+fn _symlink_force(original: &Path, link: &Path) -> Result<()> {
+    if dry_run::skip(format_args!("ln -sf {} {}", Quoted(original), Quoted(link))) {
+        return Ok(());
+    }
+    let tmp = tmp_sibling(link);
+    with_path(&tmp, symlink_aux(original, &tmp))?;
+    with_path(link, std::fs::rename(&tmp, link)).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })
+}
+
+pub fn hard_link(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<()> {
+    _hard_link(original.as_ref(), link.as_ref())
+}
+fn _hard_link(original: &Path, link: &Path) -> Result<()> {
+    if dry_run::skip(format_args!("ln {} {}", Quoted(original), Quoted(link))) {
+        return Ok(());
+    }
+    with_path(link, std::fs::hard_link(original, link))
+}
+
+#[cfg(unix)]
+fn symlink_aux(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink_aux(original: &Path, link: &Path) -> std::io::Result<()> {
+    // A relative `original` is relative to the link, not to the cwd.
+    let target = match link.parent() {
+        Some(parent) => parent.join(original),
+        None => original.to_path_buf(),
+    };
+    if target.is_dir() {
+        std::os::windows::fs::symlink_dir(original, link)
+    } else {
+        std::os::windows::fs::symlink_file(original, link)
+    }
+}
+
+/// Whether `path` exists, following symlinks, like `test -e`. Errors other
+/// than a missing path, like a permission error, are also `false`, use
+/// [`metadata`] to tell them apart.
+pub fn path_exists(path: impl AsRef<Path>) -> bool {
+    path.as_ref().exists()
+}
+/// Whether `path` is a file, following symlinks.
+pub fn is_file(path: impl AsRef<Path>) -> bool {
+    path.as_ref().is_file()
 }
-fn _cp(src: &Path, dst: &Path) -> Result<()> {
-    with_path(src, std::fs::copy(src, dst)).map(|_size| ())
+/// Whether `path` is a directory, following symlinks.
+pub fn is_dir(path: impl AsRef<Path>) -> bool {
+    path.as_ref().is_dir()
 }
 
+pub fn metadata(path: impl AsRef<Path>) -> Result<std::fs::Metadata> {
+    _metadata(path.as_ref())
+}
+fn _metadata(path: &Path) -> Result<std::fs::Metadata> {
+    with_path(path, std::fs::metadata(path))
+}
+
+/// Like [`metadata`], but doesn't follow symlinks, so a broken link is not an
+/// error.
+pub fn symlink_metadata(path: impl AsRef<Path>) -> Result<std::fs::Metadata> {
+    _symlink_metadata(path.as_ref())
+}
+fn _symlink_metadata(path: &Path) -> Result<std::fs::Metadata> {
+    with_path(path, std::fs::symlink_metadata(path))
+}
+
+/// Returns the paths of the entries in the directory `path`, including hidden
+/// ones, sorted by file name byte by byte.
 pub fn read_dir(path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
     _read_dir(path.as_ref())
 }
 fn _read_dir(path: &Path) -> Result<Vec<PathBuf>> {
-    with_path(path, read_dir_aux(path))
+    with_path(path, read_dir_aux(path, true))
+}
+
+/// Like [`read_dir`], but leaves out hidden entries.
+///
+/// An entry is hidden if its name starts with a `.`, or, on Windows, if it has
+/// the hidden attribute.
+pub fn read_dir_visible(path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    _read_dir_visible(path.as_ref())
+}
+fn _read_dir_visible(path: &Path) -> Result<Vec<PathBuf>> {
+    with_path(path, read_dir_aux(path, false))
+}
+
+/// An entry returned by [`read_dir_entries`].
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    path: PathBuf,
+    file_type: std::fs::FileType,
+    hidden: bool,
+}
+
+impl DirEntry {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+    /// The type of the entry itself, symlinks are not followed.
+    pub fn file_type(&self) -> std::fs::FileType {
+        self.file_type
+    }
+    pub fn is_dir(&self) -> bool {
+        self.file_type.is_dir()
+    }
+    pub fn is_file(&self) -> bool {
+        self.file_type.is_file()
+    }
+    /// Whether [`read_dir_visible`] would leave this entry out.
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+}
+
+/// Like [`read_dir`], but also tells the type of each entry.
+pub fn read_dir_entries(path: impl AsRef<Path>) -> Result<Vec<DirEntry>> {
+    _read_dir_entries(path.as_ref())
+}
+fn _read_dir_entries(path: &Path) -> Result<Vec<DirEntry>> {
+    with_path(path, read_dir_entries_aux(path))
+}
+
+/// Returns all files in the tree rooted at `path`, depth-first, in sorted
+/// order.
+///
+/// Directories themselves are not included. Symlinks are returned as is and
+/// never followed, so link cycles are not a problem.
+pub fn walk_dir(path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    _walk_dir(path.as_ref())
+}
+fn _walk_dir(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut res = Vec::new();
+    let mut todo = vec![DirEntry {
+        path: path.to_path_buf(),
+        file_type: with_path(path, path.symlink_metadata())?.file_type(),
+        hidden: false,
+    }];
+    while let Some(entry) = todo.pop() {
+        if entry.is_dir() {
+            let entries = _read_dir_entries(&entry.path)?;
+            todo.extend(entries.into_iter().rev());
+        } else {
+            res.push(entry.path);
+        }
+    }
+    Ok(res)
+}
+
+/// Creates a new, uniquely named, directory in the system temporary
+/// directory.
+///
+/// The directory and everything in it is removed when the returned
+/// [`TempDir`] is dropped.
+pub fn mktemp_d() -> Result<TempDir> {
+    let base = std::env::temp_dir();
+    loop {
+        let path = base.join(unique_name("xshell-tmp-dir-"));
+        match std::fs::create_dir(&path) {
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            res => return with_path(&path, res).map(|()| TempDir { path: Some(path) }),
+        }
+    }
+}
+
+/// A temporary directory, created by [`mktemp_d`].
+#[derive(Debug)]
+pub struct TempDir {
+    path: Option<PathBuf>,
+}
+
+impl TempDir {
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().unwrap()
+    }
+    /// Returns the path of the directory, without removing it.
+    ///
+    /// Useful to look at what's left after a failed run.
+    pub fn keep(mut self) -> PathBuf {
+        self.path.take().unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            // Nothing to do about errors here, and the directory might well
+            // have been removed already.
+            let _ = rm_rf_aux(path);
+        }
+    }
+}
+
+/// Creates a new, uniquely named, empty file in the system temporary
+/// directory. On Unix, only the current user can read and write it.
+///
+/// The file is removed when the returned [`TempFile`] is dropped. It can be
+/// used as a path directly, like `cmd!("tool --output {tmp}")` or
+/// `read_file(&tmp)`.
+pub fn mktemp_f() -> Result<TempFile> {
+    mktemp_f_with_suffix("")
+}
+
+/// Like [`mktemp_f`], but the name of the file ends with `suffix`, for tools
+/// which look at the extension: `mktemp_f_with_suffix(".json")`.
+pub fn mktemp_f_with_suffix(suffix: &str) -> Result<TempFile> {
+    let base = std::env::temp_dir();
+    loop {
+        let path = base.join(unique_name("xshell-tmp-file-") + suffix);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options.open(&path) {
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            res => return with_path(&path, res).map(|_file| TempFile { path: Some(path) }),
+        }
+    }
+}
+
+/// A temporary file, created by [`mktemp_f`].
+#[derive(Debug)]
+pub struct TempFile {
+    path: Option<PathBuf>,
+}
+
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().unwrap()
+    }
+    /// Returns the path of the file, without removing it.
+    pub fn keep(mut self) -> PathBuf {
+        self.path.take().unwrap()
+    }
+}
+
+impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+        self.path()
+    }
+}
+
+impl AsRef<std::ffi::OsStr> for TempFile {
+    fn as_ref(&self) -> &std::ffi::OsStr {
+        self.path().as_os_str()
+    }
 }
 
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+/// Takes an exclusive lock on the file at `path`, waiting while another
+/// process holds it. This is the way to make sure that only one instance of
+/// a script runs at a time. The file is created if it doesn't exist.
+///
+/// The lock is released when the returned [`FileLock`] is dropped, or when
+/// the process exits. It is advisory: only other lockers are excluded, the
+/// file itself can still be read and written. Two locks of the same file
+/// exclude each other even within one process.
+pub fn lock_file(path: impl AsRef<Path>) -> Result<FileLock> {
+    _lock_file(path.as_ref(), true)
+}
+
+/// Like [`lock_file`], but fails right away if the lock is held elsewhere.
+pub fn try_lock_file(path: impl AsRef<Path>) -> Result<FileLock> {
+    _lock_file(path.as_ref(), false)
+}
+
+fn _lock_file(path: &Path, block: bool) -> Result<FileLock> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    let file = with_path(path, options.open(path))?;
+    let res = if block {
+        file.lock()
+    } else {
+        file.try_lock().map_err(|err| match err {
+            std::fs::TryLockError::WouldBlock => std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "already locked, is another instance running?",
+            ),
+            std::fs::TryLockError::Error(err) => err,
+        })
+    };
+    with_path(path, res)?;
+    Ok(FileLock { path: path.to_path_buf(), _file: file })
+}
+
+/// An exclusive lock of a file, taken by [`lock_file`].
+#[derive(Debug)]
+#[must_use]
+pub struct FileLock {
+    path: PathBuf,
+    // Closing the file releases the lock.
+    _file: std::fs::File,
+}
+
+impl FileLock {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn unique_name(prefix: &str) -> String {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::SystemTime,
+    };
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{}{}-{}-{}",
+        prefix,
+        std::process::id(),
+        nanos.subsec_nanos(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// The current directory, with symlinks resolved.
 pub fn cwd() -> Result<PathBuf> {
-    with_path(&Path::new("."), std::env::current_dir())
+    with_path(Path::new("."), std::env::current_dir())
+}
+
+/// Like [`cwd`], but keeps the symlinks, like `pwd` in a shell: returns
+/// `$PWD` if it points to the current directory.
+pub fn cwd_logical() -> Result<PathBuf> {
+    let cwd = cwd()?;
+    if let Some(pwd) = std::env::var_os("PWD").map(PathBuf::from) {
+        if pwd.is_absolute() && pwd.canonicalize().ok() == cwd.canonicalize().ok() {
+            return Ok(pwd);
+        }
+    }
+    Ok(cwd)
 }
 
 fn with_path<T>(path: &Path, res: Result<T, std::io::Error>) -> Result<T> {
     res.map_err(|io_err| fs_err(path.to_path_buf(), io_err))
 }
 
+#[cfg(not(windows))]
+fn remove_file(path: &Path) -> std::io::Result<()> {
+    std::fs::remove_file(path)
+}
+
 #[cfg(not(windows))]
 fn remove_dir_all(path: &Path) -> std::io::Result<()> {
     std::fs::remove_dir_all(path)
 }
 
+#[cfg(not(windows))]
+fn remove_dir(path: &Path) -> std::io::Result<()> {
+    std::fs::remove_dir(path)
+}
+
+// Files created by git, like `.git/objects/*`, are read-only, and Windows
+// refuses to delete those. Other processes, like antivirus scanners, also
+// briefly keep files open, so we retry on sharing violations. Overly long
+// paths need no special handling, std switches to `\\?\` paths when
+// needed.
+#[cfg(windows)]
+fn remove_file(path: &Path) -> std::io::Result<()> {
+    retry(|| {
+        clear_readonly(path)?;
+        // Symlinks to directories are directories on Windows.
+        if path.symlink_metadata()?.file_type().is_symlink() && path.is_dir() {
+            return std::fs::remove_dir(path);
+        }
+        std::fs::remove_file(path)
+    })
+}
+
+#[cfg(windows)]
+fn remove_dir(path: &Path) -> std::io::Result<()> {
+    retry(|| {
+        clear_readonly(path)?;
+        std::fs::remove_dir(path)
+    })
+}
+
 #[cfg(windows)]
 fn remove_dir_all(path: &Path) -> std::io::Result<()> {
+    retry(|| {
+        clear_readonly(path)?;
+        std::fs::remove_dir_all(path)
+    })
+}
+
+#[cfg(windows)]
+#[allow(clippy::permissions_set_readonly_false)]
+fn clear_readonly(path: &Path) -> std::io::Result<()> {
+    let mut todo = vec![path.to_path_buf()];
+    while let Some(path) = todo.pop() {
+        let meta = path.symlink_metadata()?;
+        if meta.file_type().is_symlink() {
+            continue;
+        }
+        let mut perms = meta.permissions();
+        if perms.readonly() {
+            perms.set_readonly(false);
+            std::fs::set_permissions(&path, perms)?;
+        }
+        if meta.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                todo.push(entry?.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn retry(mut f: impl FnMut() -> std::io::Result<()>) -> std::io::Result<()> {
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
     for _ in 0..99 {
-        if std::fs::remove_dir_all(path).is_ok() {
-            return Ok(());
+        match f() {
+            Err(err)
+                if matches!(
+                    err.raw_os_error(),
+                    Some(ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION)
+                ) =>
+            {
+                std::thread::sleep(std::time::Duration::from_millis(10))
+            }
+            // Someone else got there first.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            res => return res,
         }
-        std::thread::sleep(std::time::Duration::from_millis(10))
     }
-    std::fs::remove_dir_all(path)
+    f()
 }
 
-fn read_dir_aux(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+fn read_dir_aux(path: &Path, include_hidden: bool) -> std::io::Result<Vec<PathBuf>> {
     let mut res = Vec::new();
     for entry in std::fs::read_dir(path)? {
         let entry = entry?;
-        res.push(entry.path())
+        if include_hidden || !is_hidden(&entry)? {
+            res.push(entry.path())
+        }
     }
-    res.sort();
+    res.sort_by(|a, b| by_file_name(a, b));
     Ok(res)
 }
+
+fn read_dir_entries_aux(path: &Path) -> std::io::Result<Vec<DirEntry>> {
+    let mut res = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let hidden = is_hidden(&entry)?;
+        res.push(DirEntry { path: entry.path(), file_type: entry.file_type()?, hidden })
+    }
+    res.sort_by(|a, b| by_file_name(&a.path, &b.path));
+    Ok(res)
+}
+
+fn by_file_name(a: &Path, b: &Path) -> std::cmp::Ordering {
+    a.file_name()
+        .map(|it| it.as_encoded_bytes())
+        .cmp(&b.file_name().map(|it| it.as_encoded_bytes()))
+}
+
+fn is_hidden(entry: &std::fs::DirEntry) -> std::io::Result<bool> {
+    Ok(entry.file_name().as_encoded_bytes().starts_with(b".") || has_hidden_attribute(entry)?)
+}
+
+#[cfg(windows)]
+fn has_hidden_attribute(entry: &std::fs::DirEntry) -> std::io::Result<bool> {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    Ok(entry.metadata()?.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_entry: &std::fs::DirEntry) -> std::io::Result<bool> {
+    Ok(false)
+}
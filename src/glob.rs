@@ -0,0 +1,149 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{error::fs_err, Result};
+
+/// Expands a glob pattern, relative to the current directory.
+///
+/// Supports `*` and `?` within a path component and `**` for any number of
+/// directories. Hidden files only match if the pattern component for them
+/// starts with a `.`. The result is sorted, and is empty if nothing matches.
+///
+/// ```no_run
+/// # use xshell::{cmd, glob};
+/// let sources = glob("src/**/*.rs")?;
+/// cmd!("rustfmt --check {sources...}").run()?;
+/// # Ok::<(), xshell::Error>(())
+/// ```
+pub fn glob(pattern: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    _glob(pattern.as_ref())
+}
+fn _glob(pattern: &Path) -> Result<Vec<PathBuf>> {
+    glob_aux(pattern).map_err(|io_err| fs_err(pattern.to_path_buf(), io_err))
+}
+
+/// Like [`glob`], but fails unless there's exactly one match.
+pub fn glob_one(pattern: impl AsRef<Path>) -> Result<PathBuf> {
+    _glob_one(pattern.as_ref())
+}
+fn _glob_one(pattern: &Path) -> Result<PathBuf> {
+    let mut paths = _glob(pattern)?;
+    if paths.len() != 1 {
+        let msg = format!("expected exactly one match, found {}", paths.len());
+        return Err(fs_err(pattern.to_path_buf(), io::Error::other(msg)));
+    }
+    Ok(paths.pop().unwrap())
+}
+
+fn glob_aux(pattern: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut base = PathBuf::new();
+    let mut parts = Vec::new();
+    for component in pattern.components() {
+        match component {
+            // Everything after the first pattern, `..` included, is matched
+            // relative to what the pattern matched.
+            _ if !parts.is_empty() => parts.push(component.as_os_str()),
+            Component::Normal(it) if is_pattern(&it.to_string_lossy()) => parts.push(it),
+            _ => base.push(component),
+        }
+    }
+    if parts.is_empty() {
+        return Ok(if exists(&base) { vec![base] } else { Vec::new() });
+    }
+
+    let mut res = Vec::new();
+    // Explicit work list rather than recursion, `**` can go deep.
+    let mut todo = vec![(base, 0)];
+    while let Some((path, idx)) = todo.pop() {
+        let part = match parts.get(idx) {
+            Some(it) => it.to_string_lossy(),
+            None => {
+                res.push(path);
+                continue;
+            }
+        };
+        if !is_pattern(&part) {
+            let path = path.join(parts[idx]);
+            if exists(&path) {
+                todo.push((path, idx + 1));
+            }
+            continue;
+        }
+        if part == "**" {
+            todo.push((path.clone(), idx + 1));
+        }
+        let dir = if path.as_os_str().is_empty() { Path::new(".") } else { path.as_path() };
+        let entries = match std::fs::read_dir(dir) {
+            Ok(it) => it,
+            Err(err) if is_skippable(&err) => continue,
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            // Match the lossy name, but join the real one, so that names
+            // which aren't UTF-8 still result in existing paths.
+            let file_name = entry.file_name();
+            let name = file_name.to_string_lossy();
+            if name.starts_with('.') && !part.starts_with('.') {
+                continue;
+            }
+            if part == "**" {
+                // Don't follow symlinks, they might form a cycle.
+                if entry.file_type()?.is_dir() {
+                    todo.push((path.join(&file_name), idx));
+                } else if idx + 1 == parts.len() {
+                    todo.push((path.join(&file_name), idx + 1));
+                }
+            } else if matches(&part, &name) {
+                todo.push((path.join(&file_name), idx + 1));
+            }
+        }
+    }
+    res.sort();
+    res.dedup();
+    Ok(res)
+}
+
+fn is_pattern(part: &str) -> bool {
+    part.contains(['*', '?'])
+}
+
+fn exists(path: &Path) -> bool {
+    path.symlink_metadata().is_ok()
+}
+
+fn is_skippable(err: &io::Error) -> bool {
+    // Matched a file, or a dangling link, where a directory was expected.
+    matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::NotADirectory)
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and of the name char it is matched up to.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,124 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use crate::Cmd;
+
+type CmdHook = Arc<dyn Fn(CmdData) -> CmdData + Send + Sync>;
+
+static CMD_HOOKS: RwLock<Vec<(u64, CmdHook)>> = RwLock::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// What a [`set_cmd_hook`] hook can see and change about a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdData {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    /// The environment overrides, `None` removes the variable.
+    pub env: Vec<(OsString, Option<OsString>)>,
+    /// The working directory, `None` is the current directory of the process.
+    pub cwd: Option<PathBuf>,
+}
+
+/// Installs `hook`, which rewrites every command before it is echoed and
+/// spawned, for things like prefixing commands with `sudo -n` or logging them.
+///
+/// The hook is global for the whole process and applies to running,
+/// reading, spawning and piping commands alike. Hooks nest: a later hook
+/// gets the command as rewritten by the earlier ones. Dropping the returned
+/// guard removes the hook.
+///
+/// ```no_run
+/// # use xshell::{cmd, set_cmd_hook};
+/// let _hook = set_cmd_hook(|mut cmd| {
+///     cmd.args.insert(0, std::mem::replace(&mut cmd.program, "nice".into()));
+///     cmd.args.splice(0..0, ["-n".into(), "19".into()]);
+///     cmd
+/// });
+/// cmd!("cargo build").run()?; // $ nice -n 19 cargo build
+/// # Ok::<(), xshell::Error>(())
+/// ```
+pub fn set_cmd_hook(hook: impl Fn(CmdData) -> CmdData + Send + Sync + 'static) -> CmdHookGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    CMD_HOOKS.write().unwrap().push((id, Arc::new(hook)));
+    CmdHookGuard { id }
+}
+
+/// Removes the hook installed by [`set_cmd_hook`] when dropped.
+#[must_use]
+#[derive(Debug)]
+pub struct CmdHookGuard {
+    id: u64,
+}
+
+impl Drop for CmdHookGuard {
+    fn drop(&mut self) {
+        CMD_HOOKS.write().unwrap().retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// Returns `cmd` as rewritten by the installed hooks, or `None` if they
+/// didn't change it.
+pub(crate) fn apply(cmd: &Cmd) -> Option<Cmd> {
+    // Don't hold the lock while calling the hooks, they might run commands
+    // themselves.
+    let hooks = CMD_HOOKS.read().unwrap().iter().map(|(_, it)| it.clone()).collect::<Vec<_>>();
+    if hooks.is_empty() {
+        return None;
+    }
+    let env = cmd.shell_env.iter().chain(&cmd.env).cloned().collect::<Vec<_>>();
+    let orig = CmdData {
+        program: cmd.args[0].clone(),
+        args: cmd.args[1..].to_vec(),
+        env,
+        cwd: cmd.working_dir(),
+    };
+    let data = hooks.iter().fold(orig.clone(), |data, hook| hook(data));
+    if data == orig {
+        return None;
+    }
+
+    let mut res = cmd.clone();
+    if data.program != orig.program || data.args != orig.args {
+        res.args = Some(data.program).into_iter().chain(data.args).collect();
+        // The script isn't where `Display` expects it anymore.
+        res.shell_script = false;
+    }
+    if data.env != orig.env {
+        // Only override what the hook changed, so that the shell variables
+        // and snapshots stay where they are and aren't echoed.
+        let value = |env: &[(OsString, Option<OsString>)], key: &OsStr| {
+            env.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+        };
+        let keys = orig.env.iter().chain(&data.env).map(|(k, _)| k.as_os_str());
+        let mut changed = Vec::new();
+        for key in keys {
+            if !changed.iter().any(|(k, _)| k == key) {
+                let new = value(&data.env, key);
+                if new != value(&orig.env, key) {
+                    changed.push((key.to_os_string(), new));
+                }
+            }
+        }
+        for (key, value) in changed {
+            match value {
+                Some(value) => res._env(&key, value.as_deref()),
+                // The hook dropped the override, so the variable is inherited.
+                None => {
+                    res.shell_env.retain(|(k, _)| *k != key);
+                    res.env.retain(|(k, _)| *k != key);
+                }
+            }
+        }
+    }
+    if data.cwd != orig.cwd {
+        res.shell_dir = None;
+        res.dir = data.cwd;
+    }
+    Some(res)
+}
@@ -2,91 +2,541 @@
 //!
 //! Docs are incoming, take a look at the `examples/ci.rs` in the meantime.
 
+mod color;
+mod dry_run;
+mod echo;
 mod env;
 mod error;
+mod exec;
 mod fs;
+mod glob;
+mod hook;
+mod shell;
+mod timing;
+mod which;
 
 use std::{
+    borrow::Cow,
     ffi::{OsStr, OsString},
-    fmt, io,
-    io::Write,
-    path::Path,
+    fmt,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
     process::Output,
-    process::Stdio,
+    slice,
+    sync::Arc,
+    thread,
+    time::Duration,
 };
 
+use echo::{echo, echo_cmds};
 use error::CmdErrorKind;
+use exec::exec_pipeline;
+use timing::timed;
 #[doc(hidden)]
-pub use xshell_macros::__cmd;
+pub use xshell_macros::{__cmd, __shell_cmd};
 
+#[cfg(feature = "async")]
+pub use crate::exec::CmdFuture;
+#[cfg(feature = "serde")]
+pub use crate::fs::{read_json_file, read_toml_file};
 pub use crate::{
-    env::{pushd, pushenv, Pushd, Pushenv},
+    color::{set_echo_color, ColorChoice},
+    dry_run::{is_dry_run, set_dry_run},
+    echo::{remove_echo_hook, set_echo_hook, set_verbosity, verbosity, Verbosity},
+    env::{
+        debug_state, lock, pushd, pushenv, pushenv_path, pushenv_remove, pushenvs, with_dir, Pushd,
+        Pushenv, Pushenvs, ShellLockGuard,
+    },
     error::{Error, Result},
-    fs::{cp, cwd, mkdir_p, read_dir, read_file, rm_rf, write_file},
+    exec::{Child, ReadLines},
+    fs::{
+        append_file, copy_dir, copy_dir_opts, cp, cp_opts, cwd, cwd_logical, hard_link, is_dir,
+        is_file, lock_file, metadata, mkdir_p, mktemp_d, mktemp_f, mktemp_f_with_suffix, mv,
+        path_exists, read_dir, read_dir_entries, read_dir_visible, read_file, read_file_bytes,
+        read_file_lossy, read_file_with, rm_rf, symlink, symlink_force, symlink_metadata,
+        try_lock_file, walk_dir, write_file, write_file_atomic, CopyOptions, CopyStats, DirEntry,
+        FileLock, Overwrite, RemoveStats, TempDir, TempFile,
+    },
+    glob::{glob, glob_one},
+    hook::{set_cmd_hook, CmdData, CmdHookGuard},
+    shell::Shell,
+    timing::{command_timings, record_command_timings, set_slow_command_threshold},
+    which::{which, which_all},
 };
 
+/// Constructs a [`Cmd`] from a shell-like template.
+///
+/// The template is split into arguments on whitespace, `'single quotes'`
+/// group words into one argument. Variables are interpolated with `{var}`,
+/// which always becomes (a part of) a single argument, whatever it contains.
+/// Anything `AsRef<OsStr>` can be interpolated, as well as anything
+/// `Display`, like numbers: `cmd!("make -j{jobs}")`.
+/// `{vars...}` splats anything that implements
+/// `IntoIterator<Item: AsRef<OsStr>>` into zero or more arguments:
+///
+/// ```no_run
+/// # use std::path::PathBuf;
+/// # use xshell::cmd;
+/// let out = "archive.tar.gz";
+/// let files: Vec<PathBuf> = vec!["a.txt".into(), "b b.txt".into()];
+/// cmd!("tar -czf {out} {files...}").run()?;
+/// # Ok::<(), xshell::Error>(())
+/// ```
+///
+/// As `Option` is `IntoIterator`, splatting it passes an optional argument:
+/// `None` expands to nothing, while `Some("")` is an explicit empty argument.
+/// A flag with a value is a flattened `Option` of a pair:
+///
+/// ```no_run
+/// # use xshell::cmd;
+/// let target: Option<&str> = Some("wasm32-unknown-unknown");
+/// let target_flag = target.map(|it| ["--target", it]).into_iter().flatten();
+/// cmd!("cargo build {target_flag...}").run()?;
+/// # Ok::<(), xshell::Error>(())
+/// ```
+///
+/// The program can be interpolated too, anything `AsRef<Path>` works:
+/// `cmd!("{python} --version")`. It can't be splatted though:
+///
+/// ```compile_fail
+/// # use xshell::cmd;
+/// let argv = ["echo", "hello"];
+/// cmd!("{argv...}");
+/// ```
+///
+/// Like in `format!`, `{{` and `}}` are literal braces, as in
+/// `cmd!("find . -name '*.o' -exec rm {{}} ;")`. Inside single quotes,
+/// braces are always literal. An unmatched brace is an error:
+///
+/// ```compile_fail
+/// # use xshell::cmd;
+/// cmd!("find . -exec rm {} ;");
+/// ```
+///
+/// Leading `NAME=value` words set environment variables for the command,
+/// like [`Cmd::env`]. The value can be interpolated or quoted:
+/// `cmd!("RUSTFLAGS='-C debuginfo=0' cargo build")`,
+/// `cmd!("CARGO_TARGET_DIR={dir} cargo build")`. Later arguments with `=` are
+/// passed as is.
+///
+/// A backslash at the end of a line continues the command on the next one,
+/// so long commands can be laid out like in a shell script:
+///
+/// ```no_run
+/// # use xshell::cmd;
+/// let image = "alpine";
+/// cmd!(
+///     "docker run --rm \
+///         -v ./src:/src \
+///         {image} ls /src"
+/// )
+/// .run()?;
+/// # Ok::<(), xshell::Error>(())
+/// ```
+///
+/// With a [`Shell`] as the first argument, the command runs in the shell's
+/// directory and environment: `cmd!(sh, "git status")`.
 #[macro_export]
 macro_rules! cmd {
     ($cmd:tt) => {{
         #[cfg(trick_rust_analyzer_into_highlighting_interpolated_bits)]
         format_args!($cmd);
         use $crate::Cmd as __CMD;
+        #[allow(unused_imports)]
+        use $crate::__private::{ArgWrap as __ARG, ViaDisplay as _, ViaOsStr as _};
         let cmd: $crate::Cmd = $crate::__cmd!(__CMD $cmd);
         cmd
     }};
+    ($sh:expr, $cmd:tt) => {{
+        let sh: &$crate::Shell = &$sh;
+        sh.__attach($crate::cmd!($cmd))
+    }};
+}
+
+/// Constructs a [`Cmd`] which runs a shell script, see [`Cmd::shell`].
+///
+/// Interpolated values are not spliced into the script, they are passed as
+/// positional parameters instead, so they can't inject anything: `{dir}`
+/// becomes `"$1"`, or just `$1` inside double quotes.
+///
+/// ```no_run
+/// # use xshell::shell_cmd;
+/// let dir = "target/doc";
+/// shell_cmd!("for f in {dir}/*.html; do gzip -k \"$f\"; done").run()?;
+/// # Ok::<(), xshell::Error>(())
+/// ```
+///
+/// Inside single quotes, braces are literal. Elsewhere, `{{` and `}}` are
+/// literal braces, as in `${{HOME}}`.
+#[macro_export]
+macro_rules! shell_cmd {
+    ($script:tt) => {{
+        use $crate::Cmd as __CMD;
+        #[allow(unused_imports)]
+        use $crate::__private::{ArgWrap as __ARG, ViaDisplay as _, ViaOsStr as _};
+        let cmd: $crate::Cmd = $crate::__shell_cmd!(__CMD $script);
+        cmd
+    }};
+    ($sh:expr, $script:tt) => {{
+        let sh: &$crate::Shell = &$sh;
+        sh.__attach($crate::shell_cmd!($script))
+    }};
+}
+
+/// A value which is passed to the command as is, but shown as `***` when the
+/// command is echoed or displayed, like in error messages:
+///
+/// ```no_run
+/// # use xshell::{cmd, Secret};
+/// let token = Secret(std::env::var("CRATES_IO_TOKEN").unwrap());
+/// cmd!("cargo publish --token {token}").run()?;
+/// # Ok::<(), xshell::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct Secret<T>(pub T);
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
 }
 
 #[must_use]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Cmd {
     args: Vec<OsString>,
     stdin_contents: Option<Vec<u8>>,
+    stdin_file: Option<PathBuf>,
+    stdout_file: Option<Redirect>,
+    stderr_file: Option<Redirect>,
+    null_stdout: bool,
+    null_stderr: bool,
+    merge_stderr: bool,
+    via_shell: bool,
+    /// The args are `sh -c script sh args...`.
+    shell_script: bool,
+    shell_dir: Option<PathBuf>,
+    dir: Option<PathBuf>,
+    shell_env: Vec<(OsString, Option<OsString>)>,
+    env: Vec<(OsString, Option<OsString>)>,
+    env_clear: bool,
+    ignore_status: bool,
+    echo_cmd: bool,
+    retain_stderr: Option<usize>,
+    tee: Option<usize>,
+    timeout: Option<Duration>,
+    force_run: bool,
+    attempts: u32,
+    retry_delay: Duration,
+    /// Arguments and env values with a [`Secret`] in them, and how they are
+    /// shown.
+    secrets: Vec<(OsString, OsString)>,
+    output_limit: Option<usize>,
+    process_group: bool,
+    interactive: bool,
+    keep_trailing_newline: bool,
+    heartbeat: Option<Heartbeat>,
+}
+
+type HeartbeatFn = Arc<dyn Fn(Duration) + Send + Sync>;
+
+#[derive(Clone)]
+struct Heartbeat {
+    interval: Duration,
+    /// `None` prints the default message.
+    callback: Option<HeartbeatFn>,
+}
+
+impl fmt::Debug for Heartbeat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Heartbeat").field("interval", &self.interval).finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Redirect {
+    path: PathBuf,
+    append: bool,
 }
 
 impl fmt::Display for Cmd {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut space = "";
-        for arg in &self.args {
-            write!(f, "{}", space)?;
-            space = " ";
-
-            let arg = arg.to_string_lossy();
-            if arg.chars().any(|it| it.is_ascii_whitespace()) {
-                write!(f, "\"{}\"", arg.escape_default())?
-            } else {
-                write!(f, "{}", arg)?
-            };
+        self.fmt_elided(f, usize::MAX)
+    }
+}
+
+impl Cmd {
+    /// Like `Display`, but with more than `max_args` arguments only the first
+    /// and the last few are shown.
+    pub(crate) fn fmt_elided(&self, f: &mut fmt::Formatter<'_>, max_args: usize) -> fmt::Result {
+        if let Some(dir) = &self.dir {
+            write!(f, "(cd ")?;
+            fmt_arg(f, self.mask(dir.as_os_str()))?;
+            write!(f, "; ")?;
+        }
+        for (key, value) in &self.env {
+            if let Some(value) = value {
+                write!(f, "{}=", key.to_string_lossy())?;
+                fmt_arg(f, self.mask(value))?;
+                write!(f, " ")?;
+            }
+        }
+        if self.shell_script {
+            if self.args.len() > 4 {
+                write!(f, "set -- ")?;
+                self.fmt_args(f, &self.args[4..], max_args)?;
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", self.mask(&self.args[2]).to_string_lossy())?;
+        } else {
+            self.fmt_args(f, &self.args, max_args)?;
+        }
+        if let Some(path) = &self.stdin_file {
+            write!(f, " < ")?;
+            fmt_arg(f, path.as_os_str())?;
+        }
+        for (fd, redirect) in [("", &self.stdout_file), ("2", &self.stderr_file)] {
+            if let Some(redirect) = redirect {
+                write!(f, " {}{} ", fd, if redirect.append { ">>" } else { ">" })?;
+                fmt_arg(f, redirect.path.as_os_str())?;
+            }
+        }
+        if self.dir.is_some() {
+            write!(f, ")")?;
         }
         Ok(())
     }
 }
 
+impl Cmd {
+    fn fmt_args(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        args: &[OsString],
+        max_args: usize,
+    ) -> fmt::Result {
+        const TAIL: usize = 4;
+        let elided = if args.len() > max_args { args.len() - max_args + 1 } else { 0 };
+        let head = args.len() - elided - TAIL.min(args.len() - elided);
+        for (idx, arg) in args.iter().enumerate() {
+            if elided > 0 && (head..head + elided).contains(&idx) {
+                if idx == head {
+                    write!(f, " [... {} more arguments ...]", elided)?;
+                }
+                continue;
+            }
+            if idx > 0 {
+                f.write_str(" ")?;
+            }
+            fmt_arg(f, self.mask(arg))?;
+        }
+        Ok(())
+    }
+
+    /// The working directory and the environment overrides which the echo
+    /// doesn't show, one per line, for verbose echoes.
+    fn details(&self) -> String {
+        let mut res = String::new();
+        let dir = match self.working_dir() {
+            Some(dir) => Ok(dir),
+            None => std::env::current_dir(),
+        };
+        if let Ok(dir) = dir {
+            res.push_str(&format!("  cwd: {}\n", dir.display()));
+        }
+        if self.env_clear {
+            res.push_str("  env: cleared\n");
+        }
+        let removed = self.env.iter().filter(|(_key, value)| value.is_none());
+        for (key, value) in self.shell_env.iter().chain(removed) {
+            let key = key.to_string_lossy();
+            match value {
+                Some(value) => {
+                    let value = self.mask(value);
+                    res.push_str(&format!("  env: {}={}\n", key, value.to_string_lossy()))
+                }
+                None => res.push_str(&format!("  env: unset {}\n", key)),
+            }
+        }
+        res
+    }
+
+    /// Shows an argument or env value with secrets in it as `***` instead.
+    fn mask<'a>(&'a self, s: &'a OsStr) -> &'a OsStr {
+        match self.secrets.iter().rev().find(|(value, _masked)| value == s) {
+            Some((_value, masked)) => masked,
+            None => s,
+        }
+    }
+}
+
+/// Appends `piece` to `value`, an argument or env value being built up by
+/// `cmd!`, and records how it is shown if it has a secret in it.
+fn extend_masked(
+    value: &mut OsString,
+    piece: &OsStr,
+    secret: bool,
+    secrets: &mut Vec<(OsString, OsString)>,
+) {
+    let masked = secrets.iter().rev().find(|(it, _masked)| it == value).map(|(_, it)| it.clone());
+    let masked = masked.or_else(|| secret.then(|| value.clone()));
+    value.push(piece);
+    if let Some(mut masked) = masked {
+        masked.push(if secret { OsStr::new("***") } else { piece });
+        secrets.push((value.clone(), masked));
+    }
+}
+
+/// Quotes `arg` so that it can be pasted back into a POSIX shell.
+fn fmt_arg(f: &mut fmt::Formatter<'_>, arg: &OsStr) -> fmt::Result {
+    let arg = match arg.to_str() {
+        Some(it) => it,
+        None => return fmt_non_utf8_arg(f, arg),
+    };
+    let is_safe = |b: u8| b.is_ascii_alphanumeric() || b"-_./=:,+@%^".contains(&b);
+    if !arg.is_empty() && arg.bytes().all(is_safe) {
+        return f.write_str(arg);
+    }
+    f.write_str("'")?;
+    for (idx, part) in arg.split('\'').enumerate() {
+        if idx > 0 {
+            f.write_str(r"'\''")?;
+        }
+        f.write_str(part)?;
+    }
+    f.write_str("'")
+}
+
+// Bash's `$'...'` is not POSIX, but it is the only way to spell arbitrary
+// bytes, and it makes it obvious that something odd is going on.
+#[cfg(unix)]
+fn fmt_non_utf8_arg(f: &mut fmt::Formatter<'_>, arg: &OsStr) -> fmt::Result {
+    use std::os::unix::ffi::OsStrExt;
+
+    write!(f, "$'")?;
+    for chunk in arg.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\'' | '\\' => write!(f, "\\{}", c)?,
+                '\n' => write!(f, "\\n")?,
+                '\t' => write!(f, "\\t")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                _ => write!(f, "{}", c)?,
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(f, "\\x{:02x}", byte)?;
+        }
+    }
+    write!(f, "'")
+}
+
+#[cfg(not(unix))]
+fn fmt_non_utf8_arg(f: &mut fmt::Formatter<'_>, arg: &OsStr) -> fmt::Result {
+    write!(f, "'{}' (not valid unicode)", arg.to_string_lossy().replace('\'', r"'\''"))
+}
+
 impl From<Cmd> for std::process::Command {
     fn from(cmd: Cmd) -> Self {
-        cmd.command()
+        cmd.into_command()
     }
 }
 
 impl Cmd {
+    /// Creates a command without arguments, for building it up with
+    /// [`arg`](Cmd::arg) and [`args`](Cmd::args) instead of [`cmd!`].
     pub fn new(program: impl AsRef<Path>) -> Cmd {
         Cmd::_new(program.as_ref())
     }
+    /// Runs `script` with `sh -c`, for when shell features like loops or
+    /// globs are really needed. See [`shell_cmd!`] for interpolation.
+    ///
+    /// Arguments added afterwards are the positional parameters `$1`, `$2`
+    /// and so on of the script. The echo shows the script as is, preceded by
+    /// `set -- args;` if there are any.
+    ///
+    /// The shell is `/bin/sh` on Unix and `sh` from `PATH` elsewhere, like the
+    /// one from Git for Windows. Use [`Cmd::shell_with`] for another
+    /// POSIX-compatible shell. PowerShell and cmd.exe don't take positional
+    /// parameters, so they can't be used this way. For cmd.exe builtins, see
+    /// [`Cmd::via_shell`].
+    pub fn shell(script: impl AsRef<OsStr>) -> Cmd {
+        let shell = if cfg!(unix) { "/bin/sh" } else { "sh" };
+        Cmd::shell_with(shell, script)
+    }
+    /// Like [`Cmd::shell`], but with `shell`, like `bash`, which has to
+    /// support `-c script name args...`.
+    pub fn shell_with(shell: impl AsRef<Path>, script: impl AsRef<OsStr>) -> Cmd {
+        let mut res = Cmd::_new(shell.as_ref());
+        res.args.extend(
+            [OsStr::new("-c"), script.as_ref(), shell.as_ref().as_os_str()]
+                .map(OsStr::to_os_string),
+        );
+        res.shell_script = true;
+        res
+    }
     fn _new(program: &Path) -> Cmd {
-        Cmd { args: vec![program.as_os_str().to_owned()], stdin_contents: None }
+        Cmd {
+            args: vec![program.as_os_str().to_owned()],
+            stdin_contents: None,
+            stdin_file: None,
+            stdout_file: None,
+            stderr_file: None,
+            null_stdout: false,
+            null_stderr: false,
+            merge_stderr: false,
+            via_shell: false,
+            shell_script: false,
+            shell_dir: None,
+            dir: None,
+            shell_env: Vec::new(),
+            env: Vec::new(),
+            env_clear: false,
+            ignore_status: false,
+            echo_cmd: true,
+            retain_stderr: None,
+            tee: None,
+            force_run: false,
+            attempts: 1,
+            retry_delay: Duration::from_secs(1),
+            secrets: Vec::new(),
+            output_limit: Some(64 * 1024 * 1024),
+            process_group: true,
+            interactive: false,
+            keep_trailing_newline: false,
+            heartbeat: None,
+            timeout: None,
+        }
     }
 
+    /// Appends an argument, after the ones from the [`cmd!`] template.
     pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Cmd {
         self._arg(arg.as_ref());
         self
     }
+    /// Appends several arguments, after the ones from the [`cmd!`] template.
     pub fn args<I>(mut self, args: I) -> Cmd
     where
         I: IntoIterator,
         I::Item: AsRef<OsStr>,
     {
-        args.into_iter().for_each(|it| self._arg(it.as_ref()));
+        let args = args.into_iter();
+        self.args.reserve(args.size_hint().0);
+        args.for_each(|it| self._arg(it.as_ref()));
+        self
+    }
+    /// Like [`Cmd::args`], but moves owned arguments, like `Vec<OsString>` or
+    /// `Vec<PathBuf>`, into the command instead of copying them.
+    pub fn args_owned<I>(mut self, args: I) -> Cmd
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
         self
     }
+    /// Appends an argument only if `cond` is true, for optional flags like
+    /// `--release`.
     pub fn arg_if(mut self, cond: bool, arg: impl AsRef<OsStr>) -> Cmd {
         if cond {
             self._arg(arg.as_ref())
@@ -104,7 +554,45 @@ impl Cmd {
         self
     }
     fn ___extend_arg(&mut self, arg: &OsStr) {
-        self.args.last_mut().unwrap().push(arg)
+        extend_masked(self.args.last_mut().unwrap(), arg, false, &mut self.secrets)
+    }
+    #[doc(hidden)]
+    pub fn __interpolate_arg(mut self, arg: __private::Arg<'_>) -> Cmd {
+        self.args.push(OsString::new());
+        extend_masked(self.args.last_mut().unwrap(), &arg.value, arg.secret, &mut self.secrets);
+        self
+    }
+    #[doc(hidden)]
+    pub fn __interpolate_extend_arg(mut self, arg: __private::Arg<'_>) -> Cmd {
+        extend_masked(self.args.last_mut().unwrap(), &arg.value, arg.secret, &mut self.secrets);
+        self
+    }
+    #[doc(hidden)]
+    pub fn __env(mut self, key: &str) -> Cmd {
+        self._env(OsStr::new(key), Some(OsStr::new("")));
+        self
+    }
+    #[doc(hidden)]
+    pub fn __extend_env(mut self, value: impl AsRef<OsStr>) -> Cmd {
+        self._extend_env(value.as_ref(), false);
+        self
+    }
+    #[doc(hidden)]
+    pub fn __interpolate_extend_env(mut self, value: __private::Arg<'_>) -> Cmd {
+        self._extend_env(&value.value, value.secret);
+        self
+    }
+    fn _extend_env(&mut self, value: &OsStr, secret: bool) {
+        let (_key, last) = self.env.last_mut().unwrap();
+        extend_masked(last.as_mut().unwrap(), value, secret, &mut self.secrets)
+    }
+
+    /// Adds an argument which is shown as `***`, like a [`Secret`].
+    pub fn secret_arg(mut self, arg: impl AsRef<OsStr>) -> Cmd {
+        let arg = arg.as_ref();
+        self.secrets.push((arg.to_os_string(), "***".into()));
+        self._arg(arg);
+        self
     }
 
     pub fn stdin(mut self, stdin: impl AsRef<[u8]>) -> Cmd {
@@ -112,55 +600,820 @@ impl Cmd {
         self
     }
     fn _stdin(&mut self, stdin: &[u8]) {
+        self.stdin_file = None;
         self.stdin_contents = Some(stdin.to_vec());
     }
 
-    pub fn read(self) -> Result<String> {
-        match self.read_raw() {
-            Ok(output) if output.status.success() => {
-                let mut stdout = String::from_utf8(output.stdout)
-                    .map_err(|utf8_err| CmdErrorKind::NonUtf8Stdout(utf8_err).err(self))?;
-                if stdout.ends_with('\n') {
-                    stdout.pop();
+    /// Connects the file at `path` to the stdin of the command, like
+    /// `cmd < path` in the shell.
+    ///
+    /// A relative `path` is resolved against the working directory of the
+    /// command. The file is opened just before the command is spawned.
+    pub fn stdin_file(mut self, path: impl AsRef<Path>) -> Cmd {
+        self._stdin_file(path.as_ref());
+        self
+    }
+    fn _stdin_file(&mut self, path: &Path) {
+        self.stdin_contents = None;
+        self.stdin_file = Some(path.to_path_buf());
+    }
+
+    /// Connects the stdout of the command to the file at `path`, like
+    /// `cmd > path` in the shell.
+    ///
+    /// The file is created or truncated when the command is spawned. A
+    /// relative `path` is resolved against the working directory of the
+    /// command. Reading the stdout of such a command, with [`Cmd::read`] and
+    /// the like, is an error.
+    pub fn stdout_file(mut self, path: impl AsRef<Path>) -> Cmd {
+        self.null_stdout = false;
+        self.stdout_file = Some(Redirect { path: path.as_ref().to_path_buf(), append: false });
+        self
+    }
+    /// Like [`Cmd::stdout_file`], but appends to the file, like `cmd >> path`.
+    pub fn stdout_file_append(mut self, path: impl AsRef<Path>) -> Cmd {
+        self.null_stdout = false;
+        self.stdout_file = Some(Redirect { path: path.as_ref().to_path_buf(), append: true });
+        self
+    }
+    /// Connects the stderr of the command to the file at `path`, like
+    /// `cmd 2> path` in the shell.
+    pub fn stderr_file(mut self, path: impl AsRef<Path>) -> Cmd {
+        self.null_stderr = false;
+        self.stderr_file = Some(Redirect { path: path.as_ref().to_path_buf(), append: false });
+        self
+    }
+    /// Like [`Cmd::stderr_file`], but appends to the file, like `cmd 2>> path`.
+    pub fn stderr_file_append(mut self, path: impl AsRef<Path>) -> Cmd {
+        self.null_stderr = false;
+        self.stderr_file = Some(Redirect { path: path.as_ref().to_path_buf(), append: true });
+        self
+    }
+
+    /// Discards the stdout of the command, like `cmd > /dev/null`, but
+    /// without showing that in the echo. Reading the stdout of such a
+    /// command is an error.
+    pub fn null_stdout(mut self) -> Cmd {
+        self.stdout_file = None;
+        self.null_stdout = true;
+        self
+    }
+    /// Discards the stderr of the command, like `cmd 2> /dev/null`, but
+    /// without showing that in the echo.
+    pub fn null_stderr(mut self) -> Cmd {
+        self.stderr_file = None;
+        self.null_stderr = true;
+        self
+    }
+    /// Runs the command through the system shell, `sh -c` on Unix and
+    /// `cmd /C` on Windows, so that shell builtins like `mklink`, `copy` or
+    /// `cd` work as programs.
+    ///
+    /// The arguments are still passed as they are, the shell doesn't split,
+    /// glob or expand them. On Unix, they are the positional parameters of
+    /// `sh -c '"$@"'`. cmd.exe can't take arguments separately, so on Windows
+    /// they are escaped on the command line instead, and it is up to the
+    /// builtin to parse its arguments: most follow the usual double quote
+    /// rules, but there is no guarantee for every one of them. The exit
+    /// status and the output are those of the shell, which are the ones of
+    /// the command.
+    pub fn via_shell(mut self) -> Cmd {
+        self.via_shell = true;
+        self
+    }
+
+    /// Sends the stderr of the command wherever its stdout goes, like
+    /// `cmd 2>&1`.
+    ///
+    /// When the output is captured, both streams share one pipe, so
+    /// [`Cmd::read`] returns them interleaved in the order they were written.
+    pub fn merge_stderr(mut self) -> Cmd {
+        self.merge_stderr = true;
+        self
+    }
+
+    /// Runs this command in `dir`. A relative `dir` is resolved against the
+    /// current directory of the process (or of the [`Shell`]).
+    pub fn dir(mut self, dir: impl AsRef<Path>) -> Cmd {
+        self._dir(dir.as_ref());
+        self
+    }
+    fn _dir(&mut self, dir: &Path) {
+        self.dir = Some(dir.to_path_buf());
+    }
+
+    /// Sets an environmental variable for this command only.
+    pub fn env(mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> Cmd {
+        self._env(key.as_ref(), Some(value.as_ref()));
+        self
+    }
+    /// Removes an environmental variable inherited by this command.
+    pub fn env_remove(mut self, key: impl AsRef<OsStr>) -> Cmd {
+        self._env(key.as_ref(), None);
+        self
+    }
+    /// Starts this command with an empty environment, apart from the variables
+    /// set afterwards with [`Cmd::env`].
+    ///
+    /// On Windows, `SYSTEMROOT` is still passed through (unless overridden),
+    /// as without it even `cmd.exe` fails to start.
+    pub fn env_clear(mut self) -> Cmd {
+        self.env_clear = true;
+        self.shell_env.clear();
+        self.env.clear();
+        self
+    }
+    /// Freezes the environment the command inherits from the process at this
+    /// point, so that changes made later, like a [`pushenv`](crate::pushenv)
+    /// on another thread, don't affect it.
+    ///
+    /// Variables set with [`Cmd::env`] and by the [`Shell`] still apply on
+    /// top of the snapshot.
+    pub fn env_snapshot(mut self) -> Cmd {
+        let snapshot = {
+            let _guard = crate::lock();
+            std::env::vars_os().map(|(k, v)| (k, Some(v))).collect::<Vec<_>>()
+        };
+        if !self.env_clear {
+            self.env_clear = true;
+            self.shell_env.splice(..0, snapshot);
+        }
+        self
+    }
+    fn _env(&mut self, key: &OsStr, value: Option<&OsStr>) {
+        self.env.retain(|(k, _)| k != key);
+        self.env.push((key.to_os_string(), value.map(OsStr::to_os_string)));
+    }
+
+    /// Don't treat a non-zero exit status as an error. Failure to spawn the
+    /// command is still reported.
+    pub fn ignore_status(mut self) -> Cmd {
+        self.ignore_status = true;
+        self
+    }
+
+    /// Controls whether [`Cmd::run`] prints the command before running it.
+    /// Error messages always include the command.
+    ///
+    /// [`Cmd::read`] and friends never echo.
+    pub fn echo_cmd(mut self, echo_cmd: bool) -> Cmd {
+        self.echo_cmd = echo_cmd;
+        self
+    }
+    /// Shorthand for `echo_cmd(false)`.
+    pub fn quiet(self) -> Cmd {
+        self.echo_cmd(false)
+    }
+
+    /// While [`Cmd::run`] streams the command's stderr to our stderr, keep its
+    /// last `max_bytes` to include in the error if the command fails. Zero
+    /// turns this off.
+    ///
+    /// By default, the last 4 KiB are retained when our stderr is not a
+    /// terminal, that is, when the output is likely to end up in a log.
+    pub fn retain_stderr(mut self, max_bytes: usize) -> Cmd {
+        self.retain_stderr = Some(max_bytes);
+        self
+    }
+    fn stderr_retention(&self) -> Option<usize> {
+        match self.retain_stderr {
+            Some(0) => None,
+            Some(max_bytes) => Some(max_bytes),
+            None if io::stderr().is_terminal() => None,
+            None => Some(4 * 1024),
+        }
+    }
+
+    /// Runs the command up to `attempts` times, until it succeeds.
+    ///
+    /// Only a non-zero exit status is retried, failing to spawn the
+    /// command is not. The delay between attempts starts at one second and
+    /// doubles every time, see [`Cmd::retry_delay`]. Each retry is echoed
+    /// like `$ git fetch  # retrying (attempt 2/3)`. Pipelines,
+    /// [`Cmd::spawn`] and [`Cmd::read_lines`] are not retried.
+    pub fn retry(mut self, attempts: u32) -> Cmd {
+        self.attempts = attempts.max(1);
+        self
+    }
+    /// Sets the delay before the first retry, see [`Cmd::retry`].
+    pub fn retry_delay(mut self, delay: Duration) -> Cmd {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Runs the command even in [dry-run mode](crate::set_dry_run), for
+    /// queries the rest of the script needs, like `git rev-parse HEAD`.
+    pub fn force_run(mut self) -> Cmd {
+        self.force_run = true;
+        self
+    }
+
+    /// Makes [`Cmd::read`], [`Cmd::output`] and the like forward the output
+    /// of the command to our stdout and stderr as it arrives, in addition to
+    /// capturing it.
+    ///
+    /// Only the last `max_bytes` of each stream are captured.
+    pub fn tee(mut self, max_bytes: usize) -> Cmd {
+        self.tee = Some(max_bytes);
+        self
+    }
+
+    /// Kills the command (together with the processes it spawned, on Unix)
+    /// if it doesn't finish within `timeout`.
+    ///
+    /// On Windows, only the command itself is killed, so a process it
+    /// started keeps running, and keeps its stdout and stderr open.
+    pub fn timeout(mut self, timeout: Duration) -> Cmd {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// While [`Cmd::run`], [`Cmd::read`] and the like wait for the command,
+    /// prints `still running after 5m0s: cmd` to stderr every `interval`, so
+    /// that CI doesn't kill a job which is silent for too long.
+    pub fn heartbeat(self, interval: Duration) -> Cmd {
+        self._heartbeat(interval, None)
+    }
+    /// Like [`Cmd::heartbeat`], but calls `callback` with the time elapsed
+    /// since the command started instead. The callback runs on a separate
+    /// thread and is never called once the command has exited.
+    pub fn heartbeat_with(
+        self,
+        interval: Duration,
+        callback: impl Fn(Duration) + Send + Sync + 'static,
+    ) -> Cmd {
+        self._heartbeat(interval, Some(Arc::new(callback)))
+    }
+    fn _heartbeat(mut self, interval: Duration, callback: Option<HeartbeatFn>) -> Cmd {
+        self.heartbeat = Some(Heartbeat { interval, callback });
+        self
+    }
+
+    /// Limits how much output [`Cmd::read`] and [`Cmd::output`] collect. A
+    /// command which prints more than `max_bytes` to its stdout or stderr is
+    /// killed, and an error is returned, even with
+    /// [`ignore_status`](Cmd::ignore_status). For [`Cmd::read_lines`], which
+    /// doesn't collect the stdout, this limits the stderr only.
+    ///
+    /// The default is 64 MiB.
+    pub fn output_limit(mut self, max_bytes: usize) -> Cmd {
+        self.output_limit = Some(max_bytes);
+        self
+    }
+    /// Collects the output, however large it is.
+    pub fn no_output_limit(mut self) -> Cmd {
+        self.output_limit = None;
+        self
+    }
+
+    /// Whether the command runs in its own process group on Unix when it may
+    /// need to be killed, that is, with a [`timeout`](Cmd::timeout), when
+    /// [spawned](Cmd::spawn) or with [`Cmd::read_lines`]. Killing it then
+    /// kills the processes it started too. A SIGINT or SIGTERM we get is
+    /// passed on to the group, like it would reach the command without it.
+    ///
+    /// On by default, except for commands which get our stdin while we are in
+    /// the foreground of a terminal. Those stay in the foreground process
+    /// group, so that they can read from the terminal, and killing one doesn't
+    /// reach the processes it started. Turn it off for programs which
+    /// misbehave in a process group of their own, like ones which open
+    /// `/dev/tty` to prompt.
+    ///
+    /// This has no effect on Windows, where killing a command never kills the
+    /// processes it started, as xshell doesn't put them into a Job Object.
+    pub fn process_group(mut self, yes: bool) -> Cmd {
+        self.process_group = yes;
+        self
+    }
+
+    /// Makes [`Cmd::read`] return the stdout verbatim, instead of trimming a
+    /// trailing newline.
+    pub fn keep_trailing_newline(mut self) -> Cmd {
+        self.keep_trailing_newline = true;
+        self
+    }
+
+    /// Runs the command and returns its stdout.
+    ///
+    /// One trailing line ending, `\n` or `\r\n`, is trimmed, like `$(cmd)` in
+    /// the shell does, but only one, and other trailing whitespace is kept.
+    /// See [`Cmd::keep_trailing_newline`] to get the stdout as is.
+    pub fn read(&self) -> Result<String> {
+        let cmd = self.hooked();
+        let res = cmd.checked_output(true);
+        cmd.into_owned().stdout_string(res)
+    }
+    fn stdout_string(self, res: std::result::Result<Output, CmdErrorKind>) -> Result<String> {
+        let output = match res {
+            Ok(it) => it,
+            Err(kind) => return Err(kind.err(self)),
+        };
+        let mut stdout = match String::from_utf8(output.stdout) {
+            Ok(it) => it,
+            Err(utf8_err) => return Err(CmdErrorKind::NonUtf8Stdout(utf8_err).err(self)),
+        };
+        if !self.keep_trailing_newline {
+            trim_newline(&mut stdout);
+        }
+        Ok(stdout)
+    }
+
+    /// Like [`Cmd::read`], but also parses the output with `parse`, like
+    /// `serde_json::from_str`:
+    ///
+    /// ```ignore
+    /// let metadata: Metadata = cmd!("cargo metadata --format-version 1")
+    ///     .read_with(serde_json::from_str)?;
+    /// ```
+    ///
+    /// If parsing fails, the error has the message of the parser, and the
+    /// line of the output it points at, if any.
+    pub fn read_with<T, E: fmt::Display>(
+        &self,
+        parse: impl FnOnce(&str) -> std::result::Result<T, E>,
+    ) -> Result<T> {
+        let stdout = self.read()?;
+        parse(&stdout).map_err(|err| {
+            CmdErrorKind::Parse(error::parse_error(&stdout, &err.to_string())).err(self.clone())
+        })
+    }
+
+    /// Runs the command and deserializes its stdout as JSON, for tools with
+    /// a `--format json` flag:
+    ///
+    /// ```ignore
+    /// let metadata: Metadata = cmd!("cargo metadata --format-version 1").read_json()?;
+    /// ```
+    ///
+    /// Errors are like the ones of [`Cmd::read_with`].
+    #[cfg(feature = "serde")]
+    pub fn read_json<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        self.read_with(|stdout| serde_json::from_str(stdout))
+    }
+
+    /// Converts this into a [`std::process::Command`], for the things xshell
+    /// doesn't do.
+    ///
+    /// The program, the arguments, the working directory and the environment
+    /// are kept. Everything that happens while xshell runs the command is
+    /// not: echoing, checking the exit status, redirections like
+    /// [`Cmd::stdin`], and [`Cmd::timeout`].
+    pub fn into_command(self) -> std::process::Command {
+        self.command()
+    }
+
+    /// Starts the command in the background, echoing it like [`Cmd::run`].
+    pub fn spawn(self) -> Result<Child> {
+        let cmd = self.into_hooked();
+        if cmd.echo_cmd {
+            echo_cmds(std::slice::from_ref(&cmd));
+        }
+        Child::spawn(cmd)
+    }
+
+    /// Spawns the command and returns an iterator over the lines of its
+    /// stdout, without waiting for the command to finish.
+    pub fn read_lines(self) -> Result<ReadLines> {
+        ReadLines::spawn(self.into_hooked())
+    }
+
+    pub fn read_bytes(&self) -> Result<Vec<u8>> {
+        self.output().map(|output| output.stdout)
+    }
+
+    pub fn read_stderr_bytes(&self) -> Result<Vec<u8>> {
+        self.output().map(|output| output.stderr)
+    }
+
+    pub fn output(&self) -> Result<Output> {
+        let cmd = self.hooked();
+        cmd.checked_output(true).map_err(|kind| kind.err(cmd.into_owned()))
+    }
+
+    /// Runs the command, echoing it first. Like the other ways to run a
+    /// command, this doesn't consume it, so the same `Cmd` can be run again.
+    pub fn run(&self) -> Result<()> {
+        let cmd = self.hooked();
+        if cmd.echo_cmd {
+            echo_cmds(std::slice::from_ref(&*cmd));
+        }
+        cmd.checked_output(false).map(|_output| ()).map_err(|kind| kind.err(cmd.into_owned()))
+    }
+
+    /// Runs the command with the terminal handed over to it, for things like
+    /// `ssh`, `psql` or `$EDITOR`.
+    ///
+    /// The command inherits stdin, stdout and stderr, and nothing is
+    /// captured. On Unix, it stays in the foreground process group, and we
+    /// ignore `SIGINT` and `SIGQUIT` while waiting for it, like `system(3)`
+    /// does, so Ctrl-C goes to the command only. The command is echoed
+    /// unless it is [`quiet`](Cmd::quiet), and the exit status is checked
+    /// like with [`Cmd::run`].
+    pub fn run_interactive(&self) -> Result<()> {
+        let mut cmd = self.clone();
+        cmd.interactive = true;
+        cmd.retain_stderr = Some(0);
+        cmd.process_group = false;
+        cmd.run()
+    }
+
+    /// For wrapper scripts: runs the command like [`Cmd::run`], and then
+    /// exits the process with the same exit code. If the command was killed
+    /// by a signal, we kill ourselves with the same signal on Unix.
+    ///
+    /// If the command can't be started, the error is printed, and the exit
+    /// code is 127 for a missing program and 126 otherwise, like in the
+    /// shell. As with [`std::process::exit`], destructors don't run.
+    pub fn run_and_propagate(&self) -> ! {
+        let cmd = self.hooked();
+        if cmd.echo_cmd {
+            echo_cmds(std::slice::from_ref(&*cmd));
+        }
+        match cmd.checked_output(false) {
+            Ok(output) => exec::exit_like(output.status),
+            Err(CmdErrorKind::NonZeroStatus { status, .. }) => exec::exit_like(status),
+            Err(kind) => {
+                let err = kind.err(cmd.into_owned());
+                eprintln!("error: {}", err);
+                std::process::exit(if err.is_program_missing() { 127 } else { 126 })
+            }
+        }
+    }
+
+    /// Replaces the current process with the command, like `exec` in the
+    /// shell, so its exit code and signals reach our parent as is. Returns
+    /// only if that fails.
+    ///
+    /// Like with [`Cmd::into_command`], redirections and timeouts don't
+    /// apply. On platforms other than Unix, this falls back to
+    /// [`Cmd::run_and_propagate`].
+    pub fn exec_replace(&self) -> Error {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+
+            let cmd = self.hooked();
+            if cmd.echo_cmd {
+                echo_cmds(std::slice::from_ref(&*cmd));
+            }
+            if is_dry_run() && !cmd.force_run {
+                std::process::exit(0)
+            }
+            let io_err = cmd.command().exec();
+            exec::spawn_err(&cmd, io_err).err(cmd.into_owned())
+        }
+        #[cfg(not(unix))]
+        {
+            self.run_and_propagate()
+        }
+    }
+
+    /// This command as rewritten by the [`set_cmd_hook`] hooks.
+    fn hooked(&self) -> Cow<'_, Cmd> {
+        match hook::apply(self) {
+            Some(it) => Cow::Owned(it),
+            None => Cow::Borrowed(self),
+        }
+    }
+    fn into_hooked(self) -> Cmd {
+        hook::apply(&self).unwrap_or(self)
+    }
+
+    fn checked_output(&self, capture: bool) -> std::result::Result<Output, CmdErrorKind> {
+        timed(self, || self.checked_output_retrying(capture))
+    }
+    fn checked_output_retrying(&self, capture: bool) -> std::result::Result<Output, CmdErrorKind> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let res = exec_pipeline(slice::from_ref(self), capture)
+                .map_err(|(_idx, kind)| kind)
+                .and_then(|(_statuses, output)| self.check_status(output));
+            match res {
+                Err(CmdErrorKind::NonZeroStatus { .. }) if attempt < self.attempts => {
+                    attempt += 1;
+                    self.echo_retry(attempt);
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
                 }
+                res => return res,
+            }
+        }
+    }
+    fn check_status(&self, output: Output) -> std::result::Result<Output, CmdErrorKind> {
+        if output.status.success() || self.ignore_status {
+            return Ok(output);
+        }
+        Err(CmdErrorKind::NonZeroStatus { status: output.status, stderr: output.stderr })
+    }
+    fn echo_retry(&self, attempt: u32) {
+        if self.echo_cmd {
+            echo(&format_args!("{}  # retrying (attempt {}/{})", self, attempt, self.attempts));
+        }
+    }
 
-                Ok(stdout)
+    /// Like [`Cmd::run`], but doesn't block the current thread.
+    ///
+    /// The command starts when the future is first polled, and is killed if
+    /// the future is dropped before it finishes. Everything else works like
+    /// with [`Cmd::run`], timeouts and retries included.
+    #[cfg(feature = "async")]
+    pub fn run_async(&self) -> CmdFuture<Result<()>> {
+        let cmd = self.hooked().into_owned();
+        CmdFuture::new(async move {
+            if cmd.echo_cmd {
+                echo_cmds(slice::from_ref(&cmd));
+            }
+            let res = cmd.checked_output_async(false).await;
+            res.map(|_output| ()).map_err(|kind| kind.err(cmd))
+        })
+    }
+    /// Like [`Cmd::read`], but doesn't block the current thread, see
+    /// [`Cmd::run_async`].
+    #[cfg(feature = "async")]
+    pub fn read_async(&self) -> CmdFuture<Result<String>> {
+        let cmd = self.hooked().into_owned();
+        CmdFuture::new(async move {
+            let res = cmd.checked_output_async(true).await;
+            cmd.stdout_string(res)
+        })
+    }
+    /// Like [`Cmd::output`], but doesn't block the current thread, see
+    /// [`Cmd::run_async`].
+    #[cfg(feature = "async")]
+    pub fn output_async(&self) -> CmdFuture<Result<Output>> {
+        let cmd = self.hooked().into_owned();
+        CmdFuture::new(async move {
+            let res = cmd.checked_output_async(true).await;
+            res.map_err(|kind| kind.err(cmd))
+        })
+    }
+    #[cfg(feature = "async")]
+    async fn checked_output_async(
+        &self,
+        capture: bool,
+    ) -> std::result::Result<Output, CmdErrorKind> {
+        let start = std::time::Instant::now();
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        let res = loop {
+            let res = exec::exec_async(self, capture).await;
+            match res.and_then(|output| self.check_status(output)) {
+                Err(CmdErrorKind::NonZeroStatus { .. }) if attempt < self.attempts => {
+                    attempt += 1;
+                    self.echo_retry(attempt);
+                    async_io::Timer::after(delay).await;
+                    delay = delay.saturating_mul(2);
+                }
+                res => break res,
             }
-            Ok(output) => Err(CmdErrorKind::NonZeroStatus(output.status).err(self)),
-            Err(io_err) => Err(CmdErrorKind::Io(io_err).err(self)),
+        };
+        timing::took(self, start.elapsed());
+        res
+    }
+
+    pub fn pipe(self, next: Cmd) -> Pipeline {
+        Pipeline { cmds: vec![self, next] }
+    }
+
+    fn working_dir(&self) -> Option<PathBuf> {
+        match (&self.shell_dir, &self.dir) {
+            (Some(shell_dir), Some(dir)) => Some(shell_dir.join(dir)),
+            (None, Some(dir)) => Some(dir.clone()),
+            (shell_dir, None) => shell_dir.clone(),
         }
     }
-    fn read_raw(&self) -> io::Result<Output> {
-        let mut child = self
-            .command()
-            .stdin(match &self.stdin_contents {
-                Some(_) => Stdio::piped(),
-                None => Stdio::null(),
-            })
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?;
 
-        if let Some(stdin_contents) = &self.stdin_contents {
-            let mut stdin = child.stdin.take().unwrap();
-            stdin.write_all(stdin_contents)?;
-            stdin.flush()?;
+    /// The value of the environmental variable, as the command will see it.
+    fn env_var(&self, key: &OsStr) -> Option<OsString> {
+        // Environment variables are case-insensitive on Windows.
+        let same_key =
+            |k: &OsStr| if cfg!(windows) { k.eq_ignore_ascii_case(key) } else { k == key };
+        let find = |env: &[(OsString, Option<OsString>)]| {
+            env.iter().rev().find(|(k, _)| same_key(k)).map(|(_, v)| v.clone())
+        };
+        if let Some(value) = find(&self.env) {
+            return value;
+        }
+        if let Some(value) = find(&self.shell_env) {
+            return value;
         }
-        child.wait_with_output()
+        if self.env_clear {
+            return None;
+        }
+        std::env::var_os(key)
     }
 
-    pub fn run(self) -> Result<()> {
-        println!("$ {}", self);
-        match self.command().status() {
-            Ok(status) if status.success() => Ok(()),
-            Ok(status) => Err(CmdErrorKind::NonZeroStatus(status).err(self)),
-            Err(io_err) => Err(CmdErrorKind::Io(io_err).err(self)),
+    fn stdout_redirected(&self) -> bool {
+        self.stdout_file.is_some() || self.null_stdout
+    }
+
+    /// Resolves a path used for a redirection against the working directory.
+    fn redirect_path(&self, path: &Path) -> PathBuf {
+        match self.working_dir() {
+            Some(dir) => dir.join(path),
+            None => path.to_path_buf(),
         }
     }
 
     fn command(&self) -> std::process::Command {
-        let mut res = std::process::Command::new(&self.args[0]);
-        res.args(&self.args[1..]);
+        let mut res = if self.via_shell {
+            exec::shell_command(&self.args)
+        } else {
+            let program = self.args[0].as_os_str();
+            // Batch files found this way are run through `cmd.exe /c` by `std`,
+            // which takes care of escaping the arguments for it.
+            #[cfg(windows)]
+            let resolved = which::resolve_program(
+                Path::new(program),
+                self.env_var("PATH".as_ref()).as_deref(),
+            );
+            #[cfg(windows)]
+            let program = resolved.as_deref().map_or(program, Path::as_os_str);
+
+            let mut res = std::process::Command::new(program);
+            res.args(&self.args[1..]);
+            res
+        };
+        if let Some(dir) = self.working_dir() {
+            res.current_dir(dir);
+        }
+        if self.env_clear {
+            res.env_clear();
+            #[cfg(windows)]
+            {
+                if let Some(system_root) = std::env::var_os("SYSTEMROOT") {
+                    res.env("SYSTEMROOT", system_root);
+                }
+            }
+        }
+        for (key, value) in self.shell_env.iter().chain(&self.env) {
+            match value {
+                Some(value) => res.env(key, value),
+                None => res.env_remove(key),
+            };
+        }
         res
     }
 }
+
+/// A sequence of commands, with the stdout of each connected to the stdin of
+/// the next one, like `a | b | c` in the shell.
+///
+/// All the commands are spawned at once, so the data is streamed between them.
+/// Like with `set -o pipefail`, the pipeline fails if any of the commands
+/// fails, and the error is reported for the rightmost failed command. The
+/// stderr of all but the last command is inherited, even when capturing the
+/// output.
+#[must_use]
+#[derive(Debug)]
+pub struct Pipeline {
+    cmds: Vec<Cmd>,
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&DisplayCmds(&self.cmds), f)
+    }
+}
+
+fn trim_newline(text: &mut String) {
+    if text.ends_with('\n') {
+        text.pop();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+}
+
+/// Displays commands joined with pipes.
+pub(crate) struct DisplayCmds<'a>(pub(crate) &'a [Cmd]);
+
+impl fmt::Display for DisplayCmds<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        for cmd in self.0 {
+            write!(f, "{}{}", sep, cmd)?;
+            sep = " | ";
+        }
+        Ok(())
+    }
+}
+
+/// Displays a path quoted like an argument of a command.
+pub(crate) struct Quoted<'a>(pub(crate) &'a Path);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_arg(f, self.0.as_os_str())
+    }
+}
+
+impl Pipeline {
+    pub fn pipe(mut self, next: Cmd) -> Pipeline {
+        self.cmds.push(next);
+        self
+    }
+
+    pub fn read(self) -> Result<String> {
+        let (last, output) = self.hooked().exec(true)?;
+        let keep_trailing_newline = last.keep_trailing_newline;
+        let mut stdout = String::from_utf8(output.stdout)
+            .map_err(|utf8_err| CmdErrorKind::NonUtf8Stdout(utf8_err).err(last))?;
+        if !keep_trailing_newline {
+            trim_newline(&mut stdout);
+        }
+        Ok(stdout)
+    }
+
+    pub fn output(self) -> Result<Output> {
+        self.hooked().exec(true).map(|(_last, output)| output)
+    }
+
+    pub fn run(self) -> Result<()> {
+        let pipeline = self.hooked();
+        if pipeline.cmds.iter().any(|it| it.echo_cmd) {
+            echo_cmds(&pipeline.cmds);
+        }
+        pipeline.exec(false).map(|_| ())
+    }
+
+    fn hooked(mut self) -> Pipeline {
+        for cmd in &mut self.cmds {
+            if let Some(hooked) = hook::apply(cmd) {
+                *cmd = hooked;
+            }
+        }
+        self
+    }
+
+    fn exec(mut self, capture: bool) -> Result<(Cmd, Output)> {
+        match timed(&self, || exec_pipeline(&self.cmds, capture)) {
+            Ok((statuses, output)) => match statuses
+                .iter()
+                .zip(&self.cmds)
+                .rposition(|(status, cmd)| !status.success() && !cmd.ignore_status)
+            {
+                Some(idx) => {
+                    // Only the stderr of the last command is captured.
+                    let stderr =
+                        if idx + 1 == self.cmds.len() { output.stderr } else { Vec::new() };
+                    let kind = CmdErrorKind::NonZeroStatus { status: statuses[idx], stderr };
+                    Err(kind.err(self.cmds.swap_remove(idx)))
+                }
+                None => Ok((self.cmds.pop().unwrap(), output)),
+            },
+            Err((idx, kind)) => Err(kind.err(self.cmds.swap_remove(idx))),
+        }
+    }
+}
+
+#[doc(hidden)]
+pub mod __private {
+    //! Interpolation in `cmd!` uses `AsRef<OsStr>` if possible, and falls back
+    //! to `Display` otherwise. `ViaOsStr` is implemented for `ArgWrap` and
+    //! `ViaDisplay` for `&ArgWrap`, so method resolution picks the former
+    //! when both apply. [`Secret`](crate::Secret)s have an inherent method,
+    //! which wins over both.
+
+    use std::{borrow::Cow, ffi::OsStr, fmt, path::Path};
+
+    use crate::Secret;
+
+    pub struct ArgWrap<'a, T: ?Sized>(pub &'a T);
+
+    pub struct Arg<'a> {
+        pub(crate) value: Cow<'a, OsStr>,
+        pub(crate) secret: bool,
+    }
+
+    impl AsRef<Path> for Arg<'_> {
+        fn as_ref(&self) -> &Path {
+            Path::new(&*self.value)
+        }
+    }
+
+    pub trait ViaOsStr {
+        fn __into_arg(&self) -> Arg<'_>;
+    }
+    impl<T: AsRef<OsStr> + ?Sized> ViaOsStr for ArgWrap<'_, T> {
+        fn __into_arg(&self) -> Arg<'_> {
+            Arg { value: Cow::Borrowed(self.0.as_ref()), secret: false }
+        }
+    }
+
+    pub trait ViaDisplay {
+        fn __into_arg(&self) -> Arg<'_>;
+    }
+    impl<T: fmt::Display + ?Sized> ViaDisplay for &ArgWrap<'_, T> {
+        fn __into_arg(&self) -> Arg<'_> {
+            Arg { value: Cow::Owned(self.0.to_string().into()), secret: false }
+        }
+    }
+
+    impl<T: AsRef<OsStr>> ArgWrap<'_, Secret<T>> {
+        pub fn __into_arg(&self) -> Arg<'_> {
+            Arg { value: Cow::Borrowed((self.0).0.as_ref()), secret: true }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    cwd, fs,
+    which::{_which, _which_all},
+    Cmd, Result,
+};
+
+/// A shell with its own current directory and environment.
+///
+/// Unlike [`pushd`](crate::pushd) and [`pushenv`](crate::pushenv), which
+/// mutate the state of the whole process, changes made to a `Shell` only
+/// affect commands and file system operations run through it. The free
+/// functions keep working on the process-global state.
+#[derive(Debug, Clone)]
+pub struct Shell {
+    cwd: PathBuf,
+    env: BTreeMap<OsString, Option<OsString>>,
+    quiet: bool,
+}
+
+impl Shell {
+    pub fn new() -> Result<Shell> {
+        Ok(Shell { cwd: cwd()?, env: BTreeMap::new(), quiet: false })
+    }
+
+    pub fn current_dir(&self) -> &Path {
+        &self.cwd
+    }
+    /// Changes the directory of this shell, like `cd`: a relative `dir` is
+    /// resolved against the current one, and `..` removes the last component
+    /// rather than following symlinks.
+    pub fn change_dir(&mut self, dir: impl AsRef<Path>) {
+        self._change_dir(dir.as_ref())
+    }
+    fn _change_dir(&mut self, dir: &Path) {
+        self.cwd = join_logical(&self.cwd, dir);
+    }
+    /// Returns a copy of this shell in `dir`, see [`Shell::change_dir`].
+    ///
+    /// ```no_run
+    /// # use xshell::{cmd, Shell};
+    /// let sh = Shell::new()?;
+    /// let frontend = sh.with_dir("../frontend");
+    /// cmd!(frontend, "npm ci").run()?;
+    /// frontend.write_file("dist/.keep", "")?;
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    pub fn with_dir(&self, dir: impl AsRef<Path>) -> Shell {
+        let mut res = self.clone();
+        res._change_dir(dir.as_ref());
+        res
+    }
+
+    pub fn var_os(&self, key: impl AsRef<OsStr>) -> Option<OsString> {
+        self._var_os(key.as_ref())
+    }
+    fn _var_os(&self, key: &OsStr) -> Option<OsString> {
+        match self.env.get(key) {
+            Some(value) => value.clone(),
+            None => std::env::var_os(key),
+        }
+    }
+    pub fn set_var(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) {
+        self._set_var(key.as_ref(), value.as_ref())
+    }
+    fn _set_var(&mut self, key: &OsStr, value: &OsStr) {
+        self.env.insert(key.to_os_string(), Some(value.to_os_string()));
+    }
+    pub fn remove_var(&mut self, key: impl AsRef<OsStr>) {
+        self._remove_var(key.as_ref())
+    }
+    fn _remove_var(&mut self, key: &OsStr) {
+        self.env.insert(key.to_os_string(), None);
+    }
+
+    /// Makes commands created from this shell [`quiet`](Cmd::quiet) by
+    /// default.
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    pub fn cmd(&self, program: impl AsRef<Path>) -> Cmd {
+        self.__attach(Cmd::new(program))
+    }
+    #[doc(hidden)]
+    pub fn __attach(&self, mut cmd: Cmd) -> Cmd {
+        cmd.shell_dir = Some(self.cwd.clone());
+        cmd.echo_cmd = !self.quiet;
+        cmd.shell_env.extend(self.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        cmd
+    }
+
+    /// Like [`which`](crate::which), but uses the `PATH` of this shell.
+    pub fn which(&self, program: impl AsRef<Path>) -> Result<PathBuf> {
+        _which(program.as_ref(), self.var_os("PATH"))
+    }
+    /// Like [`which_all`](crate::which_all), but uses the `PATH` of this shell.
+    pub fn which_all(&self, program: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+        _which_all(program.as_ref(), self.var_os("PATH"))
+    }
+
+    pub fn read_file(&self, path: impl AsRef<Path>) -> Result<String> {
+        fs::read_file(self.path(path.as_ref()))
+    }
+    pub fn read_file_with<T, E: std::fmt::Display>(
+        &self,
+        path: impl AsRef<Path>,
+        parse: impl FnOnce(&str) -> std::result::Result<T, E>,
+    ) -> Result<T> {
+        fs::read_file_with(self.path(path.as_ref()), parse)
+    }
+    #[cfg(feature = "serde")]
+    pub fn read_json_file<T: serde::de::DeserializeOwned>(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<T> {
+        fs::read_json_file(self.path(path.as_ref()))
+    }
+    #[cfg(feature = "serde")]
+    pub fn read_toml_file<T: serde::de::DeserializeOwned>(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<T> {
+        fs::read_toml_file(self.path(path.as_ref()))
+    }
+    pub fn write_file(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+        fs::write_file(self.path(path.as_ref()), contents)
+    }
+    pub fn mkdir_p(&self, path: impl AsRef<Path>) -> Result<bool> {
+        fs::mkdir_p(self.path(path.as_ref()))
+    }
+    pub fn cp(&self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+        fs::cp(self.path(src.as_ref()), self.path(dst.as_ref()))
+    }
+    pub fn cp_opts(
+        &self,
+        src: impl AsRef<Path>,
+        dst: impl AsRef<Path>,
+        opts: fs::CopyOptions,
+    ) -> Result<fs::CopyStats> {
+        fs::cp_opts(self.path(src.as_ref()), self.path(dst.as_ref()), opts)
+    }
+    pub fn read_dir(&self, path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+        fs::read_dir(self.path(path.as_ref()))
+    }
+    pub fn read_dir_visible(&self, path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+        fs::read_dir_visible(self.path(path.as_ref()))
+    }
+    pub fn rm_rf(&self, path: impl AsRef<Path>) -> Result<fs::RemoveStats> {
+        fs::rm_rf(self.path(path.as_ref()))
+    }
+    pub fn path_exists(&self, path: impl AsRef<Path>) -> bool {
+        fs::path_exists(self.path(path.as_ref()))
+    }
+    pub fn is_file(&self, path: impl AsRef<Path>) -> bool {
+        fs::is_file(self.path(path.as_ref()))
+    }
+    pub fn is_dir(&self, path: impl AsRef<Path>) -> bool {
+        fs::is_dir(self.path(path.as_ref()))
+    }
+    pub fn metadata(&self, path: impl AsRef<Path>) -> Result<std::fs::Metadata> {
+        fs::metadata(self.path(path.as_ref()))
+    }
+    pub fn symlink_metadata(&self, path: impl AsRef<Path>) -> Result<std::fs::Metadata> {
+        fs::symlink_metadata(self.path(path.as_ref()))
+    }
+
+    fn path(&self, p: &Path) -> PathBuf {
+        self.cwd.join(p)
+    }
+}
+
+fn join_logical(base: &Path, path: &Path) -> PathBuf {
+    let mut res = base.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            // `..` of the root is the root.
+            Component::ParentDir => {
+                res.pop();
+            }
+            _ => res.push(component),
+        }
+    }
+    res
+}
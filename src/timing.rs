@@ -0,0 +1,107 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::echo::{verbosity, Verbosity};
+
+/// How many of the slowest commands [`command_timings`] keeps.
+const MAX_TIMINGS: usize = 1000;
+
+static RECORD: AtomicBool = AtomicBool::new(false);
+/// The fastest of the kept commands is on top.
+static TIMINGS: Mutex<BinaryHeap<Reverse<(Duration, String)>>> = Mutex::new(BinaryHeap::new());
+static SLOW_THRESHOLD: RwLock<Option<Duration>> = RwLock::new(None);
+
+/// Prints `(took 3m12s: $ cargo test)` to stderr after commands which take
+/// longer than `threshold`. `None` turns this off, which is the default.
+pub fn set_slow_command_threshold(threshold: Option<Duration>) {
+    *SLOW_THRESHOLD.write().unwrap() = threshold;
+}
+
+/// Turns recording the durations of commands for [`command_timings`] on or
+/// off for the whole process. Off by default.
+pub fn record_command_timings(yes: bool) {
+    RECORD.store(yes, Ordering::Relaxed)
+}
+
+/// Returns the slowest commands run while recording was on, see
+/// [`record_command_timings`], together with their wall-clock durations, the
+/// slowest first. Only the 1000 slowest commands are kept.
+///
+/// Every [`Cmd::run`], [`Cmd::read`] and the like is recorded, including the
+/// failed ones. Background commands ([`Cmd::spawn`], [`Cmd::read_lines`])
+/// are not.
+///
+/// ```no_run
+/// # use xshell::cmd;
+/// xshell::record_command_timings(true);
+/// cmd!("cargo build").run()?;
+/// cmd!("cargo test").run()?;
+/// for (cmd, duration) in xshell::command_timings() {
+///     eprintln!("{:>10.1?}  {}", duration, cmd);
+/// }
+/// # Ok::<(), xshell::Error>(())
+/// ```
+///
+/// [`Cmd::run`]: crate::Cmd::run
+/// [`Cmd::read`]: crate::Cmd::read
+/// [`Cmd::spawn`]: crate::Cmd::spawn
+/// [`Cmd::read_lines`]: crate::Cmd::read_lines
+pub fn command_timings() -> Vec<(String, Duration)> {
+    let timings = TIMINGS.lock().unwrap().clone().into_sorted_vec();
+    timings.into_iter().map(|Reverse((duration, cmd))| (cmd, duration)).collect()
+}
+
+/// Runs `f`, recording how long it took.
+pub(crate) fn timed<T>(cmd: &dyn fmt::Display, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = f();
+    took(cmd, start.elapsed());
+    res
+}
+
+/// Reports and records how long `cmd` took, for commands which can't run
+/// in [`timed`], like async ones.
+pub(crate) fn took(cmd: &dyn fmt::Display, duration: Duration) {
+    let slow = SLOW_THRESHOLD.read().unwrap().is_some_and(|it| duration > it);
+    if slow || verbosity() == Verbosity::Verbose {
+        // Quiet commands aren't echoed, so say which one this was.
+        eprintln!("(took {}: $ {})", FmtDuration(duration), cmd);
+    }
+    if RECORD.load(Ordering::Relaxed) {
+        record(cmd, duration);
+    }
+}
+
+fn record(cmd: &dyn fmt::Display, duration: Duration) {
+    let mut timings = TIMINGS.lock().unwrap();
+    // Only render the command if it is going to be kept.
+    if timings.len() >= MAX_TIMINGS {
+        match timings.peek() {
+            Some(Reverse((fastest, _))) if *fastest >= duration => return,
+            _ => timings.pop(),
+        };
+    }
+    timings.push(Reverse((duration, cmd.to_string())));
+}
+
+pub(crate) struct FmtDuration(pub(crate) Duration);
+
+impl fmt::Display for FmtDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.as_secs();
+        match secs {
+            0 => write!(f, "{}ms", self.0.as_millis()),
+            1..=59 => write!(f, "{:.1}s", self.0.as_secs_f64()),
+            60..=3599 => write!(f, "{}m{}s", secs / 60, secs % 60),
+            _ => write!(f, "{}h{}m", secs / 3600, secs % 3600 / 60),
+        }
+    }
+}
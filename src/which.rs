@@ -0,0 +1,127 @@
+use std::{
+    collections::HashSet,
+    env,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+use crate::{error::which_err, Result};
+
+/// Finds `program` in the `PATH`, the way spawning a command does.
+///
+/// On Unix, only executable files are considered. On Windows, the `PATHEXT`
+/// extensions are tried. A program given as a path (like `./x.py`) is
+/// returned as is, if it exists and is executable.
+pub fn which(program: impl AsRef<Path>) -> Result<PathBuf> {
+    _which(program.as_ref(), env::var_os("PATH"))
+}
+pub(crate) fn _which(program: &Path, path: Option<OsString>) -> Result<PathBuf> {
+    match find_program(program, path.as_deref()) {
+        Some(it) => Ok(it),
+        None => Err(which_err(program, path)),
+    }
+}
+
+/// Like [`which`], but returns every match in the `PATH`, in the order they
+/// are searched, to find out which of several installed versions is used.
+///
+/// The same file is only returned once, even if it is reachable through
+/// several directories or symlinks.
+pub fn which_all(program: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    _which_all(program.as_ref(), env::var_os("PATH"))
+}
+pub(crate) fn _which_all(program: &Path, path: Option<OsString>) -> Result<Vec<PathBuf>> {
+    let res = match (is_path(program), &path) {
+        (false, Some(path)) => {
+            let mut seen = HashSet::new();
+            env::split_paths(path)
+                .flat_map(|dir| candidates(&dir, program))
+                .filter(|candidate| is_executable(candidate))
+                .filter(|candidate| {
+                    seen.insert(candidate.canonicalize().unwrap_or_else(|_| candidate.clone()))
+                })
+                .collect()
+        }
+        _ => find_program(program, path.as_deref()).into_iter().collect::<Vec<_>>(),
+    };
+    if res.is_empty() {
+        return Err(which_err(program, path));
+    }
+    Ok(res)
+}
+
+/// The files named `program` in the `PATH` which can't be executed, to
+/// explain why it wasn't found.
+pub(crate) fn not_executable(program: &Path, path: Option<&OsStr>) -> Vec<PathBuf> {
+    let path = match path {
+        Some(it) if !is_path(program) => it,
+        _ => return Vec::new(),
+    };
+    let mut res = Vec::new();
+    for candidate in env::split_paths(path).map(|dir| dir.join(program)) {
+        if candidate.is_file() && !is_executable(&candidate) && !res.contains(&candidate) {
+            res.push(candidate);
+        }
+    }
+    res
+}
+
+/// Looks up a bare program name the way `cmd.exe` does.
+///
+/// `CreateProcess` only appends `.exe`, so without this `npm` (which is
+/// really `npm.cmd`) can't be found. Programs given as paths are left alone,
+/// as are the programs we fail to find, to let the OS report the error.
+#[cfg(windows)]
+pub(crate) fn resolve_program(program: &Path, path: Option<&OsStr>) -> Option<PathBuf> {
+    if is_path(program) {
+        return None;
+    }
+    find_program(program, path)
+}
+
+fn find_program(program: &Path, path: Option<&OsStr>) -> Option<PathBuf> {
+    if is_path(program) {
+        return if is_executable(program) { Some(program.to_path_buf()) } else { None };
+    }
+    env::split_paths(path?)
+        .flat_map(|dir| candidates(&dir, program))
+        .find(|candidate| is_executable(candidate))
+}
+
+fn is_path(program: &Path) -> bool {
+    program.components().count() != 1 || program.to_string_lossy().contains(['/', '\\'])
+}
+
+#[cfg(not(windows))]
+fn candidates(dir: &Path, program: &Path) -> Vec<PathBuf> {
+    vec![dir.join(program)]
+}
+
+#[cfg(windows)]
+fn candidates(dir: &Path, program: &Path) -> Vec<PathBuf> {
+    let pathext = env::var_os("PATHEXT").unwrap_or_else(|| ".COM;.EXE;.BAT;.CMD".into());
+    let mut res = Vec::new();
+    if program.extension().is_some() {
+        res.push(dir.join(program));
+    }
+    for ext in pathext.to_string_lossy().split(';').filter(|it| !it.is_empty()) {
+        let mut name = OsString::from(program);
+        name.push(ext);
+        res.push(dir.join(name));
+    }
+    res
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    match path.metadata() {
+        Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
@@ -1,6 +1,12 @@
-use std::{ffi::OsStr, thread};
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
 
-use xshell::{cmd, cwd, pushd, pushenv, read_file};
+use xshell::{cmd, cwd, pushd, pushenv, read_file, shell_cmd, Cmd, Secret, Shell};
 
 #[test]
 fn smoke() {
@@ -20,6 +26,27 @@ fn multiline() {
     assert_eq!(output, "hello");
 }
 
+#[test]
+fn trailing_newline() {
+    for (output, trimmed) in [
+        ("a\n", "a"),
+        ("a\r\n", "a"),
+        ("a\n\n", "a\n"),
+        ("a\r\n\r\n", "a\r\n"),
+        ("a \n", "a "),
+        ("a\r", "a\r"),
+        ("a", "a"),
+        ("", ""),
+    ] {
+        let cmd = cmd!("printf %s {output}");
+        assert_eq!(cmd.read().unwrap(), trimmed, "{:?}", output);
+        let cmd = cmd.keep_trailing_newline();
+        assert_eq!(cmd.read().unwrap(), output);
+        assert_eq!(cmd!("true").pipe(cmd.clone()).read().unwrap(), output);
+    }
+    assert_eq!(cmd!("printf 'x\r\n'").pipe(cmd!("cat")).read().unwrap(), "x");
+}
+
 #[test]
 fn interpolation() {
     let hello = "hello";
@@ -32,6 +59,143 @@ fn program_interpolation() {
     let echo = "echo";
     let output = cmd!("{echo} hello").read().unwrap();
     assert_eq!(output, "hello");
+
+    let echo = xshell::which("echo").unwrap();
+    let output = cmd!("{echo} hello").read().unwrap();
+    assert_eq!(output, "hello");
+
+    let echo = std::ffi::OsString::from("echo");
+    assert_eq!(cmd!("{echo} hello").read().unwrap(), "hello");
+    let echo = String::from("echo");
+    assert_eq!(cmd!("{echo} hello").read().unwrap(), "hello");
+    let echo = Path::new("echo");
+    assert_eq!(cmd!("{echo} hello").read().unwrap(), "hello");
+}
+
+#[test]
+fn interpolation_display() {
+    let jobs: usize = 8;
+    let verbose = true;
+    let sep = ',';
+    let offset = -1i64;
+    let cmd = cmd!("make -j{jobs} --verbose={verbose} {sep} {offset}");
+    assert_eq!(cmd.to_string(), "make -j8 --verbose=true , -1");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"\xFF"));
+        let cmd = cmd!("ls {path}");
+        assert_eq!(cmd.to_string(), r"ls $'\xff'");
+    }
+}
+
+#[test]
+fn secrets() {
+    let token = Secret("hunter2");
+    let cmd = cmd!("echo --token {token} --token={token}");
+    assert_eq!(cmd.to_string(), "echo --token '***' '--token=***'");
+    assert_eq!(cmd.read().unwrap(), "--token hunter2 --token=hunter2");
+
+    let err = cmd!("false {token}").read().unwrap_err();
+    assert_eq!(err.to_string(), "command `false '***'` failed, exit code: 1");
+
+    let cmd = Cmd::new("echo").secret_arg("hunter2");
+    assert_eq!(cmd.to_string(), "echo '***'");
+    assert_eq!(format!("{:?}", token), "Secret(***)");
+
+    // Only the arguments with the secret in them are masked.
+    let pin = Secret("1");
+    let cmd = cmd!("echo v1.1 --pin={pin}x 1{pin}").env("N", "1");
+    assert_eq!(cmd.to_string(), "N=1 echo v1.1 '--pin=***x' '1***'");
+    #[cfg(unix)]
+    {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let cmd = Cmd::new("echo").arg(OsStr::from_bytes(b"\xff")).secret_arg("hunter2");
+        assert_eq!(cmd.to_string(), "echo $'\\xff' '***'");
+    }
+}
+
+#[test]
+fn builder_args() {
+    let release = true;
+    let features = ["a", "b c"];
+    let cmd = Cmd::new("echo")
+        .arg("build")
+        .arg_if(release, "--release")
+        .args(features.iter().map(|it| format!("--features={}", it)));
+    assert_eq!(cmd.to_string(), "echo build --release --features=a '--features=b c'");
+    assert_eq!(cmd.read().unwrap(), "build --release --features=a --features=b c");
+
+    let cmd = cmd!("echo {release}").args(&["x", "y"]).arg("z");
+    assert_eq!(cmd.read().unwrap(), "true x y z");
+
+    let paths = vec![PathBuf::from("a b"), PathBuf::from("c")];
+    let cmd = Cmd::new("echo").args_owned(paths).args_owned(["d".to_string()]);
+    assert_eq!(cmd.to_string(), "echo 'a b' c d");
+}
+
+#[test]
+fn reuse() {
+    let base = cmd!("cat").env("GREETING", "hello").stdin("input");
+    assert_eq!(base.read().unwrap(), "input");
+    assert_eq!(base.read().unwrap(), "input");
+    let derived = base.clone().arg("-").arg("-");
+    assert_eq!(derived.to_string(), "GREETING=hello cat - -");
+    assert_eq!(base.output().unwrap().stdout, b"input");
+}
+
+#[test]
+fn brace_escapes() {
+    let var = "value";
+    let cmd = cmd!("find . -name '*.o' -exec rm {{}} ;");
+    assert_eq!(cmd.to_string(), "find . -name '*.o' -exec rm '{}' ';'");
+    let output = cmd!("echo prefix{{x}}{var} {{{var}}} '{{literal}}'").read().unwrap();
+    assert_eq!(output, "prefix{x}value {value} {{literal}}");
+}
+
+#[test]
+fn env_assignments() {
+    let flags = "-C debuginfo=0";
+    let cmd = cmd!("RUSTFLAGS={flags} A_1='x y'z _=1 sh -c 'echo $RUSTFLAGS/$A_1/$_' X=1");
+    assert_eq!(
+        cmd.to_string(),
+        "RUSTFLAGS='-C debuginfo=0' A_1='x yz' _=1 sh -c 'echo $RUSTFLAGS/$A_1/$_' X=1"
+    );
+    assert_eq!(cmd.read().unwrap(), "-C debuginfo=0/x yz/1");
+
+    let token = Secret("hunter2");
+    let cmd = cmd!("TOKEN=t{token} printenv TOKEN");
+    assert_eq!(cmd.to_string(), "TOKEN='t***' printenv TOKEN");
+    assert_eq!(cmd.read().unwrap(), "thunter2");
+
+    // Not assignments.
+    let output = cmd!("echo 'A=1' 1A=2 A-B=3 {flags}=4").read().unwrap();
+    assert_eq!(output, "A=1 1A=2 A-B=3 -C debuginfo=0=4");
+    let program = "env";
+    assert!(cmd!("{program} A=1").read().unwrap().lines().any(|it| it == "A=1"));
+}
+
+#[test]
+fn line_continuations() {
+    let name = "x y";
+    let mounts = ["-v", "a:b"];
+    let cmd = cmd!(
+        "A=1 echo run \
+            --name {name} \
+            {mounts...} \
+            'q z'{name}\
+            --rm"
+    );
+    assert_eq!(cmd.to_string(), "A=1 echo run --name 'x y' -v a:b 'q zx y' --rm");
+    assert_eq!(cmd.read().unwrap(), "run --name x y -v a:b q zx y --rm");
+
+    let cmd = cmd!(
+        "echo a\\
+        b \\\n c\\\r\n d\\\\ e"
+    );
+    assert_eq!(cmd.to_string(), r"echo a b c 'd\\' e");
 }
 
 #[test]
@@ -59,6 +223,31 @@ fn interpolation_spat() {
     assert_eq!(output, "hello world !")
 }
 
+#[test]
+fn interpolation_splat_collections() {
+    let files: Vec<PathBuf> = vec!["a b".into(), "c".into()];
+    let paths: &[&Path] = &[Path::new("d")];
+    let empty: &[String] = &[];
+    let cmd = cmd!("echo {empty...} {files...} {paths...} {empty...}");
+    assert_eq!(cmd.to_string(), r#"echo 'a b' c d"#);
+
+    let output = cmd!("printf '<%s>' {empty...}").read().unwrap();
+    assert_eq!(output, "<>");
+}
+
+#[test]
+fn interpolation_option() {
+    let none: Option<&str> = None;
+    let empty = Some("");
+    let output = cmd!("printf '<%s>' x {none...} {empty...}").read().unwrap();
+    assert_eq!(output, "<x><>");
+
+    let target = Some("wasm32");
+    let target_flag = target.map(|it| ["--target", it]).into_iter().flatten();
+    let cmd = cmd!("cargo build {target_flag...}");
+    assert_eq!(cmd.to_string(), "cargo build --target wasm32");
+}
+
 #[test]
 fn exit_status() {
     let err = cmd!("false").read().unwrap_err();
@@ -66,146 +255,2425 @@ fn exit_status() {
 }
 
 #[test]
-fn unknown_command() {
-    let err = cmd!("nope no way").read().unwrap_err();
-    assert_eq!(err.to_string(), "command not found: `nope`");
+fn exit_status_stderr() {
+    let err = cmd!("sh -c 'echo oops >&2; exit 2'").read().unwrap_err();
+    assert_eq!(err.exit_status().unwrap().code(), Some(2));
+    assert_eq!(err.stderr(), Some(&b"oops\n"[..]));
+    assert_eq!(
+        err.to_string(),
+        "command `sh -c 'echo oops >&2; exit 2'` failed, exit code: 2\nstderr:\noops"
+    );
+
+    let script = format!("printf {} >&2; exit 1", "é".repeat(3000));
+    let err = cmd!("sh -c {script}").read().unwrap_err();
+    let msg = err.to_string();
+    assert!(msg.contains("[... 1904 bytes truncated ...]\n"));
+    assert!(msg.ends_with(&"é".repeat(2048)));
 }
 
 #[test]
-fn args_with_spaces() {
-    let hello_world = "hello world";
-    let cmd = cmd!("echo {hello_world} 'hello world' hello world");
-    assert_eq!(cmd.to_string(), r#"echo "hello world" "hello world" hello world"#)
+fn error_accessors() {
+    use std::error::Error;
+
+    let err = cmd!("nope-not-installed").run().unwrap_err();
+    assert!(err.is_not_found());
+    assert_eq!(err.io().unwrap().kind(), std::io::ErrorKind::NotFound);
+    assert!(err.source().is_some());
+    assert_eq!(err.exit_status(), None);
+
+    let err = cmd!("sh -c 'exit 3'").run().unwrap_err();
+    assert!(!err.is_not_found());
+    assert_eq!(err.exit_status().unwrap().code(), Some(3));
+    assert!(err.io().is_none() && err.source().is_none());
+
+    let err = read_file("no-such-file").unwrap_err();
+    assert!(err.is_not_found());
+    assert_eq!(err.path(), Some(Path::new("no-such-file")));
+
+    let err = cmd!("ls").dir("no-such-dir").run().unwrap_err();
+    assert_eq!(err.path(), Some(Path::new("no-such-dir")));
+
+    let err = cmd!("printf '\\377'").read().unwrap_err();
+    assert!(err.source().unwrap().to_string().contains("invalid utf-8"));
+
+    assert!(xshell::which("nope-not-installed").unwrap_err().is_not_found());
 }
 
 #[test]
-fn escape() {
-    let output = cmd!("echo \\hello\\ '\\world\\'").read().unwrap();
-    assert_eq!(output, r#"\hello\ \world\"#)
+fn program_missing() {
+    let err = cmd!("nope-not-installed").env("PATH", "/no/such/dir").run().unwrap_err();
+    assert!(err.is_program_missing() && err.is_not_found());
+    assert_eq!(
+        err.to_string(),
+        "command `PATH=/no/such/dir nope-not-installed` failed, `nope-not-installed` not found, \
+         is it installed and on PATH?
+PATH: /no/such/dir"
+    );
+    let err = cmd!("./nope-not-installed").run().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `./nope-not-installed` failed, `./nope-not-installed` not found"
+    );
+
+    let err = read_file("no-such-file").unwrap_err();
+    assert!(!err.is_program_missing());
+
+    #[cfg(unix)]
+    {
+        let dir = scratch_dir("program_missing");
+        let script = dir.join("script.sh");
+        xshell::write_file(&script, "#!/bin/sh\n").unwrap();
+        let err = Cmd::new(&script).run().unwrap_err();
+        assert!(!err.is_program_missing());
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "command `{0}` failed, can't execute `{0}`, permission denied",
+                script.display()
+            )
+        );
+    }
 }
 
+#[cfg(unix)]
 #[test]
-fn stdin_redirection() {
-    let lines = "\
-foo
-baz
-bar
-";
-    let output = cmd!("sort").stdin(lines).read().unwrap();
+fn terminated_by_signal() {
+    let err = cmd!("sh -c 'kill -KILL $$'").quiet().run().unwrap_err();
+    assert_eq!(err.signal(), Some(9));
     assert_eq!(
-        output,
-        "\
-bar
-baz
-foo"
-    )
+        err.to_string(),
+        "command `sh -c 'kill -KILL $$'` failed, terminated by signal 9 (SIGKILL)"
+    );
+    assert_eq!(cmd!("false").run().unwrap_err().signal(), None);
 }
 
 #[test]
-fn test_pushd() {
-    let d1 = cwd().unwrap();
-    {
-        let _p = pushd("xshell-macros").unwrap();
-        let d2 = cwd().unwrap();
-        assert_eq!(d2, d1.join("xshell-macros"));
-        {
-            let _p = pushd("src").unwrap();
-            let d3 = cwd().unwrap();
-            assert_eq!(d3, d1.join("xshell-macros/src"));
-        }
-        let d4 = cwd().unwrap();
-        assert_eq!(d4, d1.join("xshell-macros"));
+fn run_retains_stderr() {
+    let err = cmd!("sh -c 'echo oops >&2; exit 1'").retain_stderr(1024).run().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `sh -c 'echo oops >&2; exit 1'` failed, exit code: 1\nstderr:\noops"
+    );
+
+    let script = format!("printf {} >&2; exit 1", "x".repeat(10_000));
+    let err = cmd!("sh -c {script}").retain_stderr(100).run().unwrap_err();
+    assert_eq!(err.stderr().unwrap(), "x".repeat(100).as_bytes());
+
+    let err = cmd!("sh -c 'echo oops >&2; exit 1'").retain_stderr(0).run().unwrap_err();
+    assert_eq!(err.stderr(), Some(&b""[..]));
+
+    let err = cmd!("echo hi")
+        .pipe(cmd!("sh -c 'cat >&2; exit 1'").retain_stderr(1024))
+        .run()
+        .unwrap_err();
+    assert_eq!(err.stderr(), Some(&b"hi\n"[..]));
+    let err = cmd!("sh -c 'echo oops >&2; exit 1'")
+        .retain_stderr(1024)
+        .timeout(Duration::from_secs(60))
+        .run()
+        .unwrap_err();
+    assert_eq!(err.stderr(), Some(&b"oops\n"[..]));
+
+    // A process left running in the background still holds stderr.
+    let dir = scratch_dir("run_retains_stderr");
+    let start = Instant::now();
+    let err = cmd!("sh -c 'echo oops >&2; sleep 300 & echo $! > pid; exit 1'")
+        .dir(&dir)
+        .retain_stderr(1024)
+        .run()
+        .unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(err.stderr(), Some(&b"oops\n"[..]));
+    let pid = xshell::read_file(dir.join("pid")).unwrap();
+    let pid = pid.trim();
+    cmd!("kill {pid}").quiet().run().unwrap();
+}
+
+#[test]
+fn retain_stderr_slow_sink() {
+    // Our stderr is a pipe which is only read after a while, so forwarding
+    // the stderr of the command to it lags behind.
+    let exe = std::env::current_exe().unwrap();
+    let script = "\"$1\" --exact retain_stderr_slow_sink_child --nocapture --test-threads 1 \
+                  2>&1 | (sleep 1; cat)";
+    let output = Cmd::shell(script)
+        .arg(exe)
+        .env("RETAIN_STDERR_SLOW_SINK_CHILD", "1")
+        .quiet()
+        .read()
+        .unwrap();
+    assert!(output.contains("retained: tail\n"), "{}", &output[output.len().saturating_sub(500)..]);
+}
+
+#[test]
+fn retain_stderr_slow_sink_child() {
+    if std::env::var_os("RETAIN_STDERR_SLOW_SINK_CHILD").is_none() {
+        return;
     }
-    let d5 = cwd().unwrap();
-    assert_eq!(d5, d1);
+    // A process left running in the background holds stderr, while the
+    // command writes a lot to it just before exiting.
+    let dir = scratch_dir("retain_stderr_slow_sink");
+    let script = "sleep 300 & echo $! > pid; yes | head -c 100000 >&2; echo tail >&2; exit 1";
+    let err = cmd!("sh -c {script}").dir(&dir).quiet().retain_stderr(5).run().unwrap_err();
+    let pid = xshell::read_file(dir.join("pid")).unwrap();
+    let pid = pid.trim();
+    cmd!("kill {pid}").quiet().run().unwrap();
+    println!("retained: {}", String::from_utf8_lossy(err.stderr().unwrap()));
 }
 
 #[test]
-fn pushd_parent_dir() {
-    let current = cwd().unwrap();
-    let dirname = current.file_name().unwrap();
-    let _d = pushd("..").unwrap();
-    let _d = pushd(dirname).unwrap();
-    assert_eq!(cwd().unwrap(), current);
+fn error_cmd() {
+    let token = Secret("hunter2");
+    let err = cmd!("sh -c 'exit 3' {token}").run().unwrap_err();
+    assert_eq!(err.cmd(), Some("sh -c 'exit 3' '***'"));
+    assert!(err.to_string().starts_with("command `sh -c 'exit 3' '***'` failed"));
+
+    let files = (0..100).map(|it| format!("f{}", it));
+    let err = cmd!("sh -c 'exit 1' sh {files...}").run().unwrap_err();
+    let expected =
+        "sh -c 'exit 1' sh f0 f1 f2 f3 f4 f5 f6 [... 89 more arguments ...] f96 f97 f98 f99";
+    assert_eq!(err.cmd(), Some(expected));
+    assert_eq!(err.to_string(), format!("command `{}` failed, exit code: 1", expected));
+
+    assert_eq!(read_file("no-such-file").unwrap_err().cmd(), None);
 }
 
 #[test]
-fn test_pushd_lock() {
-    let t1 = thread::spawn(|| {
-        let _p = pushd("cbench").unwrap();
-        sleep_ms(20);
-    });
-    sleep_ms(10);
+fn unknown_command() {
+    let err = cmd!("nope no way").read().unwrap_err();
+    assert!(err.to_string().starts_with(
+        "command `nope no way` failed, `nope` not found, is it installed and on PATH?"
+    ));
+}
 
-    let t2 = thread::spawn(|| {
-        let _p = pushd("cbench").unwrap();
-        sleep_ms(30);
-    });
+#[test]
+fn output() {
+    let output = cmd!("sh -c 'echo out; echo err >&2'").output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.stderr, b"err\n");
+}
 
-    t1.join().unwrap();
-    t2.join().unwrap();
+#[test]
+fn output_limit() {
+    let err = cmd!("yes").output_limit(1000).ignore_status().read().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `yes` was killed, its output exceeded the limit of 1000 bytes"
+    );
+    assert_eq!(err.partial_stdout().unwrap(), "y\n".repeat(500).as_bytes());
+
+    let output = cmd!("sh -c 'yes | head -c 100000'").output_limit(1000).no_output_limit();
+    assert_eq!(output.read().unwrap().len(), 100000 - 1);
+    let output = cmd!("sh -c 'yes | head -c 1000'").output_limit(1000).read().unwrap();
+    assert_eq!(output.len(), 1000 - 1);
+
+    let mut lines = cmd!("sh -c 'yes >&2'").output_limit(1000).read_lines().unwrap();
+    let err = lines.next().unwrap().unwrap_err();
+    assert!(err.to_string().contains("exceeded the limit of 1000 bytes"), "{}", err);
+    assert_eq!(err.stderr().unwrap(), "y\n".repeat(500).as_bytes());
+    assert!(lines.next().is_none());
 }
 
-const VAR: &str = "SPICA";
+#[test]
+fn tee() {
+    let script = "echo out; echo err >&2; echo more";
+    let output = cmd!("sh -c {script}").tee(1024).output().unwrap();
+    assert_eq!(output.stdout, b"out\nmore\n");
+    assert_eq!(output.stderr, b"err\n");
+
+    let output = cmd!("sh -c {script}").tee(3).read().unwrap();
+    assert_eq!(output, "re");
+
+    let err = cmd!("sh -c 'echo oops >&2; exit 1'").tee(1024).read().unwrap_err();
+    assert_eq!(err.stderr(), Some(&b"oops\n"[..]));
+}
 
 #[test]
-fn test_pushenv() {
-    let e1 = std::env::var_os(VAR);
-    {
-        let _e = pushenv(VAR, "1");
-        let e2 = std::env::var_os(VAR);
-        assert_eq!(e2, Some("1".into()));
-        {
-            let _e = pushenv(VAR, "2");
-            let e3 = std::env::var_os(VAR);
-            assert_eq!(e3, Some("2".into()));
+fn read_bytes() {
+    let output = cmd!("printf '\\377hello\\n'").read_bytes().unwrap();
+    assert_eq!(output, b"\xffhello\n");
+
+    let err = cmd!("printf '\\377hello\\n'").read().unwrap_err();
+    assert!(err.to_string().starts_with("command `printf '\\377hello\\n'` produced invalid utf8"));
+
+    let output = cmd!("sh -c 'printf \"\\377\" >&2'").read_stderr_bytes().unwrap();
+    assert_eq!(output, b"\xff");
+}
+
+#[test]
+fn read_lines() {
+    let lines = cmd!("printf 'foo\\nbar\\r\\nbaz'").read_lines().unwrap();
+    let lines = lines.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(lines, ["foo", "bar", "baz"]);
+
+    let mut lines = cmd!("sh -c 'echo hello; echo oops >&2; exit 1'").read_lines().unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "hello");
+    let err = lines.next().unwrap().unwrap_err();
+    assert_eq!(err.exit_status().unwrap().code(), Some(1));
+    assert_eq!(err.stderr(), Some(&b"oops\n"[..]));
+    assert!(lines.next().is_none());
+
+    let start = Instant::now();
+    let mut lines = cmd!("yes").read_lines().unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "y");
+    drop(lines);
+
+    let mut lines = cmd!("sh -c 'echo hello; sleep 10'")
+        .timeout(Duration::from_millis(200))
+        .read_lines()
+        .unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "hello");
+    assert!(lines.next().unwrap().unwrap_err().to_string().contains("timed out"));
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // The incomplete last line comes before the timeout.
+    let mut lines = cmd!("sh -c 'echo hello; printf wor; sleep 10'")
+        .timeout(Duration::from_millis(200))
+        .read_lines()
+        .unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "hello");
+    assert_eq!(lines.next().unwrap().unwrap(), "wor");
+    assert!(lines.next().unwrap().unwrap_err().to_string().contains("timed out"));
+    assert!(lines.next().is_none());
+}
+
+#[test]
+fn spawn() {
+    let mut child = cmd!("sleep 10").spawn().unwrap();
+    assert!(child.id() > 0);
+    assert!(child.try_wait().unwrap().is_none());
+    child.kill().unwrap();
+    let err = child.wait().unwrap_err();
+    assert!(err.to_string().starts_with("command `sleep 10` failed"));
+
+    let child = cmd!("sh -c 'exit 3'").ignore_status().spawn().unwrap();
+    let status = thread::spawn(move || child.wait().unwrap()).join().unwrap();
+    assert_eq!(status.code(), Some(3));
+
+    let err = cmd!("nope no way").spawn().unwrap_err();
+    assert!(err.to_string().starts_with(
+        "command `nope no way` failed, `nope` not found, is it installed and on PATH?"
+    ));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn kill_process_group() {
+    // In a separate process without a terminal, as in the foreground of one
+    // commands don't get their own process group.
+    let exe = std::env::current_exe().unwrap();
+    cmd!("{exe} --exact kill_process_group_child --nocapture --test-threads 1")
+        .env("KILL_PROCESS_GROUP_CHILD", "1")
+        .stdin("")
+        .quiet()
+        .read()
+        .unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn kill_process_group_child() {
+    if std::env::var_os("KILL_PROCESS_GROUP_CHILD").is_none() {
+        return;
+    }
+    let dir = scratch_dir("kill_process_group");
+    let pid_file = dir.join("pid");
+    let script = "sleep 300 & echo $! > pid; wait";
+
+    let mut child = cmd!("sh -c {script}").dir(&dir).quiet().spawn().unwrap();
+    let pid = wait_for_pid(&pid_file);
+    assert!(alive(&pid));
+    child.kill().unwrap();
+    let _ = child.wait();
+    let start = Instant::now();
+    while alive(&pid) {
+        assert!(start.elapsed() < Duration::from_secs(5), "`sleep` survived");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Dropping `read_lines` early kills the whole group too.
+    std::fs::remove_file(&pid_file).unwrap();
+    let script_with_output = "sleep 300 & echo $! > pid; echo started; wait";
+    let mut lines = cmd!("sh -c {script_with_output}").dir(&dir).read_lines().unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "started");
+    let pid = wait_for_pid(&pid_file);
+    drop(lines);
+    let start = Instant::now();
+    while alive(&pid) {
+        assert!(start.elapsed() < Duration::from_secs(5), "`sleep` survived");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    std::fs::remove_file(&pid_file).unwrap();
+    let mut child = cmd!("sh -c {script}").dir(&dir).quiet().process_group(false).spawn().unwrap();
+    let pid = wait_for_pid(&pid_file);
+    child.kill().unwrap();
+    let _ = child.wait();
+    assert!(alive(&pid));
+    cmd!("kill {pid}").quiet().run().unwrap();
+}
+
+#[test]
+fn spawn_kill_on_drop() {
+    let child = cmd!("sleep 600").quiet().spawn().unwrap();
+    let pid = child.id().to_string();
+    assert!(cmd!("kill -0 {pid}").quiet().run().is_ok());
+    drop(child);
+    assert!(cmd!("kill -0 {pid}").quiet().run().is_err());
+
+    let child = cmd!("sleep 600").quiet().spawn().unwrap();
+    let pid = child.id().to_string();
+    child.detach();
+    assert!(cmd!("kill -0 {pid}").quiet().run().is_ok());
+    cmd!("kill {pid}").quiet().run().unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn interrupt_pipeline() {
+    let dir = scratch_dir("interrupt_pipeline");
+    let exe = std::env::current_exe().unwrap();
+    // No terminal, so that the commands get their own process groups.
+    let child = cmd!("{exe} --exact interrupt_pipeline_child --nocapture --test-threads 1")
+        .env("INTERRUPT_PIPELINE_CHILD", &dir)
+        .stdin("")
+        .stdout_file(dir.join("stdout"))
+        .stderr_file(dir.join("stderr"))
+        .quiet()
+        .ignore_status()
+        .spawn()
+        .unwrap();
+    let pid = wait_for_pid(&dir.join("pid"));
+    // Like Ctrl-C, or a CI runner cancelling the job.
+    let test_pid = child.id().to_string();
+    cmd!("kill -INT {test_pid}").quiet().run().unwrap();
+    let status = child.wait().unwrap();
+    assert_eq!(std::os::unix::process::ExitStatusExt::signal(&status), Some(2));
+    let start = Instant::now();
+    while alive(&pid) {
+        if start.elapsed() > Duration::from_secs(5) {
+            cmd!("kill {pid}").quiet().run().unwrap();
+            panic!("`sleep` survived");
         }
-        let e4 = std::env::var_os(VAR);
-        assert_eq!(e4, e2);
+        thread::sleep(Duration::from_millis(10));
     }
-    let e5 = std::env::var_os(VAR);
-    assert_eq!(e5, e1);
 }
 
+#[cfg(target_os = "linux")]
 #[test]
-fn test_pushenv_lock() {
-    let t1 = thread::spawn(|| {
-        let _e = pushenv(VAR, "hello");
-        sleep_ms(20);
-    });
-    sleep_ms(10);
+fn interrupt_pipeline_child() {
+    let dir = match std::env::var_os("INTERRUPT_PIPELINE_CHILD") {
+        Some(it) => PathBuf::from(it),
+        None => return,
+    };
+    // Not `sleep 300 &`, the shell makes background jobs ignore SIGINT.
+    let script = "echo $$ > pid; exec sleep 300";
+    let timeout = Duration::from_secs(60);
+    let _ =
+        cmd!("sh -c {script}").dir(&dir).timeout(timeout).pipe(cmd!("cat").timeout(timeout)).run();
+    unreachable!("the pipeline should have been interrupted");
+}
 
-    let t2 = thread::spawn(|| {
-        let _e = pushenv(VAR, "world");
-        sleep_ms(30);
+#[test]
+fn heartbeat() {
+    let beats = Arc::new(Mutex::new(Vec::new()));
+    let cmd = cmd!("sh -c 'sleep 0.35; echo done'").heartbeat_with(Duration::from_millis(100), {
+        let beats = beats.clone();
+        move |elapsed| beats.lock().unwrap().push(elapsed)
     });
+    assert_eq!(cmd.read().unwrap(), "done");
+    let count = beats.lock().unwrap().len();
+    assert!(count >= 2, "{:?}", beats);
+    assert!(beats.lock().unwrap().windows(2).all(|it| it[0] < it[1]));
+    sleep_ms(250);
+    assert_eq!(beats.lock().unwrap().len(), count);
 
-    t1.join().unwrap();
-    t2.join().unwrap();
+    beats.lock().unwrap().clear();
+    cmd.run().unwrap();
+    assert!(beats.lock().unwrap()[0] >= Duration::from_millis(100));
+
+    beats.lock().unwrap().clear();
+    cmd!("true").heartbeat_with(Duration::from_secs(1), |_| panic!("too soon")).run().unwrap();
+    cmd!("true").pipe(cmd.clone()).run().unwrap();
+    assert!(!beats.lock().unwrap().is_empty());
 }
 
 #[test]
-fn versions_match() {
-    let _lock_cwd = pushd(".");
-    let read_version = |path: &str| {
-        let text = read_file(path).unwrap();
-        text.lines().find(|it| it.starts_with("version =")).unwrap().trim().to_string()
-    };
+fn timeout() {
+    let start = Instant::now();
+    let err = cmd!("sleep 10").timeout(Duration::from_millis(100)).run().unwrap_err();
+    assert_eq!(err.to_string(), "command `sleep 10` timed out after 100ms");
+    assert!(start.elapsed() < Duration::from_secs(5));
 
-    let v1 = read_version("./Cargo.toml");
-    let v2 = read_version("./xshell-macros/Cargo.toml");
-    assert_eq!(v1, v2);
+    let start = Instant::now();
+    let err = cmd!("sh -c 'echo hello; sleep 10'")
+        .timeout(Duration::from_millis(200))
+        .read()
+        .unwrap_err();
+    assert!(err.to_string().contains("timed out"));
+    assert!(start.elapsed() < Duration::from_secs(5));
 
-    let cargo_toml = read_file("./Cargo.toml").unwrap();
-    let dep = format!("xshell-macros = {{ {}", v1);
-    assert!(cargo_toml.contains(&dep));
+    let err = cmd!("sh -c 'echo started; echo stuck >&2; sleep 10'")
+        .timeout(Duration::from_millis(200))
+        .output()
+        .unwrap_err();
+    assert_eq!(err.partial_stdout(), Some(&b"started\n"[..]));
+    assert_eq!(err.stderr(), Some(&b"stuck\n"[..]));
+    assert_eq!(
+        err.to_string(),
+        "command `sh -c 'echo started; echo stuck >&2; sleep 10'` timed out after 200ms\n\
+         stdout:\nstarted\nstderr:\nstuck"
+    );
+    let err = cmd!("sh -c 'echo started; sleep 10'")
+        .timeout(Duration::from_millis(200))
+        .tee(1024)
+        .quiet()
+        .read()
+        .unwrap_err();
+    assert_eq!(err.partial_stdout(), Some(&b"started\n"[..]));
+
+    let output = cmd!("echo hello").timeout(Duration::from_secs(10)).read().unwrap();
+    assert_eq!(output, "hello");
+    let err = cmd!("false").timeout(Duration::from_secs(10)).run().unwrap_err();
+    assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
 }
 
 #[test]
-fn formatting() {
-    cmd!("cargo fmt --all -- --check").run().unwrap()
+fn cmd_dir() {
+    let output = cmd!("ls").dir("xshell-macros").read().unwrap();
+    assert!(output.lines().any(|it| it == "src"));
+
+    let cmd = cmd!("git describe").dir("my repo").env("A", "1");
+    assert_eq!(cmd.to_string(), r#"(cd 'my repo'; A=1 git describe)"#);
+
+    let mut sh = Shell::new().unwrap();
+    sh.change_dir("xshell-macros");
+    let output = cmd!(sh, "ls").dir("src").read().unwrap();
+    assert_eq!(output, "lib.rs");
+
+    let err = cmd!("ls").dir("no-such-dir").read().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `(cd no-such-dir; ls)` failed, `no-such-dir`: no such file or directory (os error 2)"
+    );
 }
 
-fn sleep_ms(ms: u64) {
-    thread::sleep(std::time::Duration::from_millis(ms))
+#[test]
+fn cmd_env() {
+    let cmd = cmd!("printenv CMD_ENV_VAR").env("CMD_ENV_VAR", "hello").env("OTHER", "a b");
+    assert_eq!(cmd.to_string(), r#"CMD_ENV_VAR=hello OTHER='a b' printenv CMD_ENV_VAR"#);
+    assert_eq!(cmd.read().unwrap(), "hello");
+    assert_eq!(std::env::var_os("CMD_ENV_VAR"), None);
+
+    let output = cmd!("printenv CMD_ENV_VAR")
+        .env("CMD_ENV_VAR", "hello")
+        .env("CMD_ENV_VAR", "world")
+        .read()
+        .unwrap();
+    assert_eq!(output, "world");
+}
+
+#[test]
+fn cmd_env_clear() {
+    let _e = pushenv("CMD_ENV_REMOVE", "1");
+    let err = cmd!("printenv CMD_ENV_REMOVE").env_remove("CMD_ENV_REMOVE").read().unwrap_err();
+    assert_eq!(err.to_string(), "command `printenv CMD_ENV_REMOVE` failed, exit code: 1");
+
+    let printenv = cmd!("which printenv").read().unwrap();
+    let output = Cmd::new(printenv).env("A", "1").env_clear().env("B", "2").read().unwrap();
+    assert_eq!(output, "B=2");
+}
+
+#[test]
+fn into_command() {
+    let mut sh = Shell::new().unwrap();
+    sh.change_dir("xshell-macros");
+    sh.set_var("INTO_COMMAND_SHELL", "1");
+    let cmd = cmd!(sh, "sh -c 'echo $INTO_COMMAND_SHELL $INTO_COMMAND; ls'")
+        .dir("src")
+        .env("INTO_COMMAND", "2");
+    let expected = cmd.clone().read().unwrap();
+
+    let output = cmd.into_command().output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim_end(), expected);
+    assert_eq!(expected, "1 2\nlib.rs");
+}
+
+#[test]
+fn retry() {
+    let dir = scratch_dir("retry");
+    // Fails twice, then succeeds.
+    let script = "echo x >> count; [ $(wc -l < count) -ge 3 ]";
+    let cmd = cmd!("sh -c {script}").dir(&dir).retry_delay(Duration::from_millis(1));
+    cmd.clone().retry(3).run().unwrap();
+    assert_eq!(read_file(dir.join("count")).unwrap(), "x\nx\nx\n");
+
+    xshell::rm_rf(dir.join("count")).unwrap();
+    let err = cmd.clone().retry(2).run().unwrap_err();
+    assert!(err.to_string().starts_with("command `"), "{}", err);
+    assert!(err.to_string().ends_with("` failed after 2 attempts, exit code: 1"), "{}", err);
+
+    let start = Instant::now();
+    let err =
+        cmd!("nope-not-installed").retry(5).retry_delay(Duration::from_secs(10)).run().unwrap_err();
+    assert!(err.is_not_found());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn command_timings() {
+    xshell::record_command_timings(true);
+    cmd!("sleep 0.05").run().unwrap();
+    let _ = cmd!("sh -c 'sleep 0.02; exit 1'").read();
+    let timings = xshell::command_timings();
+    let find = |name: &str| timings.iter().find(|(cmd, _)| cmd == name).unwrap().1;
+    assert!(find("sleep 0.05") >= Duration::from_millis(50));
+    assert!(find("sh -c 'sleep 0.02; exit 1'") >= Duration::from_millis(20));
+    assert!(timings.windows(2).all(|w| w[0].1 >= w[1].1));
+}
+
+#[test]
+fn ignore_status() {
+    cmd!("false").ignore_status().run().unwrap();
+
+    let output = cmd!("sh -c 'echo hello; exit 3'").ignore_status().output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"hello\n");
+
+    let output = cmd!("true").pipe(cmd!("false").ignore_status()).read().unwrap();
+    assert_eq!(output, "");
+
+    let err = cmd!("nope no way").ignore_status().run().unwrap_err();
+    assert!(err.to_string().starts_with(
+        "command `nope no way` failed, `nope` not found, is it installed and on PATH?"
+    ));
+}
+
+#[test]
+fn quiet() {
+    cmd!("true").quiet().run().unwrap();
+    let err = cmd!("false").quiet().run().unwrap_err();
+    assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
+
+    let mut sh = Shell::new().unwrap();
+    sh.set_quiet(true);
+    cmd!(sh, "true").run().unwrap();
+    cmd!(sh, "true").echo_cmd(true).run().unwrap();
+}
+
+#[test]
+fn echo_hook() {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    xshell::set_echo_hook({
+        let lines = Arc::clone(&lines);
+        move |line| lines.lock().unwrap().push(line.to_string())
+    });
+    cmd!("echo echo-hook").run().unwrap();
+    cmd!("echo echo-hook-quiet").quiet().run().unwrap();
+    let retried = cmd!("sh -c 'exit 1' echo-hook-retry").retry(2).retry_delay(Duration::ZERO);
+    assert!(retried.clone().run().is_err());
+    assert!(retried.quiet().run().is_err());
+    xshell::remove_echo_hook();
+
+    let lines = lines.lock().unwrap();
+    assert!(lines.iter().any(|it| it == "echo echo-hook"));
+    assert!(!lines.iter().any(|it| it.contains("echo-hook-quiet")));
+    let retries = lines.iter().filter(|it| it.contains("echo-hook-retry")).collect::<Vec<_>>();
+    assert_eq!(
+        retries,
+        [
+            "sh -c 'exit 1' echo-hook-retry",
+            "sh -c 'exit 1' echo-hook-retry  # retrying (attempt 2/2)"
+        ]
+    );
+}
+
+#[test]
+fn args_with_spaces() {
+    let hello_world = "hello world";
+    let cmd = cmd!("echo {hello_world} 'hello world' hello world");
+    assert_eq!(cmd.to_string(), r#"echo 'hello world' 'hello world' hello world"#)
+}
+
+#[test]
+fn display_quoting() {
+    let arg = "a b";
+    assert_eq!(cmd!("printf {arg}").to_string(), "printf 'a b'");
+
+    let args = ["", "it's", "$HOME", "a\nb", "--flag=x.rs", "*"];
+    let cmd = cmd!("echo {args...}");
+    assert_eq!(cmd.to_string(), "echo '' 'it'\\''s' '$HOME' 'a\nb' --flag=x.rs '*'");
+    let quotes = ["'", "''x'", "héllo"];
+    assert_eq!(cmd!("echo {quotes...}").to_string(), r"echo ''\''' ''\'''\''x'\''' 'héllo'");
+
+    // What the shell sees is what we've shown.
+    let script =
+        format!("printf '%s|' {}", cmd!("echo {args...}").to_string().trim_start_matches("echo "));
+    let output = cmd!("sh -c {script}").read().unwrap();
+    assert_eq!(output, "|it's|$HOME|a\nb|--flag=x.rs|*|");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let arg = OsStr::from_bytes(b"a'\xFFb");
+        assert_eq!(cmd!("echo {arg}").to_string(), r"echo $'a\'\xffb'");
+    }
+}
+
+#[test]
+fn escape() {
+    let output = cmd!("echo \\hello\\ '\\world\\'").read().unwrap();
+    assert_eq!(output, r#"\hello\ \world\"#)
+}
+
+#[test]
+fn stdin_redirection() {
+    let lines = "\
+foo
+baz
+bar
+";
+    let output = cmd!("sort").stdin(lines).read().unwrap();
+    assert_eq!(
+        output,
+        "\
+bar
+baz
+foo"
+    )
+}
+
+#[test]
+fn stdin_file() {
+    let dir = scratch_dir("stdin_file");
+    xshell::write_file(dir.join("input"), "b\na\n").unwrap();
+
+    let output = cmd!("sort").stdin_file(dir.join("input")).read().unwrap();
+    assert_eq!(output, "a\nb");
+    let output = cmd!("sort").dir(&dir).stdin_file("input").read().unwrap();
+    assert_eq!(output, "a\nb");
+    let output = cmd!("sort").stdin_file(dir.join("input")).pipe(cmd!("head -n 1")).read().unwrap();
+    assert_eq!(output, "a");
+
+    assert_eq!(cmd!("sort").stdin_file("my input").to_string(), "sort < 'my input'");
+
+    let path = dir.join("missing");
+    let err = cmd!("sort").stdin_file(&path).read().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "command `sort < {0}` failed, `{0}`: no such file or directory (os error 2)",
+            path.display()
+        )
+    );
+}
+
+#[test]
+fn run_interactive() {
+    cmd!("true").quiet().run_interactive().unwrap();
+    let err = cmd!("sh -c 'exit 3'").quiet().run_interactive().unwrap_err();
+    assert_eq!(err.to_string(), "command `sh -c 'exit 3'` failed, exit code: 3");
+    cmd!("sh -c 'exit 3'").quiet().ignore_status().run_interactive().unwrap();
+}
+
+#[test]
+fn null_stdout_stderr() {
+    let cmd = cmd!("sh -c 'echo out; echo err >&2; exit 1'").ignore_status();
+    let output = cmd.clone().null_stderr().output().unwrap();
+    assert_eq!(output.stdout, b"out\n");
+    assert!(output.stderr.is_empty());
+    assert_eq!(cmd.clone().null_stdout().null_stderr().to_string(), cmd.to_string());
+
+    let err = cmd.clone().null_stdout().read().unwrap_err();
+    assert!(err.to_string().ends_with("it is discarded"), "{}", err);
+    let err = cmd!("git rev-parse --verify no-such-ref").null_stdout().null_stderr().run();
+    assert!(err.unwrap_err().exit_status().is_some());
+
+    cmd.clone().null_stdout().null_stderr().quiet().run().unwrap();
+    let output = cmd.null_stdout().stdout_file("/dev/null").null_stderr().read_stderr_bytes();
+    assert!(output.unwrap_err().to_string().ends_with("it is redirected to a file"));
+}
+
+#[test]
+fn merge_stderr() {
+    let dir = scratch_dir("merge_stderr");
+    let cmd = cmd!("sh -c 'echo a; echo b >&2; echo c >&2; echo d'").merge_stderr();
+    assert_eq!(cmd.clone().read().unwrap(), "a\nb\nc\nd");
+    let output = cmd.clone().output().unwrap();
+    assert_eq!(output.stdout, b"a\nb\nc\nd\n");
+    assert!(output.stderr.is_empty());
+
+    let lines = cmd.clone().read_lines().unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(lines, ["a", "b", "c", "d"]);
+    let output = cmd.clone().pipe(cmd!("tr a-d A-D")).read().unwrap();
+    assert_eq!(output, "A\nB\nC\nD");
+
+    cmd.clone().dir(&dir).stdout_file("out.log").run().unwrap();
+    assert_eq!(read_file(dir.join("out.log")).unwrap(), "a\nb\nc\nd\n");
+    let err = cmd.clone().output_limit(4).read().unwrap_err();
+    assert!(err.to_string().contains("exceeded the limit of 4 bytes"), "{}", err);
+}
+
+#[test]
+fn via_shell() {
+    let dir = scratch_dir("via_shell");
+    cmd!("cd /").via_shell().run().unwrap();
+    let output = cmd!("type cd").via_shell().read().unwrap();
+    assert!(output.contains("builtin"), "{}", output);
+
+    let arg = "a  b; echo $HOME `id` *";
+    let output = cmd!("printf %s {arg}").via_shell().dir(&dir).read().unwrap();
+    assert_eq!(output, arg);
+    let err = cmd!("exit 3").via_shell().to_string();
+    assert_eq!(err, "exit 3");
+    let err = cmd!("exit 3").via_shell().run().unwrap_err();
+    assert_eq!(err.exit_status().unwrap().code(), Some(3));
+}
+
+#[test]
+fn shell_script() {
+    let dir = scratch_dir("shell_script");
+    for name in ["a.txt", "b.txt", "c.md"] {
+        xshell::write_file(dir.join(name), name).unwrap();
+    }
+    let output = Cmd::shell("for f in \"$1\"/*.txt; do basename \"$f\"; done").arg(&dir).read();
+    assert_eq!(output.unwrap(), "a.txt\nb.txt");
+
+    let evil = "x; echo pwned";
+    let cmd = shell_cmd!("echo {evil} \"[{evil}]\" '{evil}' ${{HOME:+home}}");
+    assert_eq!(
+        cmd.to_string(),
+        "set -- 'x; echo pwned' 'x; echo pwned'; echo \"$1\" \"[$2]\" '{evil}' ${HOME:+home}"
+    );
+    assert_eq!(cmd.read().unwrap(), "x; echo pwned [x; echo pwned] {evil} home");
+
+    let args = ["1", "2", "3", "4", "5", "6", "7", "8", "9", "10"];
+    let [a1, a2, a3, a4, a5, a6, a7, a8, a9, a10] = args;
+    let output = shell_cmd!("echo {a1}{a2}{a3}{a4}{a5}{a6}{a7}{a8}{a9}{a10} $#").read().unwrap();
+    assert_eq!(output, "12345678910 10");
+
+    let err = Cmd::shell_with("bash", "exit $1").arg("4").run().unwrap_err();
+    assert_eq!(err.exit_status().unwrap().code(), Some(4));
+}
+
+#[test]
+fn stdout_stderr_file() {
+    let dir = scratch_dir("stdout_stderr_file");
+    let script = "echo out; echo err >&2";
+    let cmd = cmd!("sh -c {script}").dir(&dir);
+    cmd.clone().stdout_file("out.log").stderr_file("err.log").run().unwrap();
+    assert_eq!(read_file(dir.join("out.log")).unwrap(), "out\n");
+    assert_eq!(read_file(dir.join("err.log")).unwrap(), "err\n");
+
+    cmd.clone().stdout_file_append("out.log").stderr_file("err.log").run().unwrap();
+    assert_eq!(read_file(dir.join("out.log")).unwrap(), "out\nout\n");
+    assert_eq!(read_file(dir.join("err.log")).unwrap(), "err\n");
+
+    let output = cmd.clone().stderr_file("err.log").read().unwrap();
+    assert_eq!(output, "out");
+    cmd.clone().stdout_file("out.log").spawn().unwrap().wait().unwrap();
+    assert_eq!(read_file(dir.join("out.log")).unwrap(), "out\n");
+
+    assert_eq!(
+        cmd!("make").stdout_file("a b").stderr_file_append("c").to_string(),
+        "make > 'a b' 2>> c"
+    );
+
+    let err = cmd!("echo hi").stdout_file(dir.join("out.log")).read().unwrap_err();
+    assert!(err.to_string().contains("it is redirected to a file"), "{}", err);
+    let err = cmd!("echo hi").stdout_file(dir.join("no/such/dir")).run().unwrap_err();
+    assert!(err.to_string().contains("no/such/dir"), "{}", err);
+}
+
+#[test]
+fn stdin_empty() {
+    let output = cmd!("cat").stdin("").read().unwrap();
+    assert_eq!(output, "");
+}
+
+#[test]
+fn stdin_large() {
+    let input = "x".repeat(8 * 1024 * 1024);
+    let output = cmd!("cat").stdin(&input).read().unwrap();
+    assert_eq!(output.len(), input.len());
+}
+
+#[test]
+fn stdin_not_consumed() {
+    let input = "x".repeat(8 * 1024 * 1024);
+    cmd!("true").stdin(&input).read().unwrap();
+    let err = cmd!("false").stdin(&input).run().unwrap_err();
+    assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
+}
+
+#[test]
+fn pipeline() {
+    let lines = "foo\nbar\nbaz\n";
+    let output = cmd!("sort").stdin(lines).pipe(cmd!("head -n 2")).pipe(cmd!("tail -n 1")).read();
+    assert_eq!(output.unwrap(), "baz");
+}
+
+#[test]
+fn pipeline_display() {
+    let pipeline = cmd!("cat big.log").pipe(cmd!("grep ERROR"));
+    assert_eq!(pipeline.to_string(), "cat big.log | grep ERROR");
+}
+
+#[test]
+fn pipeline_streaming() {
+    let err = cmd!("yes").pipe(cmd!("head -n 3")).read().unwrap_err();
+    assert!(err.to_string().starts_with("command `yes` failed"));
+
+    let output = cmd!("seq 1000000").pipe(cmd!("tail -n 1")).read().unwrap();
+    assert_eq!(output, "1000000");
+}
+
+#[test]
+fn pipeline_failure() {
+    let err = cmd!("echo hello").pipe(cmd!("false")).pipe(cmd!("cat")).read().unwrap_err();
+    assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
+
+    let err = cmd!("echo hello").pipe(cmd!("nope no way")).run().unwrap_err();
+    assert!(err.to_string().starts_with(
+        "command `nope no way` failed, `nope` not found, is it installed and on PATH?"
+    ));
+}
+
+#[test]
+fn pipeline_stderr() {
+    let exe = std::env::current_exe().unwrap();
+    let output = cmd!("{exe} --exact pipeline_stderr_child --nocapture --test-threads 1")
+        .env("PIPELINE_STDERR_CHILD", "1")
+        .quiet()
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("oops from the middle\n"), "{}", stderr);
+}
+
+#[test]
+fn pipeline_stderr_child() {
+    if std::env::var_os("PIPELINE_STDERR_CHILD").is_none() {
+        return;
+    }
+    let output = cmd!("echo hello")
+        .pipe(cmd!("sh -c 'echo oops from the middle >&2; cat'"))
+        .pipe(cmd!("cat"))
+        .read()
+        .unwrap();
+    assert_eq!(output, "hello");
+}
+
+#[test]
+fn which() {
+    let sh = cmd!("which sh").read().unwrap();
+    assert_eq!(xshell::which("sh").unwrap(), PathBuf::from(sh));
+
+    let _e = pushenv("PATH", "/no/such/dir");
+    let err = xshell::which("sh").unwrap_err();
+    assert_eq!(err.to_string(), "program `sh` not found in PATH `/no/such/dir`");
+    drop(_e);
+
+    // Not executable.
+    assert!(xshell::which("./Cargo.toml").is_err());
+    assert!(xshell::which("nope-no-way").is_err());
+
+    let mut sh = Shell::new().unwrap();
+    sh.set_var("PATH", "/no/such/dir");
+    assert!(sh.which("sh").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn which_all() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = scratch_dir("which_all");
+    for (name, mode) in [("a/tool", 0o755), ("b/tool", 0o644), ("c/tool", 0o755)] {
+        let path = dir.join(name);
+        xshell::write_file(&path, "#!/bin/sh\necho tool\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+    xshell::symlink(dir.join("a"), dir.join("link")).unwrap();
+    let path = ["a", "b", "link", "c"].map(|it| dir.join(it));
+    let path = std::env::join_paths(&path).unwrap();
+
+    let mut sh = Shell::new().unwrap();
+    sh.set_var("PATH", &path);
+    let found = sh.which_all("tool").unwrap();
+    assert_eq!(found, [dir.join("a/tool"), dir.join("c/tool")]);
+    assert_eq!(
+        sh.which_all("./nope").unwrap_err().to_string(),
+        "program `./nope` not found in PATH `{}`".replace("{}", &path.to_string_lossy())
+    );
+
+    // A directory with the same name isn't worth mentioning.
+    xshell::mkdir_p(dir.join("d/tool")).unwrap();
+    let sh_path = std::env::join_paths([dir.join("b"), dir.join("d")]).unwrap();
+    sh.set_var("PATH", &sh_path);
+    let err = sh.which("tool").unwrap_err();
+    let not_executable = format!("\nnot executable: {}", dir.join("b/tool").display());
+    assert!(err.to_string().ends_with(&not_executable), "{}", err);
+    let err = cmd!(sh, "tool").run().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("command `tool` failed, can't execute `tool`, permission denied{}", not_executable)
+    );
+    assert!(!err.is_program_missing());
+}
+
+#[test]
+fn test_pushd() {
+    let d1 = cwd().unwrap();
+    {
+        let _p = pushd("xshell-macros").unwrap();
+        let d2 = cwd().unwrap();
+        assert_eq!(d2, d1.join("xshell-macros"));
+        {
+            let _p = pushd("src").unwrap();
+            let d3 = cwd().unwrap();
+            assert_eq!(d3, d1.join("xshell-macros/src"));
+        }
+        let d4 = cwd().unwrap();
+        assert_eq!(d4, d1.join("xshell-macros"));
+    }
+    let d5 = cwd().unwrap();
+    assert_eq!(d5, d1);
+}
+
+#[test]
+fn with_dir() {
+    let d1 = cwd().unwrap();
+    let out = xshell::with_dir("xshell-macros", || cwd().unwrap()).unwrap();
+    assert_eq!(out, d1.join("xshell-macros"));
+    assert_eq!(cwd().unwrap(), d1);
+
+    let res = std::panic::catch_unwind(|| {
+        xshell::with_dir("xshell-macros", || panic!("boom")).unwrap();
+    });
+    assert!(res.is_err());
+    assert_eq!(cwd().unwrap(), d1);
+
+    let err = xshell::with_dir("no-such-dir", || ()).unwrap_err();
+    assert!(err.to_string().contains("no-such-dir"));
+}
+
+#[test]
+fn pushd_parent_dir() {
+    let current = cwd().unwrap();
+    let dirname = current.file_name().unwrap();
+    let _d = pushd("..").unwrap();
+    let _d = pushd(dirname).unwrap();
+    assert_eq!(cwd().unwrap(), current);
+}
+
+#[test]
+fn test_pushd_lock() {
+    let t1 = thread::spawn(|| {
+        let _p = pushd("cbench").unwrap();
+        sleep_ms(20);
+    });
+    sleep_ms(10);
+
+    let t2 = thread::spawn(|| {
+        let _p = pushd("cbench").unwrap();
+        sleep_ms(30);
+    });
+
+    t1.join().unwrap();
+    t2.join().unwrap();
+}
+
+#[test]
+fn pushd_drop_while_panicking() {
+    let d1 = cwd().unwrap();
+    let res = std::panic::catch_unwind(|| {
+        let _d = pushd("xshell-macros").unwrap();
+        let _e = pushenv("PUSHD_DROP_WHILE_PANICKING", "1");
+        std::env::set_current_dir("src").unwrap();
+        std::env::set_var("PUSHD_DROP_WHILE_PANICKING", "2");
+        panic!("original failure");
+    });
+    let err = res.unwrap_err();
+    assert_eq!(err.downcast_ref::<&str>(), Some(&"original failure"));
+    assert_eq!(cwd().unwrap(), d1);
+    assert_eq!(std::env::var_os("PUSHD_DROP_WHILE_PANICKING"), None);
+}
+
+#[test]
+fn pop() {
+    let d1 = cwd().unwrap();
+    let d = pushd("xshell-macros").unwrap();
+    d.pop().unwrap();
+    assert_eq!(cwd().unwrap(), d1);
+
+    let d = pushd("xshell-macros").unwrap();
+    std::env::set_current_dir("src").unwrap();
+    let err = d.pop().unwrap_err();
+    assert!(err.to_string().contains("changed concurrently"), "{}", err);
+    assert_eq!(cwd().unwrap(), d1);
+
+    let e = pushenv("PUSHENV_POP", "1");
+    std::env::set_var("PUSHENV_POP", "2");
+    let err = e.pop().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        r#"environment variable "PUSHENV_POP" was changed concurrently, expected "1", got "2""#
+    );
+    assert_eq!(std::env::var_os("PUSHENV_POP"), None);
+}
+
+const VAR: &str = "SPICA";
+
+#[test]
+fn test_pushenv() {
+    let e1 = std::env::var_os(VAR);
+    {
+        let _e = pushenv(VAR, "1");
+        let e2 = std::env::var_os(VAR);
+        assert_eq!(e2, Some("1".into()));
+        {
+            let _e = pushenv(VAR, "2");
+            let e3 = std::env::var_os(VAR);
+            assert_eq!(e3, Some("2".into()));
+        }
+        let e4 = std::env::var_os(VAR);
+        assert_eq!(e4, e2);
+    }
+    let e5 = std::env::var_os(VAR);
+    assert_eq!(e5, e1);
+}
+
+#[test]
+fn test_pushenv_remove() {
+    const VAR: &str = "XSHELL_PUSHENV_REMOVE";
+    let _outer = pushenv(VAR, "outer");
+    {
+        let _e = xshell::pushenv_remove(VAR);
+        assert_eq!(std::env::var_os(VAR), None);
+        assert_eq!(cmd!("sh -c 'echo ${XSHELL_PUSHENV_REMOVE-unset}'").read().unwrap(), "unset");
+        {
+            let _e = pushenv(VAR, "inner");
+            assert_eq!(std::env::var_os(VAR), Some("inner".into()));
+        }
+        assert_eq!(std::env::var_os(VAR), None);
+    }
+    assert_eq!(std::env::var_os(VAR), Some("outer".into()));
+
+    let e = xshell::pushenv_remove(VAR);
+    std::env::set_var(VAR, "2");
+    let err = e.pop().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        r#"environment variable "XSHELL_PUSHENV_REMOVE" was changed concurrently, expected it to be unset, got "2""#
+    );
+    assert_eq!(std::env::var_os(VAR), Some("outer".into()));
+}
+
+#[test]
+fn debug_state() {
+    let dir = scratch_dir("debug_state");
+    let prev_dir = cwd().unwrap();
+    let _d = pushd(&dir).unwrap();
+    let _e = pushenv("XSHELL_DEBUG_STATE", "1");
+    let _s = xshell::pushenvs([("XSHELL_DEBUG_TOKEN", "hunter2")]);
+    let state = xshell::with_dir("..", xshell::debug_state).unwrap();
+    let thread = thread::current().name().unwrap().to_string();
+    let expected = format!(
+        "cwd: {parent}
+pushd {dir} -> {parent} (thread `{thread}`)
+pushenv XSHELL_DEBUG_TOKEN: unset -> *** (thread `{thread}`)
+pushenv XSHELL_DEBUG_STATE: unset -> \"1\" (thread `{thread}`)
+pushd {prev_dir} -> {dir} (thread `{thread}`)
+",
+        parent = dir.parent().unwrap().display(),
+        dir = dir.display(),
+        prev_dir = prev_dir.display(),
+        thread = thread,
+    );
+    assert_eq!(state, expected);
+    drop((_s, _e, _d));
+    let _lock = xshell::lock();
+    assert_eq!(xshell::debug_state(), format!("cwd: {}\n", prev_dir.display()));
+    drop(_lock);
+
+    // Command errors include the state in verbose mode, which is global.
+    let exe = std::env::current_exe().unwrap();
+    let output = cmd!("{exe} --exact debug_state_child --nocapture --test-threads 1")
+        .env("DEBUG_STATE_CHILD", "1")
+        .quiet()
+        .read()
+        .unwrap();
+    assert!(output.contains("command `false` failed, exit code: 1\ncwd: "), "{}", output);
+    assert!(
+        output.contains("\npushenv XSHELL_DEBUG_STATE: unset -> \"1\" (thread `"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn debug_state_child() {
+    if std::env::var_os("DEBUG_STATE_CHILD").is_none() {
+        return;
+    }
+    xshell::set_verbosity(xshell::Verbosity::Verbose);
+    let _e = pushenv("XSHELL_DEBUG_STATE", "1");
+    println!("{}", cmd!("false").quiet().run().unwrap_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn pushd_symlink() {
+    let dir = scratch_dir("pushd_symlink").canonicalize().unwrap();
+    let real = dir.join("real");
+    let link = dir.join("link");
+    xshell::mkdir_p(&real).unwrap();
+    xshell::symlink(&real, &link).unwrap();
+    {
+        let _d = pushd(&link).unwrap();
+        assert_eq!(cwd().unwrap(), real);
+        let _e = pushenv("PWD", &link);
+        assert_eq!(xshell::cwd_logical().unwrap(), link);
+        let _e = pushenv("PWD", &dir);
+        assert_eq!(xshell::cwd_logical().unwrap(), real);
+    }
+    assert_ne!(cwd().unwrap(), real);
+}
+
+#[test]
+fn shell_lock() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let guard = xshell::lock();
+    // Reentrant on the same thread.
+    drop(pushenv("XSHELL_SHELL_LOCK", "1"));
+
+    let done = Arc::new(AtomicBool::new(false));
+    let t = thread::spawn({
+        let done = Arc::clone(&done);
+        move || {
+            drop(pushenv("XSHELL_SHELL_LOCK", "2"));
+            done.store(true, Ordering::SeqCst);
+        }
+    });
+    sleep_ms(100);
+    assert!(!done.load(Ordering::SeqCst));
+    drop(guard);
+    t.join().unwrap();
+    assert!(done.load(Ordering::SeqCst));
+}
+
+#[test]
+fn env_snapshot() {
+    use std::sync::mpsc;
+
+    let cmd = {
+        let _e = pushenv("XSHELL_SNAPSHOT", "main");
+        cmd!("printenv XSHELL_SNAPSHOT").env_snapshot()
+    };
+    let plain = cmd!("printenv XSHELL_SNAPSHOT").ignore_status();
+    let (pushed_tx, pushed) = mpsc::channel();
+    let (ran_tx, ran) = mpsc::channel::<()>();
+    let t = thread::spawn(move || {
+        let _e = pushenv("XSHELL_SNAPSHOT", "other");
+        pushed_tx.send(()).unwrap();
+        ran.recv().unwrap();
+    });
+    pushed.recv().unwrap();
+    assert_eq!(cmd.read().unwrap(), "main");
+    assert_eq!(cmd.clone().env("XSHELL_SNAPSHOT", "env").read().unwrap(), "env");
+    assert_eq!(plain.read().unwrap(), "other");
+    ran_tx.send(()).unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn test_pushenv_path() {
+    let dir = scratch_dir("pushenv_path");
+    let prev_path = std::env::var_os("PATH");
+    {
+        let _p = xshell::pushenv_path(&dir).unwrap();
+        let path = std::env::var_os("PATH").unwrap();
+        let mut dirs = std::env::split_paths(&path);
+        assert_eq!(dirs.next().unwrap(), dir);
+        assert!(dirs.next().is_some());
+    }
+    assert_eq!(std::env::var_os("PATH"), prev_path);
+
+    #[cfg(unix)]
+    {
+        let err = xshell::pushenv_path("a:b").err().unwrap();
+        assert!(err.to_string().starts_with("`a:b`: "));
+        assert_eq!(std::env::var_os("PATH"), prev_path);
+    }
+}
+
+#[test]
+fn test_pushenvs() {
+    let e1 = std::env::var_os("PUSHENVS_A");
+    {
+        let _e = xshell::pushenvs([("PUSHENVS_A", "1"), ("PUSHENVS_B", "2"), ("PUSHENVS_A", "3")]);
+        assert_eq!(std::env::var_os("PUSHENVS_A"), Some("3".into()));
+        assert_eq!(std::env::var_os("PUSHENVS_B"), Some("2".into()));
+    }
+    assert_eq!(std::env::var_os("PUSHENVS_A"), e1);
+    assert_eq!(std::env::var_os("PUSHENVS_B"), None);
+
+    let res = std::panic::catch_unwind(|| {
+        let _e = xshell::pushenvs([("PUSHENVS_C", "1")]);
+        std::env::set_var("PUSHENVS_C", "2");
+    });
+    let err = res.unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(msg.contains("\"PUSHENVS_C\""), "{}", msg);
+    std::env::remove_var("PUSHENVS_C");
+}
+
+#[test]
+fn test_pushenv_lock() {
+    let t1 = thread::spawn(|| {
+        let _e = pushenv(VAR, "hello");
+        sleep_ms(20);
+    });
+    sleep_ms(10);
+
+    let t2 = thread::spawn(|| {
+        let _e = pushenv(VAR, "world");
+        sleep_ms(30);
+    });
+
+    t1.join().unwrap();
+    t2.join().unwrap();
+}
+
+#[test]
+fn shell_cwd() {
+    let mut sh = Shell::new().unwrap();
+    let d1 = cwd().unwrap();
+    sh.change_dir("xshell-macros");
+    assert_eq!(sh.current_dir(), d1.join("xshell-macros"));
+    assert_eq!(cwd().unwrap(), d1);
+
+    let output = cmd!(sh, "ls").read().unwrap();
+    assert!(output.lines().any(|it| it == "src"));
+    assert!(sh.read_file("Cargo.toml").unwrap().contains("xshell-macros"));
+}
+
+#[test]
+fn shell_with_dir() {
+    let dir = scratch_dir("shell_with_dir");
+    let sh = Shell::new().unwrap();
+    let root = sh.with_dir(&dir);
+    assert_eq!(sh.current_dir(), cwd().unwrap());
+
+    let check = |name: &'static str| {
+        let root = root.with_dir(name);
+        move || {
+            root.mkdir_p("sub").unwrap();
+            let sub = root.with_dir("./sub/");
+            assert_eq!(sub.current_dir(), root.current_dir().join("sub"));
+            for _ in 0..20 {
+                sub.write_file("file", name).unwrap();
+                assert_eq!(root.read_file("sub/file").unwrap(), name);
+                assert_eq!(cmd!(sub, "cat file").read().unwrap(), name);
+                assert_eq!(cmd!(sub, "pwd").read().unwrap(), sub.current_dir().to_str().unwrap());
+            }
+            let up = sub.with_dir("../..");
+            assert_eq!(up.current_dir(), root.current_dir().parent().unwrap());
+            assert_eq!(up.read_dir(name).unwrap(), [root.current_dir().join("sub")]);
+            root.rm_rf("sub/file").unwrap();
+            assert!(sub.read_dir(".").unwrap().is_empty());
+        }
+    };
+    for thread in [thread::spawn(check("a")), thread::spawn(check("b"))] {
+        thread.join().unwrap();
+    }
+
+    // Absolute paths are used as is.
+    assert_eq!(root.with_dir("a").with_dir(&dir).current_dir(), dir);
+    let fs_root = dir.ancestors().last().unwrap();
+    assert_eq!(sh.with_dir(fs_root).with_dir("..").current_dir(), fs_root);
+    assert_eq!(cwd().unwrap(), sh.current_dir());
+}
+
+#[test]
+fn shell_env() {
+    let mut sh = Shell::new().unwrap();
+    sh.set_var("SHELL_ENV_VAR", "hello");
+    assert_eq!(sh.var_os("SHELL_ENV_VAR"), Some("hello".into()));
+    assert_eq!(std::env::var_os("SHELL_ENV_VAR"), None);
+
+    let output = cmd!(sh, "printenv SHELL_ENV_VAR").read().unwrap();
+    assert_eq!(output, "hello");
+
+    sh.remove_var("SHELL_ENV_VAR");
+    assert!(cmd!(sh, "printenv SHELL_ENV_VAR").read().is_err());
+}
+
+#[test]
+fn shell_parallel() {
+    let threads = ["xshell-macros", "cbench", "src"]
+        .iter()
+        .map(|&dir| {
+            thread::spawn(move || {
+                let mut sh = Shell::new().unwrap();
+                sh.change_dir(dir);
+                sh.set_var("SHELL_PARALLEL", dir);
+                for _ in 0..10 {
+                    let pwd = cmd!(sh, "pwd").read().unwrap();
+                    assert!(pwd.ends_with(dir));
+                    let var = cmd!(sh, "printenv SHELL_PARALLEL").read().unwrap();
+                    assert_eq!(var, dir);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn copy_dir() {
+    let dir = scratch_dir("copy_dir");
+    let src = dir.join("src");
+    xshell::mkdir_p(src.join("a/b")).unwrap();
+    xshell::write_file(src.join("top.txt"), "top").unwrap();
+    xshell::write_file(src.join("a/b/deep.txt"), "deep").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("top.txt", src.join("link")).unwrap();
+
+    let dst = dir.join("dst");
+    xshell::mkdir_p(&dst).unwrap();
+    xshell::write_file(dst.join("top.txt"), "old").unwrap();
+    xshell::write_file(dst.join("extra.txt"), "extra").unwrap();
+
+    xshell::copy_dir(&src, &dst).unwrap();
+    assert_eq!(read_file(dst.join("top.txt")).unwrap(), "top");
+    assert_eq!(read_file(dst.join("a/b/deep.txt")).unwrap(), "deep");
+    assert_eq!(read_file(dst.join("extra.txt")).unwrap(), "extra");
+    #[cfg(unix)]
+    assert_eq!(std::fs::read_link(dst.join("link")).unwrap(), Path::new("top.txt"));
+
+    // Copying again replaces the link instead of failing.
+    xshell::copy_dir(&src, &dst).unwrap();
+
+    let err = xshell::copy_dir(dir.join("missing"), &dst).unwrap_err();
+    assert!(err.to_string().contains("missing"));
+
+    for inside in [src.clone(), src.join("a/new/b"), dir.join("dst/../src/c")] {
+        let err = xshell::copy_dir(&src, &inside).unwrap_err();
+        assert!(err.to_string().contains("can't copy a directory into itself"), "{}", err);
+    }
+    assert!(!src.join("a/new").exists());
+}
+
+#[test]
+fn copy_overwrite() {
+    use std::time::SystemTime;
+    use xshell::{CopyOptions, CopyStats, Overwrite};
+
+    let set_mtime = |path: &Path, mtime: SystemTime| {
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(mtime).unwrap();
+    };
+    let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+
+    let dir = scratch_dir("copy_overwrite");
+    let src = dir.join("src");
+    let dst = dir.join("dst");
+    xshell::mkdir_p(src.join("sub")).unwrap();
+    xshell::mkdir_p(&dst).unwrap();
+    xshell::write_file(src.join("new.txt"), "new").unwrap();
+    xshell::write_file(src.join("stale.txt"), "stale").unwrap();
+    xshell::write_file(src.join("sub/fresh.txt"), "fresh").unwrap();
+    xshell::write_file(dst.join("new.txt"), "old").unwrap();
+    xshell::write_file(dst.join("stale.txt"), "edited").unwrap();
+    set_mtime(&dst.join("new.txt"), hour_ago);
+    set_mtime(&src.join("stale.txt"), hour_ago);
+
+    let opts = CopyOptions { overwrite: Overwrite::Error };
+    let err = xshell::cp_opts(src.join("new.txt"), &dst, opts).unwrap_err();
+    let msg = err.to_string();
+    assert!(msg.contains("src") && msg.contains("refusing to overwrite") && msg.contains("dst"));
+    assert_eq!(read_file(dst.join("new.txt")).unwrap(), "old");
+    let stats = xshell::cp_opts(src.join("sub/fresh.txt"), &dst, opts).unwrap();
+    assert_eq!(stats, CopyStats { copied: 1, skipped: 0 });
+
+    let opts = CopyOptions { overwrite: Overwrite::IfNewer };
+    let stats = xshell::copy_dir_opts(&src, &dst, opts).unwrap();
+    assert_eq!(stats, CopyStats { copied: 2, skipped: 1 });
+    assert_eq!(read_file(dst.join("new.txt")).unwrap(), "new");
+    assert_eq!(read_file(dst.join("stale.txt")).unwrap(), "edited");
+    assert_eq!(read_file(dst.join("sub/fresh.txt")).unwrap(), "fresh");
+
+    let stats = xshell::copy_dir_opts(&src, &dst, CopyOptions::default()).unwrap();
+    assert_eq!(stats, CopyStats { copied: 3, skipped: 0 });
+    assert_eq!(read_file(dst.join("stale.txt")).unwrap(), "stale");
+}
+
+#[test]
+fn links() {
+    let dir = scratch_dir("links");
+    xshell::write_file(dir.join("v1"), "1").unwrap();
+    xshell::write_file(dir.join("v2"), "2").unwrap();
+
+    xshell::symlink("v1", dir.join("latest")).unwrap();
+    assert_eq!(read_file(dir.join("latest")).unwrap(), "1");
+
+    let err = xshell::symlink("v2", dir.join("latest")).unwrap_err();
+    assert!(err.to_string().contains("latest"));
+
+    xshell::symlink_force("v2", dir.join("latest")).unwrap();
+    assert_eq!(read_file(dir.join("latest")).unwrap(), "2");
+
+    // Threads forcing the same link don't trip over each other's temporary
+    // links.
+    thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| {
+                for _ in 0..100 {
+                    xshell::symlink_force("v1", dir.join("latest")).unwrap();
+                }
+            });
+        }
+    });
+    assert_eq!(read_file(dir.join("latest")).unwrap(), "1");
+
+    xshell::hard_link(dir.join("v1"), dir.join("hard")).unwrap();
+    assert_eq!(read_file(dir.join("hard")).unwrap(), "1");
+}
+
+#[test]
+fn glob() {
+    let dir = scratch_dir("glob");
+    for path in ["a.rs", "b.rs", "c.txt", ".hidden.rs", "sub/d.rs", "sub/deeper/e.rs", ".git/f.rs"]
+    {
+        let path = dir.join(path);
+        xshell::mkdir_p(path.parent().unwrap()).unwrap();
+        xshell::write_file(path, "").unwrap();
+    }
+    let _d = pushd(&dir).unwrap();
+    let glob = |pattern: &str| {
+        let paths = xshell::glob(pattern).unwrap();
+        paths.iter().map(|it| it.to_str().unwrap().to_string()).collect::<Vec<_>>().join(" ")
+    };
+
+    assert_eq!(glob("*.rs"), "a.rs b.rs");
+    assert_eq!(glob("?.*"), "a.rs b.rs c.txt");
+    assert_eq!(glob(".*.rs"), ".hidden.rs");
+    assert_eq!(glob("**/*.rs"), "a.rs b.rs sub/d.rs sub/deeper/e.rs");
+    assert_eq!(glob("sub/**"), "sub sub/d.rs sub/deeper sub/deeper/e.rs");
+    assert_eq!(glob("a.rs/*"), "");
+    assert_eq!(glob("*.md"), "");
+    assert_eq!(glob("sub/*/../d.rs"), "sub/deeper/../d.rs");
+    assert_eq!(glob("s*/../c.txt"), "sub/../c.txt");
+    assert_eq!(glob(&format!("{}/*.txt", dir.display())), format!("{}/c.txt", dir.display()));
+
+    assert_eq!(xshell::glob_one("*.txt").unwrap(), Path::new("c.txt"));
+    let err = xshell::glob_one("*.rs").unwrap_err();
+    assert_eq!(err.to_string(), "`*.rs`: expected exactly one match, found 2");
+
+    let files = xshell::glob("*.rs").unwrap();
+    assert_eq!(cmd!("wc -c {files...}").to_string(), "wc -c a.rs b.rs");
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let name = OsStr::from_bytes(b"\xff.log");
+        xshell::write_file(dir.join("sub").join(name), "").unwrap();
+        assert_eq!(xshell::glob("sub/*.log").unwrap(), [Path::new("sub").join(name)]);
+    }
+}
+
+#[test]
+fn walk_dir() {
+    let dir = scratch_dir("walk_dir");
+    for path in ["b/Cargo.toml", "a/x/Cargo.toml", "a/y.rs", "Cargo.toml"] {
+        let path = dir.join(path);
+        xshell::mkdir_p(path.parent().unwrap()).unwrap();
+        xshell::write_file(path, "").unwrap();
+    }
+    #[cfg(unix)]
+    xshell::symlink("..", dir.join("a/x/loop")).unwrap();
+
+    let entries = xshell::read_dir_entries(&dir).unwrap();
+    let dirs = entries.iter().filter(|it| it.is_dir()).map(|it| it.path()).collect::<Vec<_>>();
+    assert_eq!(dirs, [dir.join("a"), dir.join("b")]);
+
+    let files = xshell::walk_dir(&dir).unwrap();
+    let files = files.iter().map(|it| it.strip_prefix(&dir).unwrap()).collect::<Vec<_>>();
+    let mut expected = vec!["Cargo.toml", "a/x/Cargo.toml", "a/y.rs", "b/Cargo.toml"];
+    if cfg!(unix) {
+        expected.insert(2, "a/x/loop");
+    }
+    assert_eq!(files, expected.iter().map(Path::new).collect::<Vec<_>>());
+
+    let err = xshell::walk_dir(dir.join("missing")).unwrap_err();
+    assert!(err.to_string().contains("missing"));
+}
+
+#[test]
+fn read_dir_order() {
+    let dir = scratch_dir("read_dir_order");
+    for name in ["b", "B", ".DS_Store", "a.txt", "a", ".swp", "_"] {
+        xshell::write_file(dir.join(name), "").unwrap();
+    }
+    let names = |paths: Vec<PathBuf>| {
+        paths
+            .iter()
+            .map(|it| it.file_name().unwrap().to_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        names(xshell::read_dir(&dir).unwrap()),
+        [".DS_Store", ".swp", "B", "_", "a", "a.txt", "b"]
+    );
+    assert_eq!(names(xshell::read_dir_visible(&dir).unwrap()), ["B", "_", "a", "a.txt", "b"]);
+
+    let entries = xshell::read_dir_entries(&dir).unwrap();
+    let hidden = entries.iter().filter(|it| it.is_hidden()).map(|it| it.path()).collect::<Vec<_>>();
+    assert_eq!(hidden, [dir.join(".DS_Store"), dir.join(".swp")]);
+}
+
+#[test]
+fn write_file_atomic() {
+    let dir = scratch_dir("write_file_atomic");
+    let path = dir.join("config");
+    xshell::write_file_atomic(&path, "v1").unwrap();
+    assert_eq!(read_file(&path).unwrap(), "v1");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        xshell::write_file_atomic(&path, "v2").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    xshell::write_file_atomic(&path, "v3").unwrap();
+    assert_eq!(read_file(&path).unwrap(), "v3");
+    assert_eq!(xshell::read_dir(&dir).unwrap(), std::slice::from_ref(&path));
+
+    let nested = dir.join("no/such/dir");
+    xshell::write_file_atomic(&nested, "v4").unwrap();
+    assert_eq!(read_file(&nested).unwrap(), "v4");
+    let err = xshell::write_file_atomic(path.join("file"), "").unwrap_err();
+    assert_eq!(err.path(), Some(path.as_path()));
+}
+
+#[test]
+fn write_file_creates_parents() {
+    let dir = scratch_dir("write_file_creates_parents");
+    let file = dir.join("a/b/c.txt");
+    xshell::write_file(&file, "hello").unwrap();
+    assert_eq!(read_file(&file).unwrap(), "hello");
+    xshell::write_file(&file, "world").unwrap();
+    assert_eq!(read_file(&file).unwrap(), "world");
+
+    let err = xshell::write_file(dir.join("a/b/c.txt/d/e.txt"), "").unwrap_err();
+    assert_eq!(err.to_string(), format!("`{}`: not a directory", file.display()));
+    assert_eq!(err.path(), Some(file.as_path()));
+}
+
+#[test]
+fn fs_predicates() {
+    let dir = scratch_dir("fs_predicates");
+    let file = dir.join("file.txt");
+    xshell::write_file(&file, "hello").unwrap();
+
+    assert!(xshell::path_exists(&file) && xshell::is_file(&file) && !xshell::is_dir(&file));
+    assert!(xshell::is_dir(&dir) && !xshell::is_file(&dir));
+    assert_eq!(xshell::metadata(&file).unwrap().len(), 5);
+
+    let missing = dir.join("missing");
+    assert!(!xshell::path_exists(&missing));
+    let err = xshell::metadata(&missing).unwrap_err();
+    assert!(err.is_not_found());
+    assert_eq!(err.path(), Some(missing.as_path()));
+
+    let mut sh = Shell::new().unwrap();
+    sh.change_dir(&dir);
+    assert!(sh.is_file("file.txt") && sh.path_exists("file.txt"));
+    assert!(!sh.path_exists("missing"));
+
+    #[cfg(unix)]
+    {
+        let link = dir.join("broken");
+        xshell::symlink("missing", &link).unwrap();
+        assert!(!xshell::path_exists(&link));
+        assert!(xshell::metadata(&link).is_err());
+        assert!(xshell::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert!(sh.symlink_metadata("broken").is_ok());
+    }
+}
+
+#[test]
+fn append_file() {
+    let dir = scratch_dir("append_file");
+    let path = dir.join("log");
+    let threads = (0..4)
+        .map(|i| {
+            let path = path.clone();
+            thread::spawn(move || {
+                let line = format!("{}\n", i.to_string().repeat(100));
+                for _ in 0..50 {
+                    xshell::append_file(&path, &line).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+    let log = read_file(&path).unwrap();
+    assert_eq!(log.lines().count(), 200);
+    assert!(log.lines().all(|line| line.len() == 100 && line.chars().all(|c| line.starts_with(c))));
+
+    let nested = dir.join("no/such/dir");
+    xshell::append_file(&nested, "a").unwrap();
+    xshell::append_file(&nested, "b").unwrap();
+    assert_eq!(read_file(&nested).unwrap(), "ab");
+    let err = xshell::append_file(path.join("file"), "").unwrap_err();
+    assert_eq!(err.path(), Some(path.as_path()));
+}
+
+#[test]
+fn read_file_encodings() {
+    let dir = scratch_dir("read_file_encodings");
+    let path = dir.join("file");
+
+    xshell::write_file(&path, b"\xEF\xBB\xBFhello").unwrap();
+    assert_eq!(read_file(&path).unwrap(), "hello");
+    assert_eq!(xshell::read_file_bytes(&path).unwrap(), b"\xEF\xBB\xBFhello");
+
+    xshell::write_file(&path, b"\xEF\xBB\xBFab\xFFc").unwrap();
+    let err = read_file(&path).unwrap_err();
+    assert_eq!(err.to_string(), format!("`{}`: invalid UTF-8 at byte offset 5", path.display()));
+    assert_eq!(xshell::read_file_lossy(&path).unwrap(), "ab\u{FFFD}c");
+}
+
+#[test]
+fn mktemp_d() {
+    let d1 = cwd().unwrap();
+    let path = {
+        let tmp = xshell::mktemp_d().unwrap();
+        let _d = pushd(tmp.path()).unwrap();
+        xshell::write_file("file", "").unwrap();
+        tmp.path().to_path_buf()
+    };
+    assert!(!path.exists());
+    assert_eq!(cwd().unwrap(), d1);
+
+    // The other drop order.
+    let tmp = xshell::mktemp_d().unwrap();
+    let d = pushd(tmp.path()).unwrap();
+    drop(tmp);
+    drop(d);
+    assert_eq!(cwd().unwrap(), d1);
+
+    let tmp = xshell::mktemp_d().unwrap();
+    assert_ne!(tmp.path(), xshell::mktemp_d().unwrap().path());
+    let path = tmp.keep();
+    assert!(path.is_dir());
+    xshell::rm_rf(path).unwrap();
+}
+
+#[test]
+fn mktemp_f() {
+    let path = {
+        let tmp = xshell::mktemp_f().unwrap();
+        assert_eq!(read_file(&tmp).unwrap(), "");
+        cmd!("sh -c 'echo hello > \"$1\"' sh {tmp}").quiet().run().unwrap();
+        assert_eq!(read_file(&tmp).unwrap(), "hello\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = xshell::metadata(&tmp).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        tmp.path().to_path_buf()
+    };
+    assert!(!path.exists());
+
+    let tmp = xshell::mktemp_f_with_suffix(".json").unwrap();
+    assert!(tmp.path().to_str().unwrap().ends_with(".json"));
+    assert_ne!(tmp.path(), xshell::mktemp_f().unwrap().path());
+    xshell::write_file(&tmp, "{}").unwrap();
+    let path = tmp.keep();
+    assert_eq!(read_file(&path).unwrap(), "{}");
+    xshell::rm_rf(path).unwrap();
+}
+
+#[test]
+fn lock_file() {
+    let dir = scratch_dir("lock_file");
+    let path = dir.join("release.lock");
+    let lock = xshell::lock_file(&path).unwrap();
+    assert!(path.is_file());
+    assert_eq!(lock.path(), path);
+    let err = xshell::try_lock_file(&path).unwrap_err();
+    assert_eq!(err.io().unwrap().kind(), std::io::ErrorKind::WouldBlock);
+    assert_eq!(
+        err.to_string(),
+        format!("`{}`: already locked, is another instance running?", path.display())
+    );
+
+    // Another process.
+    let exe = std::env::current_exe().unwrap();
+    let child = cmd!("{exe} --exact lock_file_child --nocapture --test-threads 1")
+        .env("LOCK_FILE_CHILD", &path)
+        .quiet();
+    let output = child.clone().ignore_status().output().unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("already locked"), "{}", stderr);
+
+    let waiting = thread::spawn(move || {
+        let _lock = xshell::lock_file(&path).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert!(!waiting.is_finished());
+    drop(lock);
+    waiting.join().unwrap();
+    child.read().unwrap();
+}
+
+#[test]
+fn lock_file_child() {
+    let path = match std::env::var_os("LOCK_FILE_CHILD") {
+        Some(it) => it,
+        None => return,
+    };
+    let _lock = xshell::try_lock_file(path).unwrap();
+}
+
+#[test]
+fn read_with() {
+    // Mimics the errors of `serde_json`.
+    fn parse_list(text: &str) -> Result<Vec<u32>, String> {
+        for (idx, line) in text.lines().enumerate() {
+            if let Some(column) = line.find(|c: char| !c.is_ascii_digit() && c != ',') {
+                return Err(format!("invalid digit at line {} column {}", idx + 1, column + 1));
+            }
+        }
+        Ok(text
+            .split([',', '\n'])
+            .filter(|it| !it.is_empty())
+            .map(|it| it.parse().unwrap())
+            .collect())
+    }
+
+    let output = cmd!("printf '1,2\\n3'").read_with(parse_list).unwrap();
+    assert_eq!(output, [1, 2, 3]);
+    let err = cmd!("printf '1,2\\n3,x,4'").read_with(parse_list).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "can't parse the output of `printf '1,2\\n3,x,4'`: invalid digit at line 2 column 3\n3,x,4\n  ^"
+    );
+    let err = cmd!("true").read_with(|_| "nope".parse::<u32>()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "can't parse the output of `true`: invalid digit found in string, the input is empty"
+    );
+
+    let dir = scratch_dir("read_with");
+    let path = dir.join("list");
+    xshell::write_file(&path, "1\n2,x").unwrap();
+    let err = xshell::read_file_with(&path, parse_list).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("`{}`: invalid digit at line 2 column 3\n2,x\n  ^", path.display())
+    );
+    assert_eq!(err.io().unwrap().kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn read_json_toml() {
+    use std::collections::BTreeMap;
+
+    let output: BTreeMap<String, u32> = cmd!("echo '{\"a\": 1, \"b\": 2}'").read_json().unwrap();
+    assert_eq!(output, BTreeMap::from([("a".to_string(), 1), ("b".to_string(), 2)]));
+    let err = cmd!("echo '[1, 2,]'").read_json::<Vec<u32>>().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "can't parse the output of `echo '[1, 2,]'`: trailing comma at line 1 column 7\n[1, 2,]\n      ^"
+    );
+
+    let dir = scratch_dir("read_json_toml");
+    let path = dir.join("list.json");
+    xshell::write_file(&path, "[\n  1,\n  \"two\"\n]").unwrap();
+    let err = xshell::read_json_file::<Vec<u32>>(&path).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "`{}`: invalid type: string \"two\", expected u32 at line 3 column 7\n  \"two\"\n      ^",
+            path.display()
+        )
+    );
+    xshell::write_file(&path, "[1, 2]").unwrap();
+    assert_eq!(xshell::read_json_file::<Vec<u32>>(&path).unwrap(), [1, 2]);
+
+    let path = dir.join("Cargo.toml");
+    xshell::write_file(&path, "[package]\nname = \"xshell\"\n").unwrap();
+    type Manifest = BTreeMap<String, BTreeMap<String, String>>;
+    let manifest: Manifest = xshell::read_toml_file(&path).unwrap();
+    assert_eq!(manifest["package"]["name"], "xshell");
+    xshell::write_file(&path, "[package]\nname = xshell\n").unwrap();
+    let err = xshell::read_toml_file::<Manifest>(&path).unwrap_err();
+    let msg = err.to_string();
+    assert!(
+        msg.starts_with(&format!("`{}`: TOML parse error at line 2", path.display())),
+        "{}",
+        msg
+    );
+    assert!(msg.contains("name = xshell"), "{}", msg);
+}
+
+#[test]
+fn mkdir_p_rm_rf_stats() {
+    use xshell::RemoveStats;
+
+    let dir = scratch_dir("mkdir_p_rm_rf_stats");
+    assert!(xshell::mkdir_p(dir.join("a")).unwrap());
+    assert!(!xshell::mkdir_p(dir.join("a")).unwrap());
+    // Only some of the components are missing.
+    assert!(xshell::mkdir_p(dir.join("a/b/c")).unwrap());
+    assert!(!xshell::mkdir_p(dir.join("a/b")).unwrap());
+    xshell::write_file(dir.join("file"), "").unwrap();
+    assert!(xshell::mkdir_p(dir.join("file")).is_err());
+
+    let empty = RemoveStats { files: 0, dirs: 1, bytes: 0 };
+    assert_eq!(xshell::rm_rf(dir.join("a/b/c")).unwrap(), empty);
+    xshell::write_file(dir.join("a/b/x.txt"), "hello").unwrap();
+    xshell::write_file(dir.join("a/y.txt"), "hi").unwrap();
+    #[cfg(unix)]
+    xshell::symlink("y.txt", dir.join("a/link")).unwrap();
+    let files = if cfg!(unix) { 3 } else { 2 };
+    assert_eq!(xshell::rm_rf(dir.join("a")).unwrap(), RemoveStats { files, dirs: 2, bytes: 7 });
+    assert_eq!(xshell::rm_rf(dir.join("a")).unwrap(), RemoveStats::default());
+    assert_eq!(
+        xshell::rm_rf(dir.join("file")).unwrap(),
+        RemoveStats { files: 1, dirs: 0, bytes: 0 }
+    );
+
+    // Symlinks to directories are removed, not followed.
+    #[cfg(unix)]
+    {
+        xshell::write_file(dir.join("target/z.txt"), "z").unwrap();
+        xshell::mkdir_p(dir.join("tree")).unwrap();
+        xshell::symlink("../target", dir.join("tree/link")).unwrap();
+        xshell::symlink("target", dir.join("link")).unwrap();
+        let link = RemoveStats { files: 1, dirs: 0, bytes: 0 };
+        assert_eq!(
+            xshell::rm_rf(dir.join("tree")).unwrap(),
+            RemoveStats { files: 1, dirs: 1, bytes: 0 }
+        );
+        assert_eq!(xshell::rm_rf(dir.join("link")).unwrap(), link);
+        assert!(dir.join("target/z.txt").exists());
+
+        xshell::symlink("no-such-target", dir.join("dangling")).unwrap();
+        assert_eq!(xshell::rm_rf(dir.join("dangling")).unwrap(), link);
+        assert!(dir.join("dangling").symlink_metadata().is_err());
+    }
+}
+
+#[test]
+fn rm_rf_read_only() {
+    let dir = scratch_dir("rm_rf_read_only");
+    let tree = dir.join("tree");
+    xshell::mkdir_p(tree.join("objects")).unwrap();
+    for path in [tree.join("objects/ab"), dir.join("file")] {
+        xshell::write_file(&path, "").unwrap();
+        let mut perms = std::fs::metadata(&path).unwrap().permissions();
+        perms.set_readonly(true);
+        std::fs::set_permissions(&path, perms).unwrap();
+    }
+
+    xshell::rm_rf(&tree).unwrap();
+    xshell::rm_rf(dir.join("file")).unwrap();
+    assert!(xshell::read_dir(&dir).unwrap().is_empty());
+    xshell::rm_rf(&tree).unwrap();
+}
+
+#[test]
+fn cp() {
+    let dir = scratch_dir("cp");
+    let src = dir.join("script.sh");
+    xshell::write_file(&src, "echo hi").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    xshell::mkdir_p(dir.join("a")).unwrap();
+    xshell::mkdir_p(dir.join("b")).unwrap();
+
+    xshell::cp(&src, dir.join("a")).unwrap();
+    assert_eq!(read_file(dir.join("a/script.sh")).unwrap(), "echo hi");
+    xshell::cp(&src, format!("{}/b/", dir.display())).unwrap();
+    assert_eq!(read_file(dir.join("b/script.sh")).unwrap(), "echo hi");
+    #[cfg(windows)]
+    {
+        xshell::cp(&src, format!("{}\\a\\", dir.display())).unwrap();
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(dir.join("a/script.sh")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    let err = xshell::cp(&src, &dir).unwrap_err();
+    assert!(err.to_string().ends_with("is the same file"), "{}", err);
+    assert_eq!(read_file(&src).unwrap(), "echo hi");
+}
+
+#[test]
+fn mv() {
+    let dir = scratch_dir("mv");
+    xshell::write_file(dir.join("a"), "a").unwrap();
+    xshell::write_file(dir.join("b"), "b").unwrap();
+    xshell::mv(dir.join("a"), dir.join("b")).unwrap();
+    assert_eq!(read_file(dir.join("b")).unwrap(), "a");
+    assert!(!dir.join("a").exists());
+
+    xshell::mkdir_p(dir.join("d")).unwrap();
+    xshell::mv(dir.join("b"), dir.join("d")).unwrap();
+    assert_eq!(read_file(dir.join("d/b")).unwrap(), "a");
+
+    // Possibly a different file system.
+    let tmp = xshell::mktemp_d().unwrap();
+    xshell::mv(dir.join("d"), tmp.path().join("d")).unwrap();
+    assert_eq!(read_file(tmp.path().join("d/b")).unwrap(), "a");
+
+    let err = xshell::mv(dir.join("missing"), dir.join("x")).unwrap_err();
+    assert!(err.to_string().contains("failed to rename to"), "{}", err);
+}
+
+#[test]
+fn dry_run() {
+    // Dry-run mode is global, so check it in a separate process.
+    let exe = std::env::current_exe().unwrap();
+    let dir = scratch_dir("dry_run");
+    let output = cmd!("{exe} --exact dry_run_child --nocapture --test-threads 1")
+        .env("XSHELL_DRY_RUN", "1")
+        .env("DRY_RUN_DIR", &dir)
+        .quiet()
+        .read()
+        .unwrap();
+    let path = dir.join("file");
+    for line in [
+        format!("$ touch {}", path.display()),
+        format!("$ cat {}", path.display()),
+        format!("$ write {}", path.display()),
+        format!("$ rm -rf {}", dir.display()),
+    ] {
+        assert!(output.contains(&line), "{:?} not in:\n{}", line, output);
+    }
+    assert!(!output.contains("$ ls"));
+    assert!(dir.exists() && !path.exists());
+}
+
+#[test]
+fn dry_run_child() {
+    let dir = match std::env::var_os("DRY_RUN_DIR") {
+        Some(it) => PathBuf::from(it),
+        None => return,
+    };
+    assert!(xshell::is_dry_run());
+    let path = dir.join("file");
+    cmd!("touch {path}").run().unwrap();
+    assert_eq!(cmd!("cat {path}").read().unwrap(), "");
+    xshell::write_file(&path, "").unwrap();
+    xshell::rm_rf(&dir).unwrap();
+    assert_eq!(cmd!("ls {dir}").quiet().force_run().read().unwrap(), "");
+
+    xshell::set_dry_run(false);
+    assert!(!xshell::is_dry_run());
+}
+
+#[test]
+fn versions_match() {
+    let _lock_cwd = pushd(".");
+    let read_version = |path: &str| {
+        let text = read_file(path).unwrap();
+        text.lines().find(|it| it.starts_with("version =")).unwrap().trim().to_string()
+    };
+
+    let v1 = read_version("./Cargo.toml");
+    let v2 = read_version("./xshell-macros/Cargo.toml");
+    assert_eq!(v1, v2);
+
+    let cargo_toml = read_file("./Cargo.toml").unwrap();
+    let dep = format!("xshell-macros = {{ {}", v1);
+    assert!(cargo_toml.contains(&dep));
+}
+
+#[test]
+fn macro_diagnostics() {
+    let dir = scratch_dir("macro_diagnostics");
+    let manifest = format!(
+        "[package]\nname = \"diagnostics\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\
+         [dependencies]\nxshell = {{ path = {:?} }}\n[workspace]\n",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    xshell::write_file(dir.join("Cargo.toml"), manifest).unwrap();
+    let cases = [
+        ("echo {}", "empty interpolation `{}`: specify a variable name, or use `{{}}` for literal braces"),
+        ("echo {...}", "empty interpolation `{...}`: specify a variable name, or use `{{}}` for literal braces"),
+        ("echo {x", "unterminated interpolation starting at `{x`, add a closing `}` or use `{{` for a literal `{`"),
+        ("echo x}", "unmatched `}` in `x}`, use `}}` for a literal `}`"),
+        ("echo 'hi", "unterminated quote starting at `'hi`, add a closing `'`"),
+        ("echo {f(}", "invalid interpolation `{f(}`"),
+        ("{args...}", "the program can't be splatted, use `{program} {args...}`"),
+        ("echo {not_in_scope}", "error[E0425]: cannot find value `not_in_scope` in this scope: not found in this scope"),
+        ("FOO=1 BAR=2", "the command has only environment assignments, add a program after them"),
+        ("echo hi \\\n", "the command ends with a line continuation, remove the trailing `\\`"),
+    ];
+    let main = cases
+        .iter()
+        .map(|(template, _)| format!("    let _ = xshell::cmd!({:?});\n", template))
+        .collect::<String>();
+    xshell::write_file(dir.join("src/main.rs"), format!("fn main() {{\n{}}}\n", main)).unwrap();
+
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("macro_diagnostics_target");
+    let output = cmd!("cargo build --offline --quiet --message-format short")
+        .dir(&dir)
+        .env("CARGO_TARGET_DIR", target_dir)
+        .ignore_status()
+        .output()
+        .unwrap();
+    // Like a UI test, the errors must match exactly, and there must be no
+    // others.
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let mut actual = stderr
+        .lines()
+        .filter(|it| it.starts_with("src/main.rs:") && it.contains(": error"))
+        .collect::<Vec<_>>();
+    actual.sort();
+    let mut expected = cases
+        .iter()
+        .enumerate()
+        .map(|(idx, (_template, message))| {
+            let message = if message.starts_with("error[") {
+                message.to_string()
+            } else {
+                format!("error: {}", message)
+            };
+            format!("src/main.rs:{}:26: {}", idx + 2, message)
+        })
+        .collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(actual, expected, "stderr:\n{}", stderr);
+}
+
+#[test]
+fn formatting() {
+    cmd!("cargo fmt --all -- --check").run().unwrap()
+}
+
+// Zombies count as dead, nobody might be reaping orphans in a container.
+#[cfg(target_os = "linux")]
+fn alive(pid: &str) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => !stat.rsplit(')').next().unwrap().trim_start().starts_with('Z'),
+        Err(_) => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn wait_for_pid(path: &Path) -> String {
+    let start = Instant::now();
+    loop {
+        if let Ok(pid) = std::fs::read_to_string(path) {
+            if pid.ends_with('\n') {
+                return pid.trim().to_string();
+            }
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    xshell::rm_rf(&dir).unwrap();
+    xshell::mkdir_p(&dir).unwrap();
+    dir
+}
+
+fn sleep_ms(ms: u64) {
+    thread::sleep(std::time::Duration::from_millis(ms))
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn run_async() {
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll, Wake},
+        thread::Thread,
+    };
+
+    struct Unpark(Thread);
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark()
+        }
+    }
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = Box::pin(fut);
+        loop {
+            match fut.as_mut().poll(&mut cx) {
+                Poll::Ready(it) => return it,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    assert_eq!(block_on(cmd!("echo hello").read_async()).unwrap(), "hello");
+    let err = block_on(cmd!("sleep 10").timeout(Duration::from_millis(50)).run_async());
+    assert!(err.unwrap_err().to_string().contains("timed out"));
+    let child = cmd!("true").quiet().spawn().unwrap();
+    assert!(block_on(child.wait_async()).unwrap().success());
+
+    let dir = scratch_dir("run_async");
+    let marker = dir.join("marker");
+    // Nothing runs until the future is polled.
+    drop(cmd!("touch {marker}").run_async());
+    assert!(!marker.exists());
+    block_on(cmd!("touch {marker}").quiet().run_async()).unwrap();
+    assert!(marker.exists());
+
+    assert_eq!(block_on(cmd!("cat").stdin("piped").read_async()).unwrap(), "piped");
+    let script = "echo out; echo err >&2; exit 3";
+    let output = block_on(cmd!("sh -c {script}").ignore_status().output_async()).unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!((&output.stdout[..], &output.stderr[..]), (&b"out\n"[..], &b"err\n"[..]));
+    let err = block_on(cmd!("sh -c {script}").read_async()).unwrap_err();
+    assert_eq!(err.to_string(), cmd!("sh -c {script}").read().unwrap_err().to_string());
+    let err = block_on(cmd!("yes").output_limit(1000).read_async()).unwrap_err();
+    assert!(err.to_string().contains("exceeded the limit of 1000 bytes"), "{}", err);
+    let err = block_on(cmd!("sh -c 'echo why >&2; exit 1'").retain_stderr(10).run_async());
+    assert!(err.unwrap_err().to_string().ends_with("stderr:\nwhy"));
+
+    // Dropping the future kills the command, and the processes it started.
+    #[cfg(target_os = "linux")]
+    {
+        fn poll_until<T>(fut: &mut xshell::CmdFuture<T>, path: &Path) {
+            let mut cx = Context::from_waker(std::task::Waker::noop());
+            while !path.exists() {
+                assert!(Pin::new(&mut *fut).poll(&mut cx).is_pending());
+                sleep_ms(10);
+            }
+        }
+
+        let pid = dir.join("pid");
+        let script = format!("sleep 300 & echo $! > {}; wait", pid.display());
+        // Not our stdin, so that it gets a group of its own in a terminal too.
+        let mut fut =
+            cmd!("sh -c {script}").stdin("").timeout(Duration::from_secs(60)).quiet().run_async();
+        poll_until(&mut fut, &pid);
+        let sleep = wait_for_pid(&pid);
+        drop(fut);
+        sleep_ms(100);
+        assert!(!alive(&sleep));
+
+        let pid = dir.join("pid2");
+        let script = format!("echo $$ > {}; exec sleep 300", pid.display());
+        let child = cmd!("sh -c {script}").quiet().spawn().unwrap();
+        let mut fut = child.wait_async();
+        poll_until(&mut fut, &pid);
+        let sleep = wait_for_pid(&pid);
+        drop(fut);
+        sleep_ms(100);
+        assert!(!alive(&sleep));
+    }
+}
+
+#[test]
+fn echo_color() {
+    // The color choice is global, so check it in a separate process.
+    let exe = std::env::current_exe().unwrap();
+    let child = cmd!("{exe} --exact echo_color_child --nocapture --test-threads 1")
+        .env("ECHO_COLOR_CHILD", "1")
+        .env_remove("NO_COLOR")
+        .quiet();
+    let output = child.clone().env("CLICOLOR_FORCE", "1").read().unwrap();
+    assert!(output.contains("\x1b[1;36m$\x1b[0m \x1b[1mtrue\x1b[0m"), "{}", output);
+    assert!(output.contains("\x1b[31mcommand `false` failed, exit code: 1\x1b[0m"), "{}", output);
+    // After `set_echo_color(ColorChoice::Never)`.
+    assert!(output.contains("\n$ true"), "{}", output);
+
+    let output = child.env("CLICOLOR_FORCE", "1").env("NO_COLOR", "1").read().unwrap();
+    assert!(output.contains("$ true") && !output.contains('\x1b'), "{}", output);
+}
+
+#[test]
+fn echo_color_child() {
+    if std::env::var_os("ECHO_COLOR_CHILD").is_none() {
+        return;
+    }
+    cmd!("true").run().unwrap();
+    println!("{:?}", cmd!("false").quiet().run().unwrap_err());
+    xshell::set_echo_color(xshell::ColorChoice::Never);
+    cmd!("true").run().unwrap();
+}
+
+#[test]
+fn verbosity() {
+    // The verbosity is global, so check it in a separate process.
+    let exe = std::env::current_exe().unwrap();
+    let child = cmd!("{exe} --exact verbosity_child --nocapture --test-threads 1")
+        .env("VERBOSITY_CHILD", "1")
+        .env_remove("XSHELL_QUIET")
+        .env_remove("XSHELL_VERBOSE")
+        .quiet();
+
+    let output = child.clone().read().unwrap();
+    assert!(output.contains("$ FOO=bar echo hi\nhi\n$ true"), "{}", output);
+    assert!(!output.contains("cwd:"), "{}", output);
+
+    let output = child.clone().env("XSHELL_QUIET", "1").read().unwrap();
+    assert!(!output.contains("echo hi") && output.contains("hi\n$ true"), "{}", output);
+
+    let output = child.env("XSHELL_VERBOSE", "1").output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("$ echo shell\n  cwd: /\nshell\n"), "{}", stdout);
+    assert!(stdout.contains("$ FOO=bar echo hi\n  cwd: "), "{}", stdout);
+    // `set_verbosity` takes precedence over the environment.
+    assert!(stdout.contains("\n  env: unset BAR\nhi\n$ true\nok"), "{}", stdout);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("(took ") && stderr.contains(": $ echo shell)\n"), "{}", stderr);
+}
+
+#[test]
+fn verbosity_child() {
+    if std::env::var_os("VERBOSITY_CHILD").is_none() {
+        return;
+    }
+    let mut sh = Shell::new().unwrap();
+    sh.change_dir("/");
+    sh.cmd("echo").arg("shell").run().unwrap();
+    cmd!("echo hi").env("FOO", "bar").env_remove("BAR").run().unwrap();
+    xshell::set_verbosity(xshell::Verbosity::Normal);
+    assert_eq!(xshell::verbosity(), xshell::Verbosity::Normal);
+    cmd!("true").run().unwrap();
+}
+
+#[test]
+fn cmd_hook() {
+    // Hooks are global, so check them in a separate process.
+    let exe = std::env::current_exe().unwrap();
+    let output = cmd!("{exe} --exact cmd_hook_child --nocapture --test-threads 1")
+        .env("CMD_HOOK_CHILD", "1")
+        .quiet()
+        .read()
+        .unwrap();
+    assert!(output.contains("$ HOOKED=1 echo wrapped printenv HOOKED\n"), "{}", output);
+    assert!(output.contains("$ echo wrapped sh\n"), "{}", output);
+    assert!(output.contains("; echo wrapped pwd) | echo wrapped false\n"), "{}", output);
+    assert!(output.contains("$ echo nested echo wrapped run\n"), "{}", output);
+    assert!(output.contains("$ echo unhooked\n"), "{}", output);
+    assert!(output.contains("$ HOOKED=1 printenv SHELL_VAR\nshell\n"), "{}", output);
+    assert!(output.contains("$ HOOKED=1 printenv HOOKED\n1\n"), "{}", output);
+}
+
+#[test]
+fn cmd_hook_child() {
+    if std::env::var_os("CMD_HOOK_CHILD").is_none() {
+        return;
+    }
+    let dir = scratch_dir("cmd_hook");
+    let hook_dir = dir.clone();
+    let guard = xshell::set_cmd_hook(move |mut cmd| {
+        if cmd.program == "show-env" {
+            cmd.program = "printenv".into();
+            cmd.env.push(("HOOKED".into(), Some("1".into())));
+            return cmd;
+        }
+        cmd.args.insert(0, std::mem::replace(&mut cmd.program, "echo".into()));
+        cmd.args.insert(0, "wrapped".into());
+        if cmd.args.iter().any(|it| it == "printenv") {
+            cmd.env.push(("HOOKED".into(), Some("1".into())));
+        }
+        if cmd.args.iter().any(|it| it == "pwd") {
+            cmd.cwd = Some(hook_dir.clone());
+        }
+        cmd
+    });
+
+    assert_eq!(cmd!("printenv HOOKED").read().unwrap(), "wrapped printenv HOOKED");
+    cmd!("printenv HOOKED").run().unwrap();
+    assert_eq!(cmd!("a").output().unwrap().stdout, b"wrapped a\n");
+    // The second `echo` doesn't read its stdin, so the first one might get a SIGPIPE.
+    let pipeline = cmd!("a").ignore_status().pipe(cmd!("tr a b"));
+    assert_eq!(pipeline.read().unwrap(), "wrapped tr a b");
+    let lines = cmd!("x").read_lines().unwrap().collect::<xshell::Result<Vec<_>>>().unwrap();
+    assert_eq!(lines, ["wrapped x"]);
+    assert!(cmd!("sh").spawn().unwrap().wait().unwrap().success());
+    cmd!("pwd").pipe(cmd!("false")).run().unwrap();
+    assert_eq!(xshell::Shell::new().unwrap().cmd("pwd").read().unwrap(), "wrapped pwd",);
+
+    // Only what the hook changed shows up in the echo.
+    let mut sh = xshell::Shell::new().unwrap();
+    sh.set_var("SHELL_VAR", "shell");
+    sh.cmd("show-env").arg("SHELL_VAR").run().unwrap();
+    assert_eq!(sh.cmd("show-env").arg("SHELL_VAR").read().unwrap(), "shell");
+    cmd!("show-env HOOKED").env_snapshot().run().unwrap();
+
+    let nested = xshell::set_cmd_hook(|mut cmd| {
+        cmd.args.insert(0, std::mem::replace(&mut cmd.program, "echo".into()));
+        cmd.args.insert(0, "nested".into());
+        cmd
+    });
+    cmd!("run").run().unwrap();
+    drop(nested);
+    assert_eq!(cmd!("b").read().unwrap(), "wrapped b");
+
+    drop(guard);
+    cmd!("echo unhooked").run().unwrap();
+}
+
+#[test]
+fn propagate_exit_code() {
+    let exe = std::env::current_exe().unwrap();
+    let child = cmd!("{exe} --exact propagate_exit_code_child --test-threads 1").quiet();
+    for (mode, code, signal) in [
+        ("run", Some(7), None),
+        ("exec", Some(5), None),
+        ("missing", Some(127), None),
+        ("signal", None, Some(9)),
+    ] {
+        let err = child.clone().env("PROPAGATE_MODE", mode).output().unwrap_err();
+        assert_eq!(err.exit_status().and_then(|it| it.code()), code, "{}", mode);
+        assert_eq!(err.signal(), signal, "{}", mode);
+    }
+}
+
+#[test]
+fn propagate_exit_code_child() {
+    let mode = match std::env::var("PROPAGATE_MODE") {
+        Ok(it) => it,
+        Err(_) => return,
+    };
+    let cmd = match mode.as_str() {
+        "run" => cmd!("sh -c 'exit 7'"),
+        "exec" => cmd!("sh -c 'exit 5'"),
+        "missing" => cmd!("nope-not-installed"),
+        _ => cmd!("sh -c 'kill -KILL $$'"),
+    };
+    if mode == "exec" {
+        panic!("{}", cmd.quiet().exec_replace());
+    }
+    cmd.quiet().run_and_propagate()
 }
@@ -1,7 +1,5 @@
 extern crate proc_macro;
 
-use std::iter;
-
 use proc_macro::{Group, Span, TokenStream, TokenTree};
 
 #[doc(hidden)]
@@ -16,72 +14,350 @@ pub fn __cmd(macro_arg: TokenStream) -> TokenStream {
     };
 
     let literal_text = literal.to_string();
-    let mut args = shell_lex(literal_text.as_str(), literal.span());
+    let args = match shell_lex(literal_text.as_str(), literal.span()) {
+        Ok(it) => it,
+        Err(msg) => return compile_error(&msg, literal.span()),
+    };
+    let mut args = args.into_iter().peekable();
+
+    // Leading `NAME=value` words, like in `RUSTFLAGS=-Dwarnings cargo build`,
+    // set the environment of the command.
+    let mut env = TokenStream::new();
+    while let Some(Arg { assignment: Some(_), .. }) = args.peek() {
+        let (key, value) = args.next().unwrap().assignment.unwrap();
+        env.extend(parse_ts(&format!(".__env({:?})", key)));
+        env.extend(parse_ts(".__extend_env"));
+        env.extend(value);
+        while let Some(Arg { joined_to_prev: true, .. }) = args.peek() {
+            let arg = args.next().unwrap();
+            if arg.splat {
+                return compile_error(
+                    &format!("the value of `{}` can't be splatted", key),
+                    literal.span(),
+                );
+            }
+            let method =
+                if arg.interpolation { ".__interpolate_extend_env" } else { ".__extend_env" };
+            env.extend(parse_ts(method));
+            env.extend(arg.ts);
+        }
+    }
 
     let mut res = TokenStream::new();
 
     {
-        let (_joined_to_prev, splat, program) = args.next().expect("command line is empty!");
-        assert!(!splat);
+        let program = match args.next() {
+            Some(it) => it,
+            None if !env.is_empty() => {
+                return compile_error(
+                    "the command has only environment assignments, add a program after them",
+                    literal.span(),
+                )
+            }
+            None => panic!("command line is empty!"),
+        };
+        if program.splat {
+            return compile_error(
+                "the program can't be splatted, use `{program} {args...}`",
+                literal.span(),
+            );
+        }
         res.extend(Some(cmd));
         res.extend(parse_ts("::new"));
-        res.extend(program);
+        res.extend(program.ts);
+        res.extend(env);
     }
 
-    for (joined_to_prev, splat, arg) in args {
+    for Arg { joined_to_prev, splat, interpolation, ts, .. } in args {
         assert!(!(joined_to_prev && splat));
-        let method = match (joined_to_prev, splat) {
-            (false, false) => ".arg",
-            (false, true) => ".args",
-            (true, false) => ".__extend_arg",
-            (true, true) => panic!("can't splat and contat simultaneously"),
+        let method = match (joined_to_prev, splat, interpolation) {
+            (false, false, false) => ".arg",
+            (false, false, true) => ".__interpolate_arg",
+            (false, true, _) => ".args",
+            (true, false, false) => ".__extend_arg",
+            (true, false, true) => ".__interpolate_extend_arg",
+            (true, true, _) => panic!("can't splat and contat simultaneously"),
         };
         res.extend(parse_ts(method));
-        res.extend(arg);
+        res.extend(ts);
     }
 
     res
 }
 
-fn shell_lex(cmd: &str, call_site: Span) -> impl Iterator<Item = (bool, bool, TokenStream)> + '_ {
-    fn trim_decorations(s: &str) -> &str {
-        &s[1..s.len() - 1]
+#[doc(hidden)]
+#[proc_macro]
+pub fn __shell_cmd(macro_arg: TokenStream) -> TokenStream {
+    let (cmd, literal) = {
+        let mut iter = macro_arg.into_iter();
+        let cmd = iter.next().unwrap();
+        let literal = iter.next().unwrap();
+        assert!(iter.next().is_none());
+        (cmd, literal)
+    };
+
+    let script = match unescape_literal(&literal.to_string()) {
+        Some(it) => it,
+        None => return compile_error("expected a string literal", literal.span()),
+    };
+    let (script, interpolations) = match positional_params(&script) {
+        Ok(it) => it,
+        Err(msg) => return compile_error(&msg, literal.span()),
+    };
+
+    let mut res = TokenStream::new();
+    res.extend(Some(cmd));
+    res.extend(parse_ts(&format!("::shell({:?})", script)));
+    for expr in interpolations {
+        let ts = match format!("((&__ARG(&({}))).__into_arg())", expr).parse() {
+            Ok(it) => it,
+            Err(_) => {
+                let msg = format!("invalid interpolation `{{{}}}`", expr);
+                return compile_error(&msg, literal.span());
+            }
+        };
+        res.extend(parse_ts(".__interpolate_arg"));
+        res.extend(respan(ts, literal.span()));
     }
+    res
+}
 
-    tokenize(cmd).map(move |token| {
-        let mut splat = false;
-        let ts = match token.kind {
-            TokenKind::Word => parse_ts(&format!("(\"{}\")", token.text)),
-            TokenKind::String => parse_ts(&format!("(\"{}\")", trim_decorations(token.text))),
-            TokenKind::Interpolation { splat: s } => {
-                splat = s;
-                let text = trim_decorations(token.text);
-                let ts = if splat {
-                    format!("({})", &text[..text.len() - "...".len()])
-                } else {
-                    format!("(&({}))", trim_decorations(token.text))
+/// Replaces `{expr}` interpolations in a shell script with positional
+/// parameters, quoted unless they already are, and returns the `expr`s.
+///
+/// Braces are literal inside single quotes, elsewhere `{{` and `}}` stand for
+/// literal braces, as in `${{HOME}}`.
+fn positional_params(script: &str) -> Result<(String, Vec<&str>), String> {
+    let mut res = String::new();
+    let mut exprs = Vec::new();
+    let mut in_double_quotes = false;
+    let mut rest = script;
+    while let Some(c) = rest.chars().next() {
+        let len = c.len_utf8();
+        match c {
+            '\\' => {
+                let len = rest[1..].chars().next().map_or(1, |it| 1 + it.len_utf8());
+                res.push_str(&rest[..len]);
+                rest = &rest[len..];
+                continue;
+            }
+            '\'' if !in_double_quotes => {
+                let len = match rest[1..].find('\'') {
+                    Some(it) => it + 2,
+                    None => {
+                        return Err(format!(
+                            "unterminated quote starting at `{}`, add a closing `'`",
+                            snippet(rest)
+                        ))
+                    }
                 };
-                respan(parse_ts(&ts), call_site)
+                res.push_str(&rest[..len]);
+                rest = &rest[len..];
+                continue;
             }
+            '"' => in_double_quotes = !in_double_quotes,
+            _ if rest.starts_with("{{") || rest.starts_with("}}") => {
+                res.push(c);
+                rest = &rest[2..];
+                continue;
+            }
+            '{' => {
+                let len = match rest.find('}') {
+                    Some(it) => it + 1,
+                    None => {
+                        return Err(format!(
+                            "unterminated interpolation starting at `{}`, add a closing `}}` \
+                             or use `{{{{` for a literal `{{`",
+                            snippet(rest)
+                        ))
+                    }
+                };
+                let expr = &rest[1..len - 1];
+                if expr.ends_with("...") {
+                    return Err(format!(
+                        "`{}` can't be splatted into a script, pass the values as arguments \
+                         and use \"$@\"",
+                        &rest[..len]
+                    ));
+                }
+                if expr.trim().is_empty() {
+                    return Err(format!(
+                        "empty interpolation `{}`: specify a variable name, \
+                         or use `{{{{}}}}` for literal braces",
+                        &rest[..len]
+                    ));
+                }
+                exprs.push(expr);
+                let param = match exprs.len() {
+                    n @ 1..=9 => format!("${}", n),
+                    n => format!("${{{}}}", n),
+                };
+                if in_double_quotes {
+                    res.push_str(&param);
+                } else {
+                    res.push_str(&format!("\"{}\"", param));
+                }
+                rest = &rest[len..];
+                continue;
+            }
+            '}' => {
+                return Err(format!(
+                    "unmatched `}}` in `{}`, use `}}}}` for a literal `}}`",
+                    &script[..script.len() - rest.len() + 1]
+                ))
+            }
+            _ => (),
+        }
+        res.push(c);
+        rest = &rest[len..];
+    }
+    Ok((res, exprs))
+}
+
+/// The value of a string literal, given its source.
+fn unescape_literal(literal: &str) -> Option<String> {
+    if let Some(raw) = literal.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        return Some(raw.get(hashes + 1..raw.len() - hashes - 1)?.to_string());
+    }
+    let mut chars = literal.strip_prefix('"')?.strip_suffix('"')?.chars().peekable();
+    let mut res = String::new();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        let c = match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            'x' => {
+                let hex = [chars.next()?, chars.next()?].iter().collect::<String>();
+                char::from(u8::from_str_radix(&hex, 16).ok()?)
+            }
+            'u' => {
+                let hex = chars.by_ref().skip(1).take_while(|&it| it != '}').collect::<String>();
+                char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+            }
+            '\n' => {
+                while chars.next_if(|it| it.is_whitespace()).is_some() {}
+                continue;
+            }
+            c => c,
         };
-        (token.joined_to_prev, splat, ts)
-    })
+        res.push(c);
+    }
+    Some(res)
 }
 
-fn tokenize(cmd: &str) -> impl Iterator<Item = Token<'_>> + '_ {
+struct Arg {
+    joined_to_prev: bool,
+    splat: bool,
+    interpolation: bool,
+    /// The name and the start of the value, if this is a `NAME=value` word.
+    assignment: Option<(String, TokenStream)>,
+    ts: TokenStream,
+}
+
+fn shell_lex(cmd: &str, call_site: Span) -> Result<Vec<Arg>, String> {
+    fn trim_decorations(s: &str) -> &str {
+        &s[1..s.len() - 1]
+    }
+
+    tokenize(cmd)?
+        .into_iter()
+        .map(move |token| {
+            let mut splat = false;
+            let mut assignment = None;
+            let interpolation = matches!(token.kind, TokenKind::Interpolation { .. });
+            let ts = match token.kind {
+                TokenKind::Word => {
+                    if let Some((key, value)) = split_assignment(token.text) {
+                        let ts = parse_ts(&format!("(\"{}\")", unescape_braces(value)));
+                        assignment = Some((key.to_string(), ts));
+                    }
+                    parse_ts(&format!("(\"{}\")", unescape_braces(token.text)))
+                }
+                TokenKind::String => parse_ts(&format!("(\"{}\")", trim_decorations(token.text))),
+                TokenKind::Interpolation { splat: s } => {
+                    splat = s;
+                    let text = trim_decorations(token.text);
+                    let ts = if splat {
+                        format!("({})", &text[..text.len() - "...".len()])
+                    } else {
+                        format!("((&__ARG(&({}))).__into_arg())", trim_decorations(token.text))
+                    };
+                    let ts = ts
+                        .parse()
+                        .map_err(|_| format!("invalid interpolation `{}`", token.text))?;
+                    respan(ts, call_site)
+                }
+            };
+            if token.joined_to_prev {
+                assignment = None;
+            }
+            Ok(Arg { joined_to_prev: token.joined_to_prev, splat, interpolation, assignment, ts })
+        })
+        .collect()
+}
+
+/// Splits `NAME=value` into the name and the value, if `NAME` is a valid
+/// name for an environment variable in the shell.
+fn split_assignment(word: &str) -> Option<(&str, &str)> {
+    let (key, value) = word.split_once('=')?;
+    let mut chars = key.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then_some((key, value))
+}
+
+/// `{{` and `}}` stand for literal braces in words, like in `format!`. Inside
+/// single quotes, braces are always literal.
+fn unescape_braces(word: &str) -> String {
+    word.replace("{{", "{").replace("}}", "}")
+}
+
+fn tokenize(cmd: &str) -> Result<Vec<Token<'_>>, String> {
     let mut cmd = cmd.trim_matches('"');
-    iter::from_fn(move || {
+    let mut res = Vec::new();
+    loop {
         let old_len = cmd.len();
-        cmd = cmd.trim_start();
-        let joined_to_prev = old_len == cmd.len();
+        let mut continued = false;
+        loop {
+            cmd = cmd.trim_start();
+            match strip_continuation(cmd) {
+                Some(rest) => {
+                    cmd = rest;
+                    continued = true;
+                }
+                None => break,
+            }
+        }
+        let joined_to_prev = old_len == cmd.len() && !res.is_empty();
         if cmd.is_empty() {
-            return None;
+            if continued {
+                return Err("the command ends with a line continuation, remove the trailing `\\`"
+                    .to_string());
+            }
+            return Ok(res);
         }
-        let (len, kind) = next_token(cmd);
-        let token = Token { joined_to_prev, text: &cmd[..len], kind };
+        let (len, kind) = next_token(cmd)?;
+        res.push(Token { joined_to_prev, text: &cmd[..len], kind });
         cmd = &cmd[len..];
-        Some(token)
-    })
+    }
+}
+
+/// Like in the shell, a backslash at the end of a line joins it with the next
+/// one. In the source, that's either a Rust string continuation, `\` and a
+/// newline, or an escaped backslash, `\\`, and a newline, which itself may be
+/// escaped.
+fn strip_continuation(s: &str) -> Option<&str> {
+    if let Some(rest) = s.strip_prefix("\\\\") {
+        return ["\n", "\r\n", "\\n", "\\r\\n"].iter().find_map(|it| rest.strip_prefix(it));
+    }
+    let rest = s.strip_prefix('\\')?;
+    rest.strip_prefix('\n').or_else(|| rest.strip_prefix("\r\n"))
 }
 
 #[derive(Debug)]
@@ -97,19 +373,78 @@ enum TokenKind {
     Interpolation { splat: bool },
 }
 
-fn next_token(s: &str) -> (usize, TokenKind) {
-    if s.starts_with('{') {
-        let len = s.find('}').unwrap() + 1;
+/// As `Literal::subspan` is unstable, errors can only point at the whole
+/// literal, so they quote the problematic part.
+fn next_token(s: &str) -> Result<(usize, TokenKind), String> {
+    if s.starts_with('{') && !s.starts_with("{{") {
+        let len = match s.find('}') {
+            Some(it) => it + 1,
+            None => {
+                return Err(format!(
+                    "unterminated interpolation starting at `{}`, add a closing `}}` \
+                     or use `{{{{` for a literal `{{`",
+                    snippet(s)
+                ))
+            }
+        };
         let splat = s[..len].ends_with("...}");
-        return (len, TokenKind::Interpolation { splat });
+        let name = s[1..len - 1].trim_end_matches("...");
+        if name.trim().is_empty() {
+            return Err(format!(
+                "empty interpolation `{}`: specify a variable name, \
+                 or use `{{{{}}}}` for literal braces",
+                &s[..len]
+            ));
+        }
+        return Ok((len, TokenKind::Interpolation { splat }));
     }
-    if s.starts_with('\'') {
-        let len = s[1..].find('\'').unwrap() + 2;
-        return (len, TokenKind::String);
+    if let Some(rest) = s.strip_prefix('\'') {
+        let len = match rest.find('\'') {
+            Some(it) => it + 2,
+            None => {
+                return Err(format!(
+                    "unterminated quote starting at `{}`, add a closing `'`",
+                    snippet(s)
+                ))
+            }
+        };
+        return Ok((len, TokenKind::String));
+    }
+    let mut len = 0;
+    while let Some(c) = s[len..].chars().next() {
+        let rest = &s[len..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            len += 2;
+            continue;
+        }
+        if c == '}' {
+            return Err(format!(
+                "unmatched `}}` in `{}`, use `}}}}` for a literal `}}`",
+                &s[..len + 1]
+            ));
+        }
+        if c.is_ascii_whitespace() || c == '\'' || c == '{' || strip_continuation(rest).is_some() {
+            break;
+        }
+        if c == '\\' {
+            // Skip the escaped character, so that `\\\\` isn't mistaken for
+            // the start of a continuation.
+            len += 1;
+            if let Some(c) = s[len..].chars().next() {
+                len += c.len_utf8();
+            }
+            continue;
+        }
+        len += c.len_utf8();
+    }
+    Ok((len, TokenKind::Word))
+}
+
+fn snippet(s: &str) -> String {
+    match s.char_indices().nth(16) {
+        Some((idx, _)) => format!("{}...", &s[..idx]),
+        None => s.to_string(),
     }
-    let len =
-        s.find(|it: char| it.is_ascii_whitespace() || it == '\'' || it == '{').unwrap_or(s.len());
-    (len, TokenKind::Word)
 }
 
 fn respan(ts: TokenStream, span: Span) -> TokenStream {
@@ -117,7 +452,7 @@ fn respan(ts: TokenStream, span: Span) -> TokenStream {
     for tt in ts {
         let tt = match tt {
             TokenTree::Ident(mut ident) => {
-                ident.set_span(ident.span().resolved_at(span));
+                ident.set_span(ident.span().resolved_at(span).located_at(span));
                 TokenTree::Ident(ident)
             }
             TokenTree::Group(group) => {
@@ -130,6 +465,23 @@ fn respan(ts: TokenStream, span: Span) -> TokenStream {
     res
 }
 
+fn compile_error(msg: &str, span: Span) -> TokenStream {
+    parse_ts(&format!("compile_error!({:?})", msg))
+        .into_iter()
+        .map(|mut tt| {
+            tt.set_span(span);
+            if let TokenTree::Group(group) = &mut tt {
+                let mut stream = group.stream().into_iter().collect::<Vec<_>>();
+                stream.iter_mut().for_each(|it| it.set_span(span));
+                let mut new = Group::new(group.delimiter(), stream.into_iter().collect());
+                new.set_span(span);
+                *group = new;
+            }
+            tt
+        })
+        .collect()
+}
+
 fn parse_ts(s: &str) -> TokenStream {
     s.parse().unwrap()
 }